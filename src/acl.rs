@@ -0,0 +1,388 @@
+//! Access control for upstream destinations dialed by the server.
+//!
+//! Clients choose upstreams for OUT tunnels (explicitly at login, or per stream
+//! for channel-based tunnels), so without restrictions the server relays into
+//! any network it can reach. [`UpstreamAcl`] is an ordered list of allow/deny
+//! rules matched against the destination address and port, the first matching
+//! rule decides and destinations matching no rule are allowed.
+
+use anyhow::{Context, Result};
+use rs_utilities::log_and_bail;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Error code used to reset streams whose upstream is denied.
+pub(crate) const DENIED_STREAM_ERROR_CODE: u32 = 1;
+
+/// Decision of an ACL rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+impl Display for AclAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Allow => write!(f, "ALLOW"),
+            Self::Deny => write!(f, "DENY"),
+        }
+    }
+}
+
+/// An IP network in CIDR notation, e.g. 10.0.0.0/8 or fe80::/10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Return true if `ip` is inside the network.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid network address: {s}"))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .with_context(|| format!("invalid prefix length: {s}"))?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            log_and_bail!("invalid prefix length: {s}");
+        }
+
+        // addresses are matched in canonical form, so an IPv4-mapped network
+        // must become the IPv4 network it maps
+        if let IpAddr::V6(v6) = addr {
+            if let (Some(v4), true) = (v6.to_ipv4_mapped(), prefix_len >= 96) {
+                return Ok(IpNet {
+                    addr: IpAddr::V4(v4),
+                    prefix_len: prefix_len - 96,
+                });
+            }
+        }
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// An inclusive range of ports, e.g. 8000-9000, or a single port.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_port = |port: &str| -> Result<u16> {
+            port.trim()
+                .parse()
+                .with_context(|| format!("invalid port range: {s}"))
+        };
+
+        let range = match s.split_once('-') {
+            Some((start, end)) => PortRange {
+                start: parse_port(start)?,
+                end: parse_port(end)?,
+            },
+            None => {
+                let port = parse_port(s)?;
+                PortRange {
+                    start: port,
+                    end: port,
+                }
+            }
+        };
+
+        if range.start > range.end {
+            log_and_bail!("invalid port range: {s}");
+        }
+        Ok(range)
+    }
+}
+
+impl PortRange {
+    /// Return true if `port` is inside the range.
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    /// Parse comma-separated port ranges, e.g. "8000-9000,9100".
+    pub fn parse_list(s: &str) -> Result<Vec<PortRange>> {
+        s.split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(PortRange::from_str)
+            .collect()
+    }
+
+    /// Format port ranges as a comma-separated list.
+    pub fn format_list(ranges: &[PortRange]) -> String {
+        ranges
+            .iter()
+            .map(|range| range.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// A single ACL rule in the form ACTION^NETWORK[^PORTS], e.g. DENY^169.254.0.0/16
+/// or ALLOW^10.0.0.0/8^80-443. NETWORK can be ANY to match all addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct AclRule {
+    pub action: AclAction,
+    /// Destination network, None matches all addresses.
+    pub network: Option<IpNet>,
+    /// Destination ports, None matches all ports.
+    pub ports: Option<PortRange>,
+}
+
+impl AclRule {
+    /// Return true if the rule applies to `addr`.
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        self.network.is_none_or(|net| net.contains(&addr.ip()))
+            && self.ports.is_none_or(|ports| ports.contains(addr.port()))
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.trim().split('^').collect();
+        if parts.len() != 2 && parts.len() != 3 {
+            log_and_bail!("invalid acl rule format '{s}', expected ACTION^NETWORK[^PORTS]");
+        }
+
+        let action = match parts[0] {
+            "ALLOW" => AclAction::Allow,
+            "DENY" => AclAction::Deny,
+            _ => {
+                log_and_bail!("invalid acl action '{}', expected ALLOW or DENY", parts[0]);
+            }
+        };
+        let network = match parts[1] {
+            "ANY" => None,
+            network => Some(network.parse()?),
+        };
+        let ports = match parts.get(2) {
+            Some(ports) => Some(ports.parse()?),
+            None => None,
+        };
+
+        Ok(AclRule {
+            action,
+            network,
+            ports,
+        })
+    }
+}
+
+impl TryFrom<String> for AclRule {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<AclRule> for String {
+    fn from(rule: AclRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl Display for AclRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.network {
+            Some(net) => write!(f, "{}^{net}", self.action)?,
+            None => write!(f, "{}^ANY", self.action)?,
+        }
+        if let Some(ports) = self.ports {
+            write!(f, "^{ports}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Ordered list of rules deciding which upstreams the server may dial.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct UpstreamAcl {
+    pub rules: Vec<AclRule>,
+}
+
+impl UpstreamAcl {
+    /// Return true if the server may dial `addr`.
+    pub fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(addr))
+            .is_none_or(|rule| rule.action == AclAction::Allow)
+    }
+
+    /// Return true if the ACL has no rules, i.e. everything is allowed.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl FromStr for UpstreamAcl {
+    type Err = anyhow::Error;
+
+    /// Parse comma-separated rules, see [`AclRule`].
+    fn from_str(s: &str) -> Result<Self> {
+        let rules = s
+            .split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(AclRule::from_str)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(UpstreamAcl { rules })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_format_rules() {
+        let acl: UpstreamAcl = "DENY^169.254.0.0/16, ALLOW^10.0.0.0/8^80-443,DENY^ANY"
+            .parse()
+            .unwrap();
+        let rules: Vec<String> = acl.rules.iter().map(|rule| rule.to_string()).collect();
+        assert_eq!(
+            rules,
+            ["DENY^169.254.0.0/16", "ALLOW^10.0.0.0/8^80-443", "DENY^ANY"]
+        );
+        assert_eq!(
+            "ALLOW^fe80::1".parse::<AclRule>().unwrap().to_string(),
+            "ALLOW^fe80::1/128"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_rules() {
+        for rule in [
+            "ALLOW",
+            "PERMIT^ANY",
+            "ALLOW^10.0.0.0/33",
+            "ALLOW^::/129",
+            "ALLOW^10.0.0.0/8^443-80",
+            "ALLOW^10.0.0.0/8^80^90",
+            "ALLOW^example.com",
+        ] {
+            assert!(rule.parse::<AclRule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl: UpstreamAcl = "ALLOW^10.1.0.0/16,DENY^10.0.0.0/8,ALLOW^ANY^22,DENY^ANY"
+            .parse()
+            .unwrap();
+        assert!(acl.is_allowed(&addr("10.1.2.3:80")));
+        assert!(!acl.is_allowed(&addr("10.2.0.1:22")));
+        assert!(acl.is_allowed(&addr("192.168.1.1:22")));
+        assert!(!acl.is_allowed(&addr("192.168.1.1:80")));
+        assert!(UpstreamAcl::default().is_allowed(&addr("10.0.0.1:1")));
+    }
+
+    #[test]
+    fn match_ipv4_mapped_addresses() {
+        let acl: UpstreamAcl = "DENY^10.0.0.0/8,DENY^::ffff:192.168.0.0/112"
+            .parse()
+            .unwrap();
+        assert!(!acl.is_allowed(&addr("[::ffff:10.0.0.1]:80")));
+        assert!(!acl.is_allowed(&addr("192.168.3.4:80")));
+        assert!(!acl.is_allowed(&addr("[::ffff:192.168.3.4]:80")));
+        assert!(acl.is_allowed(&addr("[::ffff:172.16.0.1]:80")));
+    }
+
+    #[test]
+    fn port_ranges() {
+        assert_eq!(
+            PortRange::parse_list("80, 8000-9000,").unwrap(),
+            [
+                PortRange { start: 80, end: 80 },
+                PortRange {
+                    start: 8000,
+                    end: 9000
+                }
+            ]
+        );
+        let range: PortRange = "8000-9000".parse().unwrap();
+        assert!(range.contains(8000) && range.contains(9000) && !range.contains(9001));
+        assert!("9000-8000".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+    }
+}
@@ -0,0 +1,292 @@
+//! Admission control for client sessions on the server.
+//!
+//! Every incoming connection takes a [`SessionPermit`] before its handshake is
+//! driven, connections over the total or per-IP limits are refused right away.
+//! The per-user limit can only be checked once the client has logged in. The
+//! permit is released when the connection is closed.
+
+use crate::ServerConfig;
+use anyhow::{bail, Result};
+use quinn::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Counters of the admission control, for monitoring.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct AdmissionStats {
+    /// Sessions currently admitted, including handshakes in progress.
+    pub active_sessions: usize,
+    /// Connections refused because the server was full.
+    pub refused_total: u64,
+    /// Connections refused because their source IP had too many sessions.
+    pub refused_per_ip: u64,
+    /// Logins rejected because the user had too many sessions.
+    pub refused_per_user: u64,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_user: HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+pub(crate) struct AdmissionControl {
+    max_sessions: AtomicUsize,
+    max_sessions_per_ip: AtomicUsize,
+    max_sessions_per_user: AtomicUsize,
+    sessions: Mutex<Sessions>,
+    refused_total: AtomicU64,
+    refused_per_ip: AtomicU64,
+    refused_per_user: AtomicU64,
+}
+
+impl AdmissionControl {
+    pub fn new(config: &ServerConfig) -> Self {
+        AdmissionControl {
+            max_sessions: AtomicUsize::new(config.max_sessions),
+            max_sessions_per_ip: AtomicUsize::new(config.max_sessions_per_ip),
+            max_sessions_per_user: AtomicUsize::new(config.max_sessions_per_user),
+            sessions: Mutex::new(Sessions::default()),
+            refused_total: AtomicU64::new(0),
+            refused_per_ip: AtomicU64::new(0),
+            refused_per_user: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply the limits of a reloaded `config`, admitted sessions are kept.
+    pub fn update_limits(&self, config: &ServerConfig) {
+        self.max_sessions
+            .store(config.max_sessions, Ordering::Relaxed);
+        self.max_sessions_per_ip
+            .store(config.max_sessions_per_ip, Ordering::Relaxed);
+        self.max_sessions_per_user
+            .store(config.max_sessions_per_user, Ordering::Relaxed);
+    }
+
+    /// Admit a new connection from `ip`, None if it must be refused.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<SessionPermit> {
+        let ip = ip.to_canonical();
+        let mut sessions = self.sessions.lock().unwrap();
+        let max_sessions = self.max_sessions.load(Ordering::Relaxed);
+        if max_sessions > 0 && sessions.total >= max_sessions {
+            self.refused_total.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let ip_sessions = sessions.per_ip.get(&ip).copied().unwrap_or(0);
+        let max_sessions_per_ip = self.max_sessions_per_ip.load(Ordering::Relaxed);
+        if max_sessions_per_ip > 0 && ip_sessions >= max_sessions_per_ip {
+            self.refused_per_ip.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        *sessions.per_ip.entry(ip).or_default() += 1;
+        sessions.total += 1;
+        Some(SessionPermit {
+            control: self.clone(),
+            ip,
+            user: None,
+        })
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            active_sessions: self.sessions.lock().unwrap().total,
+            refused_total: self.refused_total.load(Ordering::Relaxed),
+            refused_per_ip: self.refused_per_ip.load(Ordering::Relaxed),
+            refused_per_user: self.refused_per_user.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A session slot held for the lifetime of a connection.
+#[derive(Debug)]
+pub(crate) struct SessionPermit {
+    control: Arc<AdmissionControl>,
+    ip: IpAddr,
+    user: Option<String>,
+}
+
+impl SessionPermit {
+    /// Count the session for `user`, fails if the user has too many sessions.
+    pub fn bind_user(&mut self, user: &str) -> Result<()> {
+        let control = &self.control;
+        let mut sessions = control.sessions.lock().unwrap();
+        let user_sessions = sessions.per_user.get(user).copied().unwrap_or(0);
+        let max_sessions_per_user = control.max_sessions_per_user.load(Ordering::Relaxed);
+        if max_sessions_per_user > 0 && user_sessions >= max_sessions_per_user {
+            control.refused_per_user.fetch_add(1, Ordering::Relaxed);
+            bail!("too many sessions for user {user}, limit: {max_sessions_per_user}");
+        }
+
+        *sessions.per_user.entry(user.to_string()).or_default() += 1;
+        self.user = Some(user.to_string());
+        Ok(())
+    }
+
+    /// Release the permit once `conn` is closed.
+    pub fn release_on_close(self, conn: Connection) {
+        tokio::spawn(async move {
+            conn.closed().await;
+            drop(self);
+        });
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut sessions = self.control.sessions.lock().unwrap();
+        sessions.total -= 1;
+        if let Some(count) = sessions.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.per_ip.remove(&self.ip);
+            }
+        }
+        if let Some(user) = &self.user {
+            if let Some(count) = sessions.per_user.get_mut(user) {
+                *count -= 1;
+                if *count == 0 {
+                    sessions.per_user.remove(user);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(max_sessions: usize, per_ip: usize, per_user: usize) -> Arc<AdmissionControl> {
+        Arc::new(AdmissionControl::new(&ServerConfig {
+            max_sessions,
+            max_sessions_per_ip: per_ip,
+            max_sessions_per_user: per_user,
+            ..ServerConfig::default()
+        }))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn total_limit() {
+        let control = control(2, 0, 0);
+        let a = control.admit(ip("10.0.0.1")).unwrap();
+        let _b = control.admit(ip("10.0.0.2")).unwrap();
+        assert!(control.admit(ip("10.0.0.3")).is_none());
+        assert_eq!(control.stats().active_sessions, 2);
+        assert_eq!(control.stats().refused_total, 1);
+
+        drop(a);
+        assert!(control.admit(ip("10.0.0.3")).is_some());
+        assert_eq!(control.stats().refused_per_ip, 0);
+    }
+
+    #[test]
+    fn per_ip_limit() {
+        let control = control(0, 1, 0);
+        let _a = control.admit(ip("10.0.0.1")).unwrap();
+        assert!(control.admit(ip("10.0.0.1")).is_none());
+        // the IPv4-mapped address is the same client
+        assert!(control.admit(ip("::ffff:10.0.0.1")).is_none());
+        let _b = control.admit(ip("10.0.0.2")).unwrap();
+
+        let stats = control.stats();
+        assert_eq!(stats.active_sessions, 2);
+        assert_eq!(stats.refused_per_ip, 2);
+        assert_eq!(stats.refused_total, 0);
+    }
+
+    #[test]
+    fn mapped_addresses_count_toward_the_same_ip() {
+        let control = control(0, 0, 0);
+        let a = control.admit(ip("::ffff:10.0.0.1")).unwrap();
+        let b = control.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(control.sessions.lock().unwrap().per_ip[&ip("10.0.0.1")], 2);
+        drop(a);
+        drop(b);
+        assert!(control.sessions.lock().unwrap().per_ip.is_empty());
+    }
+
+    #[test]
+    fn per_user_limit() {
+        let control = control(0, 0, 1);
+        let mut a = control.admit(ip("10.0.0.1")).unwrap();
+        let mut b = control.admit(ip("10.0.0.2")).unwrap();
+        let mut c = control.admit(ip("10.0.0.3")).unwrap();
+        a.bind_user("alice").unwrap();
+        assert!(b.bind_user("alice").is_err());
+        c.bind_user("bob").unwrap();
+        assert_eq!(control.stats().refused_per_user, 1);
+
+        // a refused login still holds its connection slot until it is closed
+        assert_eq!(control.stats().active_sessions, 3);
+        drop(b);
+        assert_eq!(control.stats().active_sessions, 2);
+
+        drop(a);
+        let mut d = control.admit(ip("10.0.0.4")).unwrap();
+        d.bind_user("alice").unwrap();
+    }
+
+    #[test]
+    fn drop_releases_slots() {
+        let control = control(0, 0, 0);
+        let mut a = control.admit(ip("10.0.0.1")).unwrap();
+        let mut b = control.admit(ip("10.0.0.1")).unwrap();
+        a.bind_user("alice").unwrap();
+        b.bind_user("alice").unwrap();
+
+        drop(a);
+        {
+            let sessions = control.sessions.lock().unwrap();
+            assert_eq!(sessions.total, 1);
+            assert_eq!(sessions.per_ip[&ip("10.0.0.1")], 1);
+            assert_eq!(sessions.per_user["alice"], 1);
+        }
+
+        drop(b);
+        let sessions = control.sessions.lock().unwrap();
+        assert_eq!(sessions.total, 0);
+        assert!(sessions.per_ip.is_empty());
+        assert!(sessions.per_user.is_empty());
+    }
+
+    #[test]
+    fn update_limits_keeps_held_permits() {
+        let control = control(0, 0, 0);
+        let mut a = control.admit(ip("10.0.0.1")).unwrap();
+        let _b = control.admit(ip("10.0.0.1")).unwrap();
+        a.bind_user("alice").unwrap();
+
+        control.update_limits(&ServerConfig {
+            max_sessions: 2,
+            max_sessions_per_ip: 1,
+            max_sessions_per_user: 1,
+            ..ServerConfig::default()
+        });
+        assert_eq!(control.stats().active_sessions, 2);
+        assert!(control.admit(ip("10.0.0.2")).is_none());
+        assert_eq!(control.stats().refused_total, 1);
+
+        drop(a);
+        assert!(control.admit(ip("10.0.0.1")).is_none());
+        assert_eq!(control.stats().refused_per_ip, 1);
+        let mut c = control.admit(ip("10.0.0.2")).unwrap();
+        c.bind_user("alice").unwrap();
+        assert!(control.admit(ip("10.0.0.3")).is_none());
+
+        control.update_limits(&ServerConfig::default());
+        let mut d = control.admit(ip("10.0.0.3")).unwrap();
+        d.bind_user("alice").unwrap();
+        assert_eq!(control.stats().active_sessions, 3);
+    }
+}
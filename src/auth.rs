@@ -0,0 +1,387 @@
+//! Login authentication.
+//!
+//! The server sends a random nonce on the login stream and the client answers
+//! with an HMAC keyed by its password over keying material exported from the
+//! TLS session of the QUIC connection. The proof is bound to both the nonce and
+//! the TLS session, so the password never crosses the wire and a proof captured
+//! by a man-in-the-middle cannot be replayed on another connection.
+//!
+//! When client certificates are required, the identity is taken from the
+//! verified certificate presented during the TLS handshake.
+//!
+//! Logins are decided by an [`Authenticator`]. [`DefaultAuthenticator`] checks
+//! the shared password and user accounts of the [`ServerConfig`]; applications
+//! embedding the server can plug in their own user store instead.
+
+use crate::{ServerConfig, Tunnel};
+use anyhow::{anyhow, bail, Result};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use quinn::Connection;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use rs_utilities::log_and_bail;
+use rustls::pki_types::CertificateDer;
+use std::net::SocketAddr;
+use x509_parser::extensions::GeneralName;
+
+/// Length of the login nonce in bytes.
+pub(crate) const NONCE_LEN: usize = 32;
+const KEYING_MATERIAL_LEN: usize = 32;
+const KEYING_MATERIAL_LABEL: &[u8] = b"EXPORTER-rstun-login";
+
+/// Generate a random nonce for a login challenge.
+pub(crate) fn generate_nonce() -> Result<Vec<u8>> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("failed to generate login nonce"))?;
+    Ok(nonce)
+}
+
+/// Compute the login proof of `username`/`password` for the given nonce.
+pub(crate) fn compute_proof(
+    conn: &Connection,
+    nonce: &[u8],
+    username: &str,
+    password: &str,
+) -> Result<Vec<u8>> {
+    let keying_material = export_keying_material(conn, nonce)?;
+    Ok(proof(&keying_material, nonce, username, password))
+}
+
+/// Verify a login proof in constant time.
+pub(crate) fn verify_proof(
+    keying_material: &[u8],
+    nonce: &[u8],
+    username: &str,
+    password: &str,
+    proof: &[u8],
+) -> bool {
+    let msg = proof_message(keying_material, nonce, username);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, password.as_bytes());
+    hmac::verify(&key, &msg, proof).is_ok()
+}
+
+/// Export the keying material of the TLS session of `conn` for the given nonce.
+fn export_keying_material(conn: &Connection, nonce: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != NONCE_LEN {
+        bail!("invalid login nonce length: {}", nonce.len());
+    }
+
+    let mut keying_material = vec![0u8; KEYING_MATERIAL_LEN];
+    conn.export_keying_material(&mut keying_material, KEYING_MATERIAL_LABEL, nonce)
+        .map_err(|_| anyhow!("failed to export keying material"))?;
+    Ok(keying_material)
+}
+
+fn proof(keying_material: &[u8], nonce: &[u8], username: &str, password: &str) -> Vec<u8> {
+    let msg = proof_message(keying_material, nonce, username);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, password.as_bytes());
+    hmac::sign(&key, &msg).as_ref().to_vec()
+}
+
+fn proof_message(keying_material: &[u8], nonce: &[u8], username: &str) -> Vec<u8> {
+    [keying_material, nonce, username.as_bytes()].concat()
+}
+
+/// Identity of the client certificate presented on the connection, if any.
+///
+/// This is the subject common name, or the first DNS/email subject alternative
+/// name if the subject has no common name.
+pub(crate) fn client_cert_identity(conn: &Connection) -> Option<String> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?).ok()?;
+
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+    {
+        return Some(cn.to_string());
+    }
+
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
+/// Credentials presented by a client logging in.
+pub struct Credentials<'a> {
+    conn: &'a Connection,
+    username: &'a str,
+    nonce: &'a [u8],
+    proof: &'a [u8],
+    cert_identity: Option<String>,
+}
+
+impl<'a> Credentials<'a> {
+    pub(crate) fn new(
+        conn: &'a Connection,
+        username: &'a str,
+        nonce: &'a [u8],
+        proof: &'a [u8],
+    ) -> Self {
+        Credentials {
+            conn,
+            username,
+            nonce,
+            proof,
+            cert_identity: client_cert_identity(conn),
+        }
+    }
+
+    /// Username sent by the client, empty for shared-password logins.
+    pub fn username(&self) -> &str {
+        self.username
+    }
+
+    /// Identity of the verified client certificate, if the client presented one.
+    pub fn cert_identity(&self) -> Option<&str> {
+        self.cert_identity.as_deref()
+    }
+
+    /// Return true if the client proved knowledge of `password`.
+    ///
+    /// The password itself never crosses the wire, so authenticators look up
+    /// the expected password of the user and check it against the login proof.
+    pub fn verify_password(&self, password: &str) -> bool {
+        export_keying_material(self.conn, self.nonce).is_ok_and(|keying_material| {
+            verify_proof(
+                &keying_material,
+                self.nonce,
+                self.username,
+                password,
+                self.proof,
+            )
+        })
+    }
+}
+
+/// A login to be decided by an [`Authenticator`].
+pub struct AuthRequest<'a> {
+    /// Address the client connects from.
+    pub remote_addr: SocketAddr,
+    /// Tunnel the client requests.
+    pub tunnel: &'a Tunnel,
+    /// Credentials presented by the client.
+    pub credentials: Credentials<'a>,
+}
+
+/// Outcome of an [`Authenticator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Accept the login, `identity` becomes the user of the session.
+    Allow { identity: String },
+    /// Reject the login, `reason` is sent back to the client.
+    Deny { reason: String },
+}
+
+/// Decides whether a client may log in.
+pub trait Authenticator: Send + Sync {
+    /// Decide the login described by `request`.
+    fn authenticate<'a>(&'a self, request: AuthRequest<'a>) -> BoxFuture<'a, AuthDecision>;
+}
+
+/// Authenticator checking the shared password, user accounts and client
+/// certificates configured in [`ServerConfig`].
+pub struct DefaultAuthenticator {
+    config: ServerConfig,
+}
+
+impl DefaultAuthenticator {
+    pub fn new(config: &ServerConfig) -> Self {
+        DefaultAuthenticator {
+            config: config.clone(),
+        }
+    }
+
+    /// Verify the client certificate and/or the login proof and return the
+    /// authenticated user, which is empty for logins with the shared password.
+    fn check_credentials(&self, credentials: &Credentials) -> Result<String> {
+        let username = credentials.username();
+        if let Some(cert_identity) = credentials.cert_identity() {
+            if !username.is_empty() && username != cert_identity {
+                log_and_bail!(
+                    "username {username} doesn't match client certificate: {cert_identity}"
+                );
+            }
+
+            if self.config.client_cert_only {
+                if self
+                    .config
+                    .find_user(cert_identity)
+                    .is_some_and(|u| !u.enabled)
+                {
+                    log_and_bail!("user is disabled: {cert_identity}");
+                }
+                return Ok(cert_identity.to_string());
+            }
+        }
+
+        let user = self.check_password_credentials(credentials)?;
+        Ok(credentials
+            .cert_identity()
+            .map(str::to_string)
+            .unwrap_or(user))
+    }
+
+    fn check_password_credentials(&self, credentials: &Credentials) -> Result<String> {
+        let username = credentials.username();
+        if username.is_empty() {
+            if self.config.password.is_empty() {
+                log_and_bail!("login without username is not allowed");
+            }
+            Self::check_password(credentials, &self.config.password)?;
+            return Ok(String::new());
+        }
+
+        match self.config.find_user(username) {
+            Some(user) if !user.enabled => {
+                log_and_bail!("user is disabled: {username}");
+            }
+            Some(user) => {
+                Self::check_password(credentials, &user.password)?;
+                Ok(username.to_string())
+            }
+            None => {
+                log_and_bail!("unknown user: {username}");
+            }
+        }
+    }
+
+    fn check_password(credentials: &Credentials, password: &str) -> Result<()> {
+        if !credentials.verify_password(password) {
+            log_and_bail!("passwords don't match!");
+        }
+        Ok(())
+    }
+}
+
+impl Authenticator for DefaultAuthenticator {
+    fn authenticate<'a>(&'a self, request: AuthRequest<'a>) -> BoxFuture<'a, AuthDecision> {
+        // the detailed error is logged, clients only learn that the login failed
+        let decision = match self.check_credentials(&request.credentials) {
+            Ok(identity) => AuthDecision::Allow { identity },
+            Err(_) => AuthDecision::Deny {
+                reason: "login failed".to_string(),
+            },
+        };
+        futures_util::future::ready(decision).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYING_MATERIAL: [u8; KEYING_MATERIAL_LEN] = [7; KEYING_MATERIAL_LEN];
+    const NONCE: [u8; NONCE_LEN] = [1; NONCE_LEN];
+
+    #[test]
+    fn proof_round_trip() {
+        let user_proof = proof(&KEYING_MATERIAL, &NONCE, "alice", "secret");
+        assert_eq!(user_proof.len(), 32);
+        assert!(verify_proof(
+            &KEYING_MATERIAL,
+            &NONCE,
+            "alice",
+            "secret",
+            &user_proof
+        ));
+
+        let shared_password_proof = proof(&KEYING_MATERIAL, &NONCE, "", "secret");
+        assert!(verify_proof(
+            &KEYING_MATERIAL,
+            &NONCE,
+            "",
+            "secret",
+            &shared_password_proof
+        ));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let proof = proof(&KEYING_MATERIAL, &NONCE, "alice", "secret");
+        assert!(!verify_proof(
+            &KEYING_MATERIAL,
+            &NONCE,
+            "alice",
+            "Secret",
+            &proof
+        ));
+        assert!(!verify_proof(&KEYING_MATERIAL, &NONCE, "alice", "", &proof));
+    }
+
+    #[test]
+    fn wrong_nonce_or_session() {
+        let proof = proof(&KEYING_MATERIAL, &NONCE, "alice", "secret");
+        let mut nonce = NONCE;
+        nonce[NONCE_LEN - 1] ^= 1;
+        assert!(!verify_proof(
+            &KEYING_MATERIAL,
+            &nonce,
+            "alice",
+            "secret",
+            &proof
+        ));
+
+        let mut keying_material = KEYING_MATERIAL;
+        keying_material[0] ^= 1;
+        assert!(!verify_proof(
+            &keying_material,
+            &NONCE,
+            "alice",
+            "secret",
+            &proof
+        ));
+    }
+
+    #[test]
+    fn changed_username() {
+        let proof = proof(&KEYING_MATERIAL, &NONCE, "alice", "secret");
+        assert!(!verify_proof(
+            &KEYING_MATERIAL,
+            &NONCE,
+            "bob",
+            "secret",
+            &proof
+        ));
+        assert!(!verify_proof(
+            &KEYING_MATERIAL,
+            &NONCE,
+            "",
+            "secret",
+            &proof
+        ));
+    }
+
+    #[test]
+    fn wrong_proof_length() {
+        let proof = proof(&KEYING_MATERIAL, &NONCE, "alice", "secret");
+        for len in [0, 1, proof.len() - 1] {
+            assert!(!verify_proof(
+                &KEYING_MATERIAL,
+                &NONCE,
+                "alice",
+                "secret",
+                &proof[..len]
+            ));
+        }
+        let mut longer = proof.clone();
+        longer.push(0);
+        assert!(!verify_proof(
+            &KEYING_MATERIAL,
+            &NONCE,
+            "alice",
+            "secret",
+            &longer
+        ));
+    }
+}
@@ -3,10 +3,12 @@
 //! Connects to an rstund server, authenticates, and starts TCP/UDP tunnels
 //! according to the provided mappings. See --help for details and examples.
 
+use anyhow::Result;
 use clap::builder::PossibleValuesParser;
 use clap::builder::TypedValueParser as _;
 use clap::Parser;
 use log::error;
+use rs_utilities::log_and_bail;
 use rstun::*;
 
 fn main() {
@@ -14,27 +16,9 @@ fn main() {
     let log_filter = format!("rstun={},rs_utilities={}", args.loglevel, args.loglevel);
     rs_utilities::LogHelper::init_logger("rstunc", log_filter.as_str());
 
-    let config = ClientConfig::create(
-        &args.server_addr,
-        &args.password,
-        &args.cert,
-        &args.cipher,
-        &args.tcp_mappings,
-        &args.udp_mappings,
-        &args.dot,
-        &args.dns,
-        args.workers,
-        args.wait_before_retry_ms,
-        args.quic_timeout_ms,
-        args.tcp_timeout_ms,
-        args.udp_timeout_ms,
-        args.hop_interval_ms,
-    )
-    .map_err(|e| {
+    if let Ok(config) = build_config(args).map_err(|e| {
         error!("{e}");
-    });
-
-    if let Ok(config) = config {
+    }) {
         let mut client = Client::new(config);
 
         #[cfg(target_os = "android")]
@@ -50,66 +34,181 @@ fn main() {
     }
 }
 
+/// Set the config fields whose flags were given on the command line.
+macro_rules! override_config {
+    ($config:ident, $($field:ident = $value:expr),* $(,)?) => {
+        $(
+            if let Some(value) = $value {
+                $config.$field = value;
+            }
+        )*
+    };
+}
+
+fn build_config(args: RstuncArgs) -> Result<ClientConfig> {
+    let mut config = if args.config.is_empty() {
+        ClientConfig::default()
+    } else {
+        ClientConfig::from_file(&args.config)?
+    };
+
+    override_config!(
+        config,
+        server_addr = args.server_addr,
+        username = args.user,
+        password = args
+            .password
+            .map(|password| resolve_secret("--password", &password))
+            .transpose()?,
+        cert_path = args.cert,
+        client_cert_path = args.client_cert,
+        client_key_path = args.client_key,
+        cipher = args.cipher,
+        workers = args.workers,
+        wait_before_retry_ms = args.wait_before_retry_ms,
+        quic_timeout_ms = args.quic_timeout_ms,
+        tcp_timeout_ms = args.tcp_timeout_ms,
+        udp_timeout_ms = args.udp_timeout_ms,
+        max_udp_payload_size = args.max_udp_payload_size,
+        hop_interval_ms = args.hop_interval_ms,
+        dot_servers = args.dot.as_deref().map(ClientConfig::split_servers),
+        dns_servers = args.dns.as_deref().map(ClientConfig::split_servers),
+        metrics_server = args.metrics_addr,
+    );
+    if args.multiplex {
+        config.multiplex = true;
+    }
+    if args.udp_datagrams {
+        config.udp_datagrams = true;
+    }
+    if args.tcp_mappings.is_some() || args.udp_mappings.is_some() {
+        config.set_mappings(
+            &args.tcp_mappings.unwrap_or_default(),
+            &args.udp_mappings.unwrap_or_default(),
+        )?;
+    }
+    if let Some(rate_limit) = args.rate_limit {
+        for tunnel in &mut config.tunnels {
+            tunnel.rate_limit.get_or_insert(rate_limit);
+        }
+    }
+    if config.cipher.is_empty() {
+        config.cipher = SUPPORTED_CIPHER_SUITE_STRS[0].to_string();
+    }
+    if config.wait_before_retry_ms == 0 {
+        config.wait_before_retry_ms = 5000;
+    }
+
+    if config.server_addr.is_empty() {
+        log_and_bail!(
+            "the server address must be specified with --server-addr or in the config file"
+        );
+    }
+    if config.tunnels.is_empty() {
+        log_and_bail!("must specify --tcp-mappings, --udp-mappings or tunnels in the config file");
+    }
+    config.validate()?;
+    config.normalize();
+    Ok(config)
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct RstuncArgs {
+    /// Path to a TOML config file with the keys of `ClientConfig` and named [[tunnels]], flags given on the command line override its values.
+    /// --tcp-mappings and --udp-mappings replace the tunnels of the file. The password may be given as env:NAME or file:PATH.
+    #[arg(long, default_value = "", verbatim_doc_comment)]
+    config: String,
+
     /// Server address (<domain:ip>[:port]) of rstund. Default port is 3515.
     #[arg(short = 'a', long)]
-    server_addr: String,
+    server_addr: Option<String>,
+
+    /// Username for server authentication, omit to log in with the server's shared password
+    #[arg(short = 'U', long)]
+    user: Option<String>,
 
-    /// Password for server authentication (must match server's --password)
-    #[arg(short = 'p', long, required = true)]
-    password: String,
+    /// Password for server authentication (must match server's --password or the user's password)
+    #[arg(short = 'p', long)]
+    password: Option<String>,
 
     /// Comma-separated list of TCP tunnel mappings. Each mapping is in the form MODE^[ip:]port^[ip:]port, e.g. OUT^8080^0.0.0.0:9090
     /// MODE is either OUT or IN. Use OUT^8000^ANY to use the server's default upstream for OUT mode.
-    #[arg(short = 't', long, verbatim_doc_comment, default_value = "")]
-    tcp_mappings: String,
+    #[arg(short = 't', long, verbatim_doc_comment)]
+    tcp_mappings: Option<String>,
 
     /// Comma-separated list of UDP tunnel mappings. Each mapping is in the form MODE^[ip:]port^[ip:]port, e.g. OUT^8080^0.0.0.0:9090
     /// MODE is either OUT or IN. Use OUT^8000^ANY to use the server's default upstream for OUT mode.
-    #[arg(short = 'u', long, verbatim_doc_comment, default_value = "")]
-    udp_mappings: String,
+    #[arg(short = 'u', long, verbatim_doc_comment)]
+    udp_mappings: Option<String>,
+
+    /// Throughput limit of each tunnel without its own limit as UPLOAD:DOWNLOAD bytes per second, K/M/G suffixes allowed, e.g. "1M:5M" [0 for unlimited]
+    #[arg(long)]
+    rate_limit: Option<RateLimit>,
 
     /// Path to the certificate file (only needed for self-signed certificates)
-    #[arg(short = 'c', long, default_value = "")]
-    cert: String,
+    #[arg(short = 'c', long)]
+    cert: Option<String>,
 
-    /// Preferred cipher suite
-    #[arg(short = 'e', long, default_value_t = String::from(SUPPORTED_CIPHER_SUITE_STRS[0]),
+    /// Path to a client certificate file, for servers that require client certificates
+    #[arg(long)]
+    client_cert: Option<String>,
+
+    /// Path to the private key file of --client-cert
+    #[arg(long)]
+    client_key: Option<String>,
+
+    /// Preferred cipher suite [default: aes-128-gcm]
+    #[arg(short = 'e', long,
         value_parser = PossibleValuesParser::new(SUPPORTED_CIPHER_SUITE_STRS).map(|v| v.to_string()))]
-    cipher: String,
+    cipher: Option<String>,
 
     /// Number of async worker threads [uses all logical CPUs if 0]
-    #[arg(short = 'w', long, default_value_t = 0)]
-    workers: usize,
+    #[arg(short = 'w', long)]
+    workers: Option<usize>,
+
+    /// Wait time in milliseconds before retrying connection [default: 5000]
+    #[arg(short = 'r', long)]
+    wait_before_retry_ms: Option<u64>,
 
-    /// Wait time in milliseconds before retrying connection
-    #[arg(short = 'r', long, default_value_t = 5000)]
-    wait_before_retry_ms: u64,
+    /// QUIC idle timeout in milliseconds [default: 30000]
+    #[arg(long)]
+    quic_timeout_ms: Option<u64>,
 
-    /// QUIC idle timeout in milliseconds
-    #[arg(long, default_value_t = 30000)]
-    quic_timeout_ms: u64,
+    /// TCP idle timeout in milliseconds [default: 30000]
+    #[arg(long)]
+    tcp_timeout_ms: Option<u64>,
 
-    /// TCP idle timeout in milliseconds
-    #[arg(long, default_value_t = 30000)]
-    tcp_timeout_ms: u64,
+    /// UDP idle timeout in milliseconds [default: 5000]
+    #[arg(long)]
+    udp_timeout_ms: Option<u64>,
 
-    /// UDP idle timeout in milliseconds
-    #[arg(long, default_value_t = 5000)]
-    udp_timeout_ms: u64,
+    /// Largest UDP payload in bytes to forward, up to 65507, larger datagrams are dropped [default: 1500]
+    #[arg(long)]
+    max_udp_payload_size: Option<usize>,
 
-    #[arg(long, default_value_t = 0)]
-    hop_interval_ms: u64,
+    #[arg(long)]
+    hop_interval_ms: Option<u64>,
+
+    /// Carry all tunnels over a single connection, falls back to one connection per tunnel if the server can't
+    #[arg(long)]
+    multiplex: bool,
+
+    /// Send UDP payloads as QUIC datagrams, avoiding head-of-line blocking between packets (if the server supports it)
+    #[arg(long)]
+    udp_datagrams: bool,
 
     /// Comma-separated DoT servers (domains) for DNS resolution, e.g. "dns.google,one.one.one.one". Takes precedence over --dns if set.
-    #[arg(long, verbatim_doc_comment, default_value = "")]
-    dot: String,
+    #[arg(long, verbatim_doc_comment)]
+    dot: Option<String>,
 
     /// Comma-separated DNS servers (IPs) for DNS resolution, e.g. "1.1.1.1,8.8.8.8"
-    #[arg(long, verbatim_doc_comment, default_value = "")]
-    dns: String,
+    #[arg(long, verbatim_doc_comment)]
+    dns: Option<String>,
+
+    /// Address (ip:port) to serve Prometheus metrics on at /metrics, e.g. 127.0.0.1:9091 [disabled if empty]
+    #[arg(long)]
+    metrics_addr: Option<String>,
 
     /// Log level
     #[arg(short = 'l', long, default_value_t = String::from("I"),
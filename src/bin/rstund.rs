@@ -5,7 +5,7 @@
 //!
 //! This binary is built as part of the `rstun` crate.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::builder::PossibleValuesParser;
 use clap::builder::TypedValueParser as _;
 use clap::Parser;
@@ -13,7 +13,10 @@ use log::error;
 use log::info;
 use rs_utilities::log_and_bail;
 use rstun::*;
-use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Time clients logging in get to learn that the server is shutting down.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 fn main() {
     let args = RstundArgs::parse();
@@ -43,36 +46,152 @@ fn main() {
         })
 }
 
-async fn run(mut args: RstundArgs) -> Result<()> {
-    if args.addr.is_empty() {
-        args.addr = "0.0.0.0:0".to_string();
+/// Set the config fields whose flags were given on the command line.
+macro_rules! override_config {
+    ($config:ident, $($field:ident = $value:expr),* $(,)?) => {
+        $(
+            if let Some(value) = $value {
+                $config.$field = value;
+            }
+        )*
+    };
+}
+
+async fn run(args: RstundArgs) -> Result<()> {
+    let config = load_config(args.clone())?;
+    let mut server = Server::new(config);
+    // re-read the config file and the files given by flags on reload
+    server.set_config_loader(move || load_config(args.clone()));
+    server.bind()?;
+    #[cfg(unix)]
+    server.reload_on_sighup()?;
+
+    let serve = server.serve();
+    tokio::pin!(serve);
+    tokio::select! {
+        result = &mut serve => {
+            server.save_quota_usage();
+            return result;
+        }
+        result = shutdown_signal() => result?,
     }
+    server.shutdown();
+    // keep serving for a moment, so that clients logging in learn why they are rejected
+    tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, serve)
+        .await
+        .ok();
+    server.save_quota_usage();
+    Ok(())
+}
 
-    if !args.addr.contains(':') {
-        args.addr = format!("127.0.0.1:{}", args.addr);
+/// Wait for Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .context("failed to listen for SIGTERM")?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = sigterm.recv() => {}
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
 
-    let config = ServerConfig {
-        addr: args.addr,
-        password: args.password,
-        cert_path: args.cert,
-        key_path: args.key,
-        default_tcp_upstream: parse_upstreams("tcp", &args.tcp_upstream)?,
-        default_udp_upstream: parse_upstreams("udp", &args.udp_upstream)?,
-        quic_timeout_ms: args.quic_timeout_ms,
-        tcp_timeout_ms: args.tcp_timeout_ms,
-        udp_timeout_ms: args.udp_timeout_ms,
-        dashboard_server: "".to_string(),
-        dashboard_server_credential: "".to_string(),
+/// Build the config from the config file, if any, and the flags.
+fn load_config(args: RstundArgs) -> Result<ServerConfig> {
+    let mut config = if args.config.is_empty() {
+        ServerConfig::default()
+    } else {
+        ServerConfig::from_file(&args.config)?
     };
 
-    let mut server = Server::new(config);
-    server.bind()?;
-    server.serve().await?;
-    Ok(())
+    override_config!(
+        config,
+        addr = args.addr,
+        password = args
+            .password
+            .map(|password| resolve_secret("--password", &password))
+            .transpose()?,
+        users = args.users.map(|path| load_users(&path)).transpose()?,
+        cert_path = args.cert,
+        key_path = args.key,
+        client_ca_path = args.client_ca,
+        default_tcp_upstream = args
+            .tcp_upstream
+            .map(|upstream| parse_upstreams("tcp", &upstream))
+            .transpose()?,
+        default_udp_upstream = args
+            .udp_upstream
+            .map(|upstream| parse_upstreams("udp", &upstream))
+            .transpose()?,
+        upstream_acl = args.upstream_acl.map(|acl| acl.parse()).transpose()?,
+        in_port_ranges = args
+            .in_ports
+            .map(|ports| PortRange::parse_list(&ports))
+            .transpose()?,
+        rate_limit = args.rate_limit.map(|limit| limit.parse()).transpose()?,
+        tunnel_rate_limit = args
+            .tunnel_rate_limit
+            .map(|limit| limit.parse())
+            .transpose()?,
+        quota_state_path = args.quota_state,
+        max_sessions = args.max_sessions,
+        max_sessions_per_ip = args.max_sessions_per_ip,
+        max_sessions_per_user = args.max_sessions_per_user,
+        max_streams_per_conn = args.max_streams_per_conn,
+        max_new_streams_per_sec = args.max_new_streams_per_sec,
+        max_login_failures = args.max_login_failures,
+        login_ban_secs = args.login_ban_secs,
+        quic_timeout_ms = args.quic_timeout_ms,
+        tcp_timeout_ms = args.tcp_timeout_ms,
+        udp_timeout_ms = args.udp_timeout_ms,
+        max_udp_payload_size = args.max_udp_payload_size,
+        dashboard_server = args.dashboard_server,
+        dashboard_server_credential = args
+            .dashboard_credential
+            .map(|credential| resolve_secret("--dashboard-credential", &credential))
+            .transpose()?,
+        metrics_server = args.metrics_addr,
+    );
+    if args.client_cert_only {
+        config.client_cert_only = true;
+    }
+
+    if config.addr.is_empty() {
+        log_and_bail!(
+            "the address to listen on must be specified with --addr or in the config file"
+        );
+    }
+
+    if !config.addr.contains(':') {
+        config.addr = format!("127.0.0.1:{}", config.addr);
+    }
+
+    config.validate()?;
+    Ok(config)
 }
 
-fn parse_upstreams(upstream_type: &str, upstreams_str: &str) -> Result<Option<SocketAddr>> {
+fn load_users(path: &str) -> Result<Vec<UserConfig>> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let content =
+        std::fs::read_to_string(path).context(format!("failed to read users file: {path}"))?;
+    let mut users: Vec<UserConfig> =
+        serde_json::from_str(&content).context(format!("invalid users file: {path}"))?;
+    for user in &mut users {
+        let key = format!("{path}: {}.password", user.username);
+        user.password = resolve_secret(&key, &user.password)?;
+    }
+    info!("loaded {} users from {path}", users.len());
+    Ok(users)
+}
+
+fn parse_upstreams(upstream_type: &str, upstreams_str: &str) -> Result<Option<UpstreamAddr>> {
     if upstreams_str.is_empty() {
         return Ok(None);
     }
@@ -93,66 +212,131 @@ fn parse_upstreams(upstream_type: &str, upstreams_str: &str) -> Result<Option<So
     }
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct RstundArgs {
+    /// Path to a TOML config file with the keys of `ServerConfig`, flags given on the command line override its values.
+    /// Secrets in the file and in flags may be given as env:NAME or file:PATH. The file is re-read on SIGHUP.
+    #[arg(long, default_value = "", verbatim_doc_comment)]
+    config: String,
+
     /// Address ([ip:]port) to listen on. If only a port is given, binds to 127.0.0.1:PORT.
-    #[arg(
-        short = 'a',
-        long,
-        required = true,
-        default_value = "",
-        verbatim_doc_comment
-    )]
-    addr: String,
-
-    /// Default TCP upstream for OUT tunnels ([ip:]port). Used if client does not specify an upstream.
-    #[arg(
-        short = 't',
-        long,
-        required = false,
-        default_value = "",
-        verbatim_doc_comment
-    )]
-    tcp_upstream: String,
-
-    /// Default UDP upstream for OUT tunnels ([ip:]port). Used if client does not specify an upstream.
-    #[arg(
-        short = 'u',
-        long,
-        required = false,
-        default_value = "",
-        verbatim_doc_comment
-    )]
-    udp_upstream: String,
-
-    /// Server password (required, must match client --password)
-    #[arg(short = 'p', long, required = true)]
-    password: String,
+    #[arg(short = 'a', long, verbatim_doc_comment)]
+    addr: Option<String>,
+
+    /// Default TCP upstream for OUT tunnels ([ip:]port or domain:port). Used if client does not specify an upstream.
+    #[arg(short = 't', long, verbatim_doc_comment)]
+    tcp_upstream: Option<String>,
+
+    /// Default UDP upstream for OUT tunnels ([ip:]port or domain:port). Used if client does not specify an upstream.
+    #[arg(short = 'u', long, verbatim_doc_comment)]
+    udp_upstream: Option<String>,
+
+    /// Comma-separated upstream ACL rules for OUT tunnels, first match wins, unmatched upstreams are allowed.
+    /// Each rule is ACTION^NETWORK[^PORTS], e.g. "DENY^169.254.0.0/16,ALLOW^10.0.0.0/8^80-443,DENY^ANY"
+    #[arg(long, verbatim_doc_comment)]
+    upstream_acl: Option<String>,
+
+    /// Comma-separated ports or port ranges IN tunnels may listen on, e.g. "9000-9100,10000" [any port if empty]
+    #[arg(long)]
+    in_ports: Option<String>,
+
+    /// Throughput limit shared by all clients as UPLOAD:DOWNLOAD bytes per second, K/M/G suffixes allowed, e.g. "10M:50M" [default: 0:0, unlimited]
+    #[arg(long)]
+    rate_limit: Option<String>,
+
+    /// Throughput limit of each tunnel as UPLOAD:DOWNLOAD bytes per second, e.g. "1M:5M" [default: 0:0, unlimited]
+    #[arg(long)]
+    tunnel_rate_limit: Option<String>,
+
+    /// Path to a JSON file persisting the traffic usage of users for quotas across restarts
+    #[arg(long)]
+    quota_state: Option<String>,
+
+    /// Maximum number of concurrent sessions [default: 0, unlimited]
+    #[arg(long)]
+    max_sessions: Option<usize>,
+
+    /// Maximum number of concurrent sessions from one source IP [default: 0, unlimited]
+    #[arg(long)]
+    max_sessions_per_ip: Option<usize>,
+
+    /// Maximum number of concurrent sessions of one user [default: 0, unlimited]
+    #[arg(long)]
+    max_sessions_per_user: Option<usize>,
+
+    /// Maximum number of concurrent streams on a connection [default: 1024]
+    #[arg(long)]
+    max_streams_per_conn: Option<u32>,
+
+    /// Maximum number of new streams per second on a connection [default: 0, unlimited]
+    #[arg(long)]
+    max_new_streams_per_sec: Option<u32>,
+
+    /// Failed logins after which the source IP is temporarily banned [default: 5, protection disabled if 0]
+    #[arg(long)]
+    max_login_failures: Option<u32>,
+
+    /// How long a source IP stays banned after too many failed logins, in seconds [default: 600]
+    #[arg(long)]
+    login_ban_secs: Option<u64>,
+
+    /// Shared password for clients logging in without a username (must match client --password),
+    /// prefer env:NAME or file:PATH over the literal password, which is visible in the process list
+    #[arg(short = 'p', long, verbatim_doc_comment)]
+    password: Option<String>,
+
+    /// Path to a JSON file with user accounts, e.g. [{"username":"alice","password":"secret","enabled":true}]
+    #[arg(long, verbatim_doc_comment)]
+    users: Option<String>,
 
     /// Path to certificate file (optional). If empty, a self-signed certificate for "localhost" is generated (testing only).
-    #[arg(short = 'c', long, default_value = "", verbatim_doc_comment)]
-    cert: String,
+    #[arg(short = 'c', long, verbatim_doc_comment)]
+    cert: Option<String>,
 
     /// Path to key file (optional, only needed if --cert is set)
-    #[arg(short = 'k', long, default_value = "")]
-    key: String,
+    #[arg(short = 'k', long)]
+    key: Option<String>,
+
+    /// Path to a CA certificate file; when set, clients must present a certificate signed by it
+    #[arg(long)]
+    client_ca: Option<String>,
+
+    /// Accept a valid client certificate without a password, the certificate subject becomes the user
+    #[arg(long, default_value_t = false)]
+    client_cert_only: bool,
+
+    /// Address ([ip:]port) of the admin HTTP API, e.g. 127.0.0.1:3516 [disabled if empty]
+    #[arg(long)]
+    dashboard_server: Option<String>,
+
+    /// Credential of the admin HTTP API in the form user:password (HTTP Basic authentication)
+    #[arg(long)]
+    dashboard_credential: Option<String>,
+
+    /// Address (ip:port) to serve Prometheus metrics on at /metrics, e.g. 127.0.0.1:9090 [disabled if empty]
+    #[arg(long)]
+    metrics_addr: Option<String>,
 
     /// Number of async worker threads [uses all logical CPUs if 0]
     #[arg(short = 'w', long, default_value_t = 0)]
     workers: usize,
 
-    /// QUIC idle timeout in milliseconds
-    #[arg(long, default_value_t = 40000)]
-    quic_timeout_ms: u64,
+    /// QUIC idle timeout in milliseconds [default: 40000]
+    #[arg(long)]
+    quic_timeout_ms: Option<u64>,
+
+    /// TCP idle timeout in milliseconds [default: 30000]
+    #[arg(long)]
+    tcp_timeout_ms: Option<u64>,
 
-    /// TCP idle timeout in milliseconds
-    #[arg(long, default_value_t = 30000)]
-    tcp_timeout_ms: u64,
+    /// UDP idle timeout in milliseconds [default: 5000]
+    #[arg(long)]
+    udp_timeout_ms: Option<u64>,
 
-    /// UDP idle timeout in milliseconds
-    #[arg(long, default_value_t = 5000)]
-    udp_timeout_ms: u64,
+    /// Largest UDP payload in bytes to forward, up to 65507, larger datagrams are dropped [default: 1500]
+    #[arg(long)]
+    max_udp_payload_size: Option<usize>,
 
     /// Log level
     #[arg(short = 'l', long, default_value_t = String::from("I"),
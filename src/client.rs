@@ -1,18 +1,27 @@
 use crate::{
+    auth,
+    metrics::{MetricsServer, METRICS},
+    mux::{self, StreamHandler, TunnelId},
     pem_util, socket_addr_with_unspecified_ip_port,
     tcp::{tcp_tunnel::TcpTunnel, AsyncStream, StreamReceiver, StreamRequest},
     tunnel_info_bridge::{TunnelInfo, TunnelInfoBridge, TunnelInfoType, TunnelTraffic},
-    tunnel_message::TunnelMessage,
-    udp::{udp_server::UdpServer, udp_tunnel::UdpTunnel, UdpReceiver, UdpSender},
-    ClientConfig, LoginInfo, SelectedCipherSuite, TcpServer, Tunnel, TunnelConfig, TunnelMode,
-    UpstreamType,
+    tunnel_message::{
+        parse_quota_close_reason, CloseCode, Features, Hello, LoginErrorCode, LoginFailure,
+        TunnelMessage, TunnelStatus,
+    },
+    udp::{
+        datagram::Datagrams, udp_server::UdpServer, udp_tunnel::UdpTunnel, UdpReceiver, UdpSender,
+    },
+    util::rate_limiter::{LimitBuckets, TrafficLimiter},
+    ClientConfig, LoginInfo, RateLimit, SelectedCipherSuite, TcpServer, Tunnel, TunnelConfig,
+    TunnelMode, UpstreamType,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use backon::ExponentialBuilder;
 use backon::Retryable;
 use log::{debug, error, info, warn};
 use quinn::{congestion, crypto::rustls::QuicClientConfig, Connection, Endpoint, TransportConfig};
-use quinn::{IdleTimeout, VarInt};
+use quinn::{ConnectionError, IdleTimeout, VarInt};
 use rs_utilities::dns::{self, DNSQueryOrdering, DNSResolverConfig, DNSResolverLookupIpStrategy};
 use rs_utilities::log_and_bail;
 use rustls::{
@@ -35,6 +44,14 @@ use tokio::net::TcpStream;
 const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S.%3f";
 const DEFAULT_SERVER_PORT: u16 = 3515;
 const POST_TRAFFIC_DATA_INTERVAL_SECS: u64 = 30;
+const CHANNEL_TCP_TUNNEL_NAME: &str = "channel-tcp";
+const CHANNEL_UDP_TUNNEL_NAME: &str = "channel-udp";
+const MULTIPLEXED_TUNNEL_NAME: &str = "mux";
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Least time to wait before logging in again to a server shutting down.
+const SHUTTING_DOWN_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Time to wait for an exhausted quota to be reset if the server doesn't say.
+const QUOTA_EXCEEDED_RETRY_DELAY: Duration = Duration::from_secs(600);
 static INIT: Once = Once::new();
 
 #[derive(Clone, Serialize, PartialEq)]
@@ -67,7 +84,10 @@ struct State {
     tcp_servers: HashMap<SocketAddr, TcpServer>,
     udp_servers: HashMap<SocketAddr, UdpServer>,
     endpoint: Option<Endpoint>,
-    connections: HashMap<SocketAddr, Connection>,
+    /// Live connections keyed by the local address of a network-based
+    /// tunnel, the server address of a multiplexed one or the name of a
+    /// channel-based one.
+    connections: HashMap<String, Connection>,
     client_state: ClientState,
     total_traffic_data: TunnelTraffic,
     tunnel_info_bridge: TunnelInfoBridge,
@@ -98,6 +118,37 @@ impl State {
     }
 }
 
+/// Login error of a multiplexed tunnel when the server can't multiplex.
+#[derive(Debug)]
+struct MultiplexUnsupported;
+
+impl Display for MultiplexUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "multiplexing is not supported by the server")
+    }
+}
+
+impl std::error::Error for MultiplexUnsupported {}
+
+/// What a tunnel does after the server closed its connection.
+enum ServerClose {
+    /// Connect again, backing off as usual if that fails.
+    Reconnect,
+    /// Connect again once the delay has passed, e.g. after a quota is reset.
+    ReconnectAfter(Duration),
+    /// Stop the tunnel for good.
+    Stop,
+}
+
+/// An authenticated connection to the server.
+struct Session {
+    conn: Connection,
+    /// Features negotiated with the server.
+    features: Features,
+    /// Status of each tunnel of a multiplexed login.
+    tunnel_statuses: Option<Vec<TunnelStatus>>,
+}
+
 struct LoginConfig {
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
@@ -154,26 +205,54 @@ impl Client {
     }
 
     /// Spawn async tasks for network/channel-based tunnels; does not block.
+    ///
+    /// With [ClientConfig::multiplex] all tunnels share one connection, unless
+    /// the server can't multiplex, in which case each gets its own.
     pub fn connect_and_serve_async(&mut self) {
-        for (index, tunnel_config) in self.config.tunnels.iter().cloned().enumerate() {
+        if self.config.multiplex && !self.config.tunnels.is_empty() {
             let mut this = self.clone();
             tokio::spawn(async move {
-                this.connect_and_serve::<TcpStream>(
-                    index,
-                    Tunnel::NetworkBased(tunnel_config),
-                    None,
-                    None,
-                )
-                .await;
+                let tunnel = Tunnel::Multiplexed(this.config.tunnels.clone());
+                if let Err(e) = this
+                    .connect_and_serve::<TcpStream>(
+                        MULTIPLEXED_TUNNEL_NAME.to_string(),
+                        tunnel,
+                        None,
+                        None,
+                    )
+                    .await
+                {
+                    warn!("{e}, will use one connection per tunnel");
+                    this.spawn_network_based_tunnels();
+                }
             });
+        } else {
+            self.spawn_network_based_tunnels();
         }
 
         self.report_traffic_data_in_background();
+        self.start_metrics_server();
         if self.config.hop_interval_ms > 0 {
             self.start_migration_task();
         }
     }
 
+    fn spawn_network_based_tunnels(&self) {
+        for tunnel_config in self.config.tunnels.iter().cloned() {
+            let mut this = self.clone();
+            tokio::spawn(async move {
+                this.connect_and_serve::<TcpStream>(
+                    tunnel_config.name.clone(),
+                    Tunnel::NetworkBased(tunnel_config),
+                    None,
+                    None,
+                )
+                .await
+                .ok();
+            });
+        }
+    }
+
     /// Connect and serve a channel-based TCP tunnel using an external stream receiver.
     pub fn connect_and_serve_tcp_async<S: AsyncStream>(
         &mut self,
@@ -182,12 +261,13 @@ impl Client {
         let mut this = self.clone();
         tokio::spawn(async move {
             this.connect_and_serve::<S>(
-                0,
+                CHANNEL_TCP_TUNNEL_NAME.to_string(),
                 Tunnel::ChannelBased(UpstreamType::Tcp),
                 Some(stream_receiver),
                 None,
             )
-            .await;
+            .await
+            .ok();
         });
     }
 
@@ -196,12 +276,36 @@ impl Client {
         let mut this = self.clone();
         tokio::spawn(async move {
             this.connect_and_serve::<TcpStream>(
-                0,
+                CHANNEL_UDP_TUNNEL_NAME.to_string(),
                 Tunnel::ChannelBased(UpstreamType::Udp),
                 None,
                 Some(ch),
             )
-            .await;
+            .await
+            .ok();
+        });
+    }
+
+    fn start_metrics_server(&self) {
+        if self.config.metrics_server.is_empty() {
+            return;
+        }
+
+        let addr = self.config.metrics_server.clone();
+        let state = self.inner_state.clone();
+        tokio::spawn(async move {
+            let connections = move || {
+                state
+                    .lock()
+                    .unwrap()
+                    .connections
+                    .iter()
+                    .map(|(key, conn)| (key.clone(), conn.clone()))
+                    .collect()
+            };
+            if let Err(e) = MetricsServer::start(&addr, Arc::new(connections)).await {
+                error!("{e:?}");
+            }
         });
     }
 
@@ -261,7 +365,8 @@ impl Client {
     /// Start a local UDP server for an OUT tunnel and return its handle.
     pub async fn start_udp_server(&self, addr: SocketAddr) -> Result<UdpServer> {
         // create a local udp server for 'OUT' tunnel
-        let bind_udp_server = || async { UdpServer::bind_and_start(addr).await };
+        let bind_udp_server =
+            || async { UdpServer::bind_and_start(addr, self.config.max_udp_payload_size).await };
         let udp_server = bind_udp_server
             .retry(
                 ExponentialBuilder::default()
@@ -346,20 +451,29 @@ impl Client {
         while tasks.join_next().await.is_some() {}
     }
 
+    /// Connect, log in and serve `tunnel`, reconnecting until the client is
+    /// stopped or the login fails for a reason retrying can't fix. Fails only
+    /// if `tunnel` is multiplexed and the server can't multiplex.
     async fn connect_and_serve<S: AsyncStream>(
         &mut self,
-        index: usize,
+        name: String,
         tunnel: Tunnel,
         mut stream_receiver: Option<StreamReceiver<S>>,
         mut ch: Option<(UdpSender, UdpReceiver)>,
-    ) {
+    ) -> Result<()> {
         let login_info = LoginInfo {
-            password: self.config.password.clone(),
+            username: self.config.username.clone(),
             tunnel: tunnel.clone(),
         };
 
         let mut pending_network_based_stream = None;
         let mut pending_channel_based_stream = None;
+        let mut pending_multiplexed_streams = match &tunnel {
+            Tunnel::Multiplexed(tunnel_configs) => std::iter::repeat_with(|| None)
+                .take(tunnel_configs.len())
+                .collect(),
+            _ => Vec::new(),
+        };
         loop {
             let connect = || async {
                 let login_cfg = self.prepare_login_config().await?;
@@ -374,9 +488,9 @@ impl Client {
                     endpoint
                 };
 
-                let conn = self
+                let login_result = self
                     .login(
-                        index,
+                        &name,
                         &endpoint,
                         &login_info,
                         &login_cfg.remote_addr,
@@ -384,7 +498,7 @@ impl Client {
                     )
                     .await?;
 
-                Ok(conn)
+                Ok(login_result)
             };
             let result = connect
                 .retry(
@@ -392,10 +506,28 @@ impl Client {
                         .with_max_delay(Duration::from_secs(10))
                         .with_max_times(usize::MAX),
                 )
-                .when(|_| !self.should_quit())
+                .when(|e: &anyhow::Error| {
+                    !self.should_quit()
+                        && !e.is::<MultiplexUnsupported>()
+                        && !e
+                            .downcast_ref::<LoginFailure>()
+                            .is_some_and(|failure| failure.code.is_permanent())
+                })
+                .adjust(
+                    |e: &anyhow::Error, dur| match e.downcast_ref::<LoginFailure>() {
+                        Some(failure) if failure.code == LoginErrorCode::ShuttingDown => {
+                            dur.map(|dur| dur.max(SHUTTING_DOWN_RETRY_DELAY))
+                        }
+                        Some(failure) if failure.code == LoginErrorCode::QuotaExceeded => {
+                            let delay = failure.retry_after().unwrap_or(QUOTA_EXCEEDED_RETRY_DELAY);
+                            dur.map(|dur| dur.max(delay))
+                        }
+                        _ => dur,
+                    },
+                )
                 .sleep(tokio::time::sleep)
                 .notify(|err: &anyhow::Error, dur: Duration| {
-                    warn!("will retry after {dur:?}, err: {err:?}");
+                    warn!("will retry after {dur:?}, err: {err:#}");
                 })
                 .await;
 
@@ -404,71 +536,120 @@ impl Client {
             }
 
             match result {
-                Ok(conn) => match &tunnel {
-                    Tunnel::NetworkBased(tunnel_config) => {
-                        let local_server_addr = tunnel_config.local_server_addr.unwrap();
-                        inner_state!(self, connections).insert(local_server_addr, conn.clone());
-
-                        self.handle_network_based_tunnel(
-                            index,
-                            conn.clone(),
-                            tunnel_config,
-                            &mut pending_network_based_stream,
-                        )
-                        .await;
-
-                        inner_state!(self, connections).remove(&local_server_addr);
-                    }
-                    Tunnel::ChannelBased(upstream_type) => match upstream_type {
-                        UpstreamType::Tcp => {
-                            self.post_tunnel_log(
-                                format!(
-                                    "{index}:STREAM_OUT start serving via {}",
-                                    conn.remote_address()
-                                )
-                                .as_str(),
-                            );
-                            self.set_and_post_tunnel_state(ClientState::Tunneling);
-
-                            let stream_receiver = stream_receiver.as_mut().unwrap();
-                            TcpTunnel::start_serving(
-                                true,
-                                &conn,
-                                stream_receiver,
-                                &mut pending_channel_based_stream,
-                                self.config.tcp_timeout_ms,
+                Ok(session) => {
+                    let conn = session.conn;
+                    let datagrams = session
+                        .features
+                        .contains(Features::DATAGRAM)
+                        .then(|| Datagrams::start(&conn));
+                    match &tunnel {
+                        Tunnel::NetworkBased(tunnel_config) => {
+                            let local_server_addr = tunnel_config.local_server_addr.unwrap();
+                            inner_state!(self, connections)
+                                .insert(local_server_addr.to_string(), conn.clone());
+
+                            self.handle_network_based_tunnel(
+                                &name,
+                                conn.clone(),
+                                datagrams,
+                                tunnel_config,
+                                &mut pending_network_based_stream,
                             )
                             .await;
-                        }
 
-                        UpstreamType::Udp => {
-                            self.post_tunnel_log(
-                                format!(
-                                    "{index}:UDP_OUT start serving via {}",
-                                    conn.remote_address()
-                                )
-                                .as_str(),
-                            );
-                            self.set_and_post_tunnel_state(ClientState::Tunneling);
-
-                            let ch = ch.as_mut().unwrap();
-                            UdpTunnel::start_serving(
-                                &conn,
-                                &ch.0,
-                                &mut ch.1,
-                                self.config.udp_timeout_ms,
+                            inner_state!(self, connections).remove(&local_server_addr.to_string());
+                        }
+                        Tunnel::ChannelBased(upstream_type) => {
+                            inner_state!(self, connections).insert(name.clone(), conn.clone());
+                            match upstream_type {
+                                UpstreamType::Tcp => {
+                                    self.post_tunnel_log(
+                                        format!(
+                                            "{name}:STREAM_OUT start serving via {}",
+                                            conn.remote_address()
+                                        )
+                                        .as_str(),
+                                    );
+                                    self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+                                    let stream_receiver = stream_receiver.as_mut().unwrap();
+                                    TcpTunnel::start_serving(
+                                        true,
+                                        &conn,
+                                        None,
+                                        stream_receiver,
+                                        &mut pending_channel_based_stream,
+                                        self.create_channel_traffic_limiter(),
+                                        self.config.tcp_timeout_ms,
+                                    )
+                                    .await;
+                                }
+
+                                UpstreamType::Udp => {
+                                    self.post_tunnel_log(
+                                        format!(
+                                            "{name}:UDP_OUT start serving via {}",
+                                            conn.remote_address()
+                                        )
+                                        .as_str(),
+                                    );
+                                    self.set_and_post_tunnel_state(ClientState::Tunneling);
+
+                                    let ch = ch.as_mut().unwrap();
+                                    UdpTunnel::start_serving(
+                                        &conn,
+                                        None,
+                                        datagrams,
+                                        &ch.0,
+                                        &mut ch.1,
+                                        self.create_channel_traffic_limiter(),
+                                        self.config.udp_timeout_ms,
+                                    )
+                                    .await;
+                                }
+                            }
+
+                            inner_state!(self, connections).remove(&name);
+                            self.add_traffic_data(&conn);
+                        }
+                        Tunnel::Multiplexed(tunnel_configs) => {
+                            let remote_addr = conn.remote_address().to_string();
+                            inner_state!(self, connections)
+                                .insert(remote_addr.clone(), conn.clone());
+
+                            self.handle_multiplexed_tunnels(
+                                conn.clone(),
+                                datagrams,
+                                tunnel_configs,
+                                &session.tunnel_statuses.unwrap_or_default(),
+                                &mut pending_multiplexed_streams,
                             )
                             .await;
+
+                            inner_state!(self, connections).remove(&remote_addr);
                         }
-                    },
-                },
+                    }
+
+                    match self.handle_server_close(&name, &login_info, &conn) {
+                        ServerClose::Reconnect => {}
+                        ServerClose::ReconnectAfter(delay) => tokio::time::sleep(delay).await,
+                        ServerClose::Stop => break,
+                    }
+                }
+
+                Err(e) if e.is::<MultiplexUnsupported>() => return Err(e),
 
                 Err(e) => {
-                    error!("{e}");
-                    info!(
-                        "[{login_info}] quit after having retried for {} times",
-                        usize::MAX
-                    );
+                    error!("{e:#}");
+                    match e.downcast_ref::<LoginFailure>() {
+                        Some(failure) if failure.code.is_permanent() => {
+                            info!("{name}:{login_info} quit, will not retry: {}", failure.code);
+                        }
+                        _ => info!(
+                            "{name}:{login_info} quit after having retried for {} times",
+                            usize::MAX
+                        ),
+                    }
                     break;
                 }
             };
@@ -477,52 +658,211 @@ impl Client {
                 break;
             }
         }
-        self.post_tunnel_log(format!("[{login_info}] quit").as_str());
+        self.post_tunnel_log(format!("{name}:{login_info} quit").as_str());
+        Ok(())
     }
 
     async fn handle_network_based_tunnel(
         &mut self,
-        index: usize,
+        name: &str,
         conn: Connection,
+        datagrams: Option<Arc<Datagrams>>,
         tunnel_config: &TunnelConfig,
         pending_request: &mut Option<StreamRequest<TcpStream>>,
     ) {
         let upstream_type = &tunnel_config.upstream.upstream_type;
         let local_server_addr = tunnel_config.local_server_addr.unwrap();
+        let limiter = Self::create_traffic_limiter(tunnel_config);
+        let timeout_ms = tunnel_config.idle_timeout_ms(&self.config);
 
         if tunnel_config.mode == TunnelMode::Out {
             match upstream_type {
                 UpstreamType::Tcp => {
                     self.serve_outbound_tcp(
-                        index,
+                        name,
                         conn.clone(),
                         local_server_addr,
+                        None,
                         pending_request,
+                        limiter,
+                        timeout_ms,
                     )
                     .await
                     .ok();
                 }
                 UpstreamType::Udp => {
-                    self.serve_outbound_udp(index, conn.clone(), local_server_addr)
-                        .await
-                        .ok();
+                    self.serve_outbound_udp(
+                        name,
+                        conn.clone(),
+                        local_server_addr,
+                        None,
+                        datagrams,
+                        limiter,
+                        timeout_ms,
+                    )
+                    .await
+                    .ok();
                 }
             }
         } else {
             match upstream_type {
                 UpstreamType::Tcp => {
-                    self.serve_inbound_tcp(index, conn.clone(), local_server_addr)
-                        .await
-                        .ok();
+                    self.serve_inbound_tcp(
+                        name,
+                        conn.clone(),
+                        local_server_addr,
+                        limiter,
+                        timeout_ms,
+                    )
+                    .await
+                    .ok();
                 }
                 UpstreamType::Udp => {
-                    self.serve_inbound_udp(index, conn.clone(), local_server_addr)
-                        .await
-                        .ok();
+                    self.serve_inbound_udp(
+                        name,
+                        conn.clone(),
+                        datagrams,
+                        local_server_addr,
+                        limiter,
+                        timeout_ms,
+                    )
+                    .await
+                    .ok();
                 }
             }
         }
 
+        self.add_traffic_data(&conn);
+    }
+
+    /// Serve the tunnels of a multiplexed connection until it is closed.
+    ///
+    /// OUT tunnels are served by tasks of their own, whose pending requests
+    /// are kept in `pending_requests` for the next connection. Streams opened
+    /// by the server for IN tunnels are dispatched by their tunnel id.
+    async fn handle_multiplexed_tunnels(
+        &mut self,
+        conn: Connection,
+        datagrams: Option<Arc<Datagrams>>,
+        tunnel_configs: &[TunnelConfig],
+        tunnel_statuses: &[TunnelStatus],
+        pending_requests: &mut [Option<StreamRequest<TcpStream>>],
+    ) {
+        let mut handlers = HashMap::new();
+        let mut out_tasks = Vec::new();
+        for (tunnel_id, (tunnel_config, status)) in
+            tunnel_configs.iter().zip(tunnel_statuses).enumerate()
+        {
+            let name = tunnel_config.name.clone();
+            if let Some(failure) = status.failure() {
+                self.post_tunnel_log(
+                    format!(
+                        "{name}:{}_{} rejected by server, {}: {failure}",
+                        tunnel_config.upstream.upstream_type, tunnel_config.mode, failure.code
+                    )
+                    .as_str(),
+                );
+                self.post_login_failure(failure);
+                continue;
+            }
+
+            let tunnel_id = tunnel_id as TunnelId;
+            let local_server_addr = tunnel_config.local_server_addr.unwrap();
+            let limiter = Self::create_traffic_limiter(tunnel_config);
+            let timeout_ms = tunnel_config.idle_timeout_ms(&self.config);
+            let upstream_type = tunnel_config.upstream.upstream_type.clone();
+            if tunnel_config.mode == TunnelMode::Out {
+                let mut this = self.clone();
+                let conn = conn.clone();
+                let datagrams = datagrams.clone();
+                let mut pending_request = pending_requests[tunnel_id as usize].take();
+                out_tasks.push(tokio::spawn(async move {
+                    match upstream_type {
+                        UpstreamType::Tcp => this
+                            .serve_outbound_tcp(
+                                &name,
+                                conn,
+                                local_server_addr,
+                                Some(tunnel_id),
+                                &mut pending_request,
+                                limiter,
+                                timeout_ms,
+                            )
+                            .await
+                            .ok(),
+                        UpstreamType::Udp => this
+                            .serve_outbound_udp(
+                                &name,
+                                conn,
+                                local_server_addr,
+                                Some(tunnel_id),
+                                datagrams,
+                                limiter,
+                                timeout_ms,
+                            )
+                            .await
+                            .ok(),
+                    };
+                    (tunnel_id, pending_request)
+                }));
+            } else {
+                self.post_tunnel_log(
+                    format!(
+                        "{name}:{upstream_type}_IN start serving via: {}",
+                        conn.remote_address()
+                    )
+                    .as_str(),
+                );
+                handlers.insert(
+                    tunnel_id,
+                    StreamHandler {
+                        upstream_type,
+                        upstream_addr: local_server_addr.into(),
+                        upstream_acl: None,
+                        limiter,
+                        timeout_ms,
+                    },
+                );
+            }
+        }
+
+        self.set_and_post_tunnel_state(ClientState::Tunneling);
+        mux::accept_streams(
+            &conn,
+            handlers,
+            &TrafficLimiter::default(),
+            datagrams,
+            self.config.max_udp_payload_size,
+        )
+        .await;
+        for task in out_tasks {
+            if let Ok((tunnel_id, pending_request)) = task.await {
+                pending_requests[tunnel_id as usize] = pending_request;
+            }
+        }
+
+        self.add_traffic_data(&conn);
+    }
+
+    fn create_traffic_limiter(tunnel_config: &TunnelConfig) -> TrafficLimiter {
+        Self::traffic_limiter_for(&tunnel_config.rate_limit)
+    }
+
+    fn create_channel_traffic_limiter(&self) -> TrafficLimiter {
+        Self::traffic_limiter_for(&self.config.channel_rate_limit)
+    }
+
+    fn traffic_limiter_for(rate_limit: &Option<RateLimit>) -> TrafficLimiter {
+        match rate_limit {
+            Some(rate_limit) => {
+                TrafficLimiter::default().with(LimitBuckets::for_client(rate_limit))
+            }
+            None => TrafficLimiter::default(),
+        }
+    }
+
+    /// Add the traffic of a closed connection to the totals.
+    fn add_traffic_data(&self, conn: &Connection) {
         let stats = conn.stats();
         let data = &mut inner_state!(self, total_traffic_data);
         data.rx_bytes += stats.udp_rx.bytes;
@@ -564,16 +904,16 @@ impl Client {
 
     async fn login(
         &self,
-        index: usize,
+        name: &str,
         endpoint: &Endpoint,
         login_info: &LoginInfo,
         remote_addr: &SocketAddr,
         domain: &str,
-    ) -> Result<Connection> {
+    ) -> Result<Session> {
         self.set_and_post_tunnel_state(ClientState::Connecting);
         self.post_tunnel_log(
             format!(
-                "{index}:{} connecting, idle_timeout:{}, retry_timeout:{}, cipher:{}, threads:{}",
+                "{name}:{} connecting, idle_timeout:{}, retry_timeout:{}, cipher:{}, threads:{}",
                 login_info.format_with_remote_addr(remote_addr),
                 self.config.quic_timeout_ms,
                 self.config.wait_before_retry_ms,
@@ -591,9 +931,49 @@ impl Client {
 
         self.set_and_post_tunnel_state(ClientState::Connected);
 
+        // only advertise the features this client is configured to use
+        let hello = if self.config.udp_datagrams {
+            Hello::local()
+        } else {
+            Hello::local().without(Features::DATAGRAM)
+        };
+        hello.send(&mut quic_send).await?;
+        let server_hello = tokio::time::timeout(HELLO_TIMEOUT, Hello::recv(&mut quic_recv))
+            .await
+            .map_err(|_| {
+                anyhow!("{name}: no protocol hello from the server, it may be too old, please upgrade the server")
+            })??
+            .ok_or_else(|| {
+                self.login_failed(
+                    LoginFailure::new(
+                        LoginErrorCode::VersionMismatch,
+                        "unexpected response to the protocol hello",
+                    ),
+                    format!("{name}: incompatible server"),
+                )
+            })?;
+        let negotiated = hello
+            .negotiate(&server_hello, "client", "server")
+            .map_err(|e| {
+                self.login_failed(
+                    LoginFailure::new(LoginErrorCode::VersionMismatch, e.to_string()),
+                    format!("{name}: incompatible server"),
+                )
+            })?;
+        debug!(
+            "{name}: protocol v{}, features {}",
+            negotiated.version, negotiated.features
+        );
+        if matches!(login_info.tunnel, Tunnel::Multiplexed(_))
+            && !negotiated.features.contains(Features::MULTIPLEX)
+        {
+            conn.close(VarInt::from_u32(0), b"");
+            return Err(MultiplexUnsupported.into());
+        }
+
         self.post_tunnel_log(
             format!(
-                "{index}:{} logging in...",
+                "{name}:{} logging in...",
                 login_info.format_with_remote_addr(remote_addr)
             )
             .as_str(),
@@ -602,36 +982,87 @@ impl Client {
         let login_msg = TunnelMessage::ReqLogin(login_info.clone());
         TunnelMessage::send(&mut quic_send, &login_msg).await?;
 
-        let resp = TunnelMessage::recv(&mut quic_recv).await?;
-        if let TunnelMessage::RespFailure(msg) = resp {
-            bail!(
-                "{index}:{} failed to login: {msg}",
+        let failed_to_login = || {
+            format!(
+                "{name}:{} failed to login",
                 login_info.format_with_remote_addr(remote_addr)
-            );
+            )
+        };
+        let nonce = match TunnelMessage::recv(&mut quic_recv).await? {
+            TunnelMessage::RespChallenge(nonce) => nonce,
+            TunnelMessage::RespFailure(msg) => {
+                let failure = LoginFailure::new(LoginErrorCode::Other, msg);
+                return Err(self.login_failed(failure, failed_to_login()));
+            }
+            TunnelMessage::RespLoginFailure(failure) => {
+                return Err(self.login_failed(failure, failed_to_login()));
+            }
+            _ => bail!(
+                "{name}:{} unexpected response, expected login challenge",
+                login_info.format_with_remote_addr(remote_addr)
+            ),
+        };
+        let proof =
+            auth::compute_proof(&conn, &nonce, &login_info.username, &self.config.password)?;
+        TunnelMessage::send(&mut quic_send, &TunnelMessage::ReqLoginProof(proof)).await?;
+
+        let resp = TunnelMessage::recv(&mut quic_recv).await?;
+        let failure = match &resp {
+            TunnelMessage::RespFailure(msg) => {
+                Some(LoginFailure::new(LoginErrorCode::Other, msg.clone()))
+            }
+            TunnelMessage::RespLoginFailure(failure) => Some(failure.clone()),
+            _ => None,
+        };
+        if let Some(failure) = failure {
+            METRICS.login_failures.inc();
+            return Err(self.login_failed(failure, failed_to_login()));
         }
         if !resp.is_resp_success() {
             bail!(
-                "{index}:{} unexpected response, failed to login",
+                "{name}:{} unexpected response, failed to login",
                 login_info.format_with_remote_addr(remote_addr)
             );
         }
         TunnelMessage::handle_message(&resp)?;
+        let tunnel_statuses = match &login_info.tunnel {
+            Tunnel::Multiplexed(tunnel_configs) => match TunnelMessage::recv(&mut quic_recv).await?
+            {
+                TunnelMessage::RespTunnels(statuses) if statuses.len() == tunnel_configs.len() => {
+                    Some(statuses)
+                }
+                _ => bail!(
+                    "{name}:{} unexpected response, expected tunnel statuses",
+                    login_info.format_with_remote_addr(remote_addr)
+                ),
+            },
+            _ => None,
+        };
+        METRICS.login_successes.inc();
         self.post_tunnel_log(
             format!(
-                "{index}:{} login succeeded!",
+                "{name}:{} login succeeded!",
                 login_info.format_with_remote_addr(remote_addr)
             )
             .as_str(),
         );
-        Ok(conn)
+        Ok(Session {
+            conn,
+            features: negotiated.features,
+            tunnel_statuses,
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn serve_outbound_tcp(
         &mut self,
-        index: usize,
+        name: &str,
         conn: Connection,
         local_server_addr: SocketAddr,
+        tunnel_id: Option<TunnelId>,
         pending_request: &mut Option<StreamRequest<TcpStream>>,
+        limiter: TrafficLimiter,
+        timeout_ms: u64,
     ) -> Result<()> {
         let tcp_server = {
             inner_state!(self, tcp_servers)
@@ -646,7 +1077,7 @@ impl Client {
 
         self.post_tunnel_log(
             format!(
-                "{index}:TCP_OUT start serving from {} via {}",
+                "{name}:TCP_OUT start serving from {} via {}",
                 tcp_server.addr(),
                 conn.remote_address()
             )
@@ -659,9 +1090,11 @@ impl Client {
         TcpTunnel::start_serving(
             true,
             &conn,
+            tunnel_id,
             &mut tcp_receiver,
             pending_request,
-            self.config.tcp_timeout_ms,
+            limiter,
+            timeout_ms,
         )
         .await;
 
@@ -670,11 +1103,16 @@ impl Client {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn serve_outbound_udp(
         &mut self,
-        index: usize,
+        name: &str,
         conn: Connection,
         local_server_addr: SocketAddr,
+        tunnel_id: Option<TunnelId>,
+        datagrams: Option<Arc<Datagrams>>,
+        limiter: TrafficLimiter,
+        timeout_ms: u64,
     ) -> Result<()> {
         let udp_server = {
             inner_state!(self, udp_servers)
@@ -689,7 +1127,7 @@ impl Client {
 
         self.post_tunnel_log(
             format!(
-                "{index}:UDP_OUT start serving from {} via {}",
+                "{name}:UDP_OUT start serving from {} via {}",
                 udp_server.addr(),
                 conn.remote_address()
             )
@@ -703,9 +1141,12 @@ impl Client {
 
         UdpTunnel::start_serving(
             &conn,
+            tunnel_id,
+            datagrams,
             &udp_sender,
             &mut udp_receiver,
-            self.config.udp_timeout_ms,
+            limiter,
+            timeout_ms,
         )
         .await;
 
@@ -716,46 +1157,101 @@ impl Client {
 
     async fn serve_inbound_tcp(
         &mut self,
-        index: usize,
+        name: &str,
         conn: Connection,
         local_server_addr: SocketAddr,
+        limiter: TrafficLimiter,
+        timeout_ms: u64,
     ) -> Result<()> {
         self.post_tunnel_log(
-            format!(
-                "{index}:TCP_IN start serving via: {}",
-                conn.remote_address()
-            )
-            .as_str(),
+            format!("{name}:TCP_IN start serving via: {}", conn.remote_address()).as_str(),
         );
 
         self.set_and_post_tunnel_state(ClientState::Tunneling);
-        TcpTunnel::start_accepting(&conn, Some(local_server_addr), self.config.tcp_timeout_ms)
-            .await;
+        TcpTunnel::start_accepting(
+            &conn,
+            Some(local_server_addr.into()),
+            None,
+            limiter,
+            timeout_ms,
+        )
+        .await;
 
         Ok(())
     }
 
     async fn serve_inbound_udp(
         &mut self,
-        index: usize,
+        name: &str,
         conn: Connection,
+        datagrams: Option<Arc<Datagrams>>,
         local_server_addr: SocketAddr,
+        limiter: TrafficLimiter,
+        timeout_ms: u64,
     ) -> Result<()> {
         self.post_tunnel_log(
-            format!(
-                "{index}:UDP_IN start serving via: {}",
-                conn.remote_address()
-            )
-            .as_str(),
+            format!("{name}:UDP_IN start serving via: {}", conn.remote_address()).as_str(),
         );
 
         self.set_and_post_tunnel_state(ClientState::Tunneling);
-        UdpTunnel::start_accepting(&conn, Some(local_server_addr), self.config.udp_timeout_ms)
-            .await;
+        UdpTunnel::start_accepting(
+            &conn,
+            datagrams,
+            Some(local_server_addr.into()),
+            None,
+            limiter,
+            timeout_ms,
+            self.config.max_udp_payload_size,
+        )
+        .await;
 
         Ok(())
     }
 
+    /// Log why the server closed `conn` and decide whether the tunnel
+    /// connects again: a revoked user stops it, an exhausted quota delays
+    /// it until the quota is reset.
+    fn handle_server_close(
+        &self,
+        name: &str,
+        login_info: &LoginInfo,
+        conn: &Connection,
+    ) -> ServerClose {
+        let Some(ConnectionError::ApplicationClosed(close)) = conn.close_reason() else {
+            return ServerClose::Reconnect;
+        };
+        let Some(code) = CloseCode::from_varint(close.error_code) else {
+            return ServerClose::Reconnect;
+        };
+
+        let reason = || String::from_utf8_lossy(&close.reason).into_owned();
+        let (action, reason, next) = match code {
+            CloseCode::UserRevoked => (
+                ServerClose::Stop,
+                reason(),
+                "will not reconnect".to_string(),
+            ),
+            CloseCode::AdminClosed => (
+                ServerClose::Reconnect,
+                reason(),
+                "will reconnect".to_string(),
+            ),
+            CloseCode::QuotaExceeded => {
+                let (delay, reason) = parse_quota_close_reason(&close.reason)
+                    .unwrap_or_else(|| (QUOTA_EXCEEDED_RETRY_DELAY, reason()));
+                (
+                    ServerClose::ReconnectAfter(delay),
+                    reason,
+                    format!("will reconnect in {}s", delay.as_secs()),
+                )
+            }
+        };
+        self.post_tunnel_log(
+            format!("{name}:{login_info} closed by server, {code}: {reason}, {next}").as_str(),
+        );
+        action
+    }
+
     fn should_quit(&self) -> bool {
         let state = self.get_state();
         state == ClientState::Stopping || state == ClientState::Terminated
@@ -855,21 +1351,21 @@ impl Client {
                     None => self.config.server_addr.to_string(),
                 };
 
-                let client_config = self
-                    .create_client_config_builder(&cipher)?
-                    .with_platform_verifier()?
-                    .with_no_client_auth();
+                let client_config = self.with_client_auth(
+                    self.create_client_config_builder(&cipher)?
+                        .with_platform_verifier()?,
+                )?;
 
                 return Ok((client_config, domain));
             }
 
-            let client_config = self
-                .create_client_config_builder(&cipher)?
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(InsecureCertVerifier::new(
-                    self.get_crypto_provider(&cipher),
-                )))
-                .with_no_client_auth();
+            let client_config = self.with_client_auth(
+                self.create_client_config_builder(&cipher)?
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(InsecureCertVerifier::new(
+                        self.get_crypto_provider(&cipher),
+                    ))),
+            )?;
 
             static ONCE: Once = Once::new();
             ONCE.call_once(|| {
@@ -905,13 +1401,37 @@ impl Client {
         };
 
         Ok((
-            self.create_client_config_builder(&cipher)?
-                .with_root_certificates(roots)
-                .with_no_client_auth(),
+            self.with_client_auth(
+                self.create_client_config_builder(&cipher)?
+                    .with_root_certificates(roots),
+            )?,
             domain_or_ip,
         ))
     }
 
+    fn with_client_auth(
+        &self,
+        cfg_builder: rustls::ConfigBuilder<rustls::ClientConfig, rustls::client::WantsClientCert>,
+    ) -> Result<rustls::ClientConfig> {
+        if self.config.client_cert_path.is_empty() {
+            return Ok(cfg_builder.with_no_client_auth());
+        }
+
+        let certs = pem_util::load_certificates_from_pem(self.config.client_cert_path.as_str())
+            .context(format!(
+                "failed to read client cert file: {}",
+                self.config.client_cert_path
+            ))?;
+        let key = pem_util::load_private_key_from_pem(self.config.client_key_path.as_str())
+            .context(format!(
+                "failed to read client key file: {}",
+                self.config.client_key_path
+            ))?;
+        cfg_builder
+            .with_client_auth_cert(certs, key)
+            .context("invalid client certificate or key")
+    }
+
     pub fn get_state(&self) -> ClientState {
         inner_state!(self, client_state).clone()
     }
@@ -991,6 +1511,21 @@ impl Client {
         ));
     }
 
+    /// Report `failure` to the info listener and turn it into the error of
+    /// the login, prefixed with `context`.
+    fn login_failed(&self, failure: LoginFailure, context: String) -> anyhow::Error {
+        self.post_login_failure(failure.clone());
+        anyhow::Error::new(failure).context(context)
+    }
+
+    fn post_login_failure(&self, failure: LoginFailure) {
+        let state = self.inner_state.lock().unwrap();
+        state.post_tunnel_info(TunnelInfo::new(
+            TunnelInfoType::LoginFailure,
+            Box::new(failure),
+        ));
+    }
+
     fn set_and_post_tunnel_state(&self, client_state: ClientState) {
         let mut state = self.inner_state.lock().unwrap();
         state.client_state = client_state.clone();
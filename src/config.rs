@@ -0,0 +1,343 @@
+//! Loading configuration from files.
+//!
+//! Client tunnels are listed as `[[tunnels]]` tables with a unique `name`, a
+//! `mode` (IN or OUT), a `protocol` (tcp or udp), a `local` address and an
+//! optional `upstream`, plus optional `idle_timeout_ms` and `rate_limit`
+//! overrides. Addresses are [ip:]port as in the CLI mappings.
+//!
+//! Secrets (passwords and the dashboard credential) can be given indirectly so
+//! they appear neither in the config file nor on the command line:
+//!
+//! - `env:NAME` is replaced with the value of the environment variable `NAME`
+//! - `file:PATH` is replaced with the content of the file at `PATH`, without
+//!   the trailing newline
+//!
+//! Any other value is used as is.
+
+use crate::{
+    parse_tunnel_addr, parse_upstream_addr, ClientConfig, RateLimit, ServerConfig, TunnelConfig,
+    TunnelMode, Upstream, UpstreamType,
+};
+use anyhow::{Context, Result};
+use log::info;
+use rs_utilities::log_and_bail;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+
+impl ServerConfig {
+    /// Load a config from the TOML file at `path` and resolve its secrets.
+    pub fn from_file(path: &str) -> Result<ServerConfig> {
+        let content =
+            std::fs::read_to_string(path).context(format!("failed to read config file: {path}"))?;
+        let mut config: ServerConfig = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(e) => {
+                log_and_bail!("invalid config file {path}: {e}");
+            }
+        };
+        config.resolve_secrets()?;
+        info!("loaded config from {path}");
+        Ok(config)
+    }
+
+    /// Replace `env:` and `file:` references in the secrets with their values.
+    pub fn resolve_secrets(&mut self) -> Result<()> {
+        self.password = resolve_secret("password", &self.password)?;
+        self.dashboard_server_credential = resolve_secret(
+            "dashboard_server_credential",
+            &self.dashboard_server_credential,
+        )?;
+        for user in &mut self.users {
+            let key = format!("users.{}.password", user.username);
+            user.password = resolve_secret(&key, &user.password)?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientConfig {
+    /// Load a config from the TOML file at `path` and resolve its password,
+    /// the caller validates it and fills in the defaults with
+    /// [`ClientConfig::validate`] and [`ClientConfig::normalize`].
+    pub fn from_file(path: &str) -> Result<ClientConfig> {
+        let content =
+            std::fs::read_to_string(path).context(format!("failed to read config file: {path}"))?;
+        let mut config: ClientConfig = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(e) => {
+                log_and_bail!("invalid config file {path}: {e}");
+            }
+        };
+        config.resolve_secrets()?;
+        info!("loaded config from {path}");
+        Ok(config)
+    }
+
+    /// Replace `env:` and `file:` references in the password with its value.
+    pub fn resolve_secrets(&mut self) -> Result<()> {
+        self.password = resolve_secret("password", &self.password)?;
+        Ok(())
+    }
+}
+
+/// A tunnel as written in the client config file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TunnelEntry {
+    name: String,
+    mode: String,
+    protocol: String,
+    local: String,
+    #[serde(default)]
+    upstream: Option<String>,
+    #[serde(default)]
+    idle_timeout_ms: Option<u64>,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+}
+
+impl TryFrom<TunnelEntry> for TunnelConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: TunnelEntry) -> Result<Self> {
+        let name = entry.name;
+        let mode = match entry.mode.to_ascii_uppercase().as_str() {
+            "IN" => TunnelMode::In,
+            "OUT" => TunnelMode::Out,
+            _ => log_and_bail!(
+                "tunnel {name}: invalid mode '{}', expected IN or OUT",
+                entry.mode
+            ),
+        };
+        let upstream_type = match entry.protocol.to_ascii_lowercase().as_str() {
+            "tcp" => UpstreamType::Tcp,
+            "udp" => UpstreamType::Udp,
+            _ => log_and_bail!(
+                "tunnel {name}: invalid protocol '{}', expected tcp or udp",
+                entry.protocol
+            ),
+        };
+        let Some(local_server_addr) =
+            parse_tunnel_addr(&entry.local).with_context(|| format!("tunnel {name}"))?
+        else {
+            log_and_bail!("tunnel {name}: 'ANY' is not allowed as local address");
+        };
+        let upstream_addr = match &entry.upstream {
+            Some(upstream) => {
+                parse_upstream_addr(upstream).with_context(|| format!("tunnel {name}"))?
+            }
+            None => None,
+        };
+
+        Ok(TunnelConfig {
+            name,
+            mode,
+            local_server_addr: Some(local_server_addr),
+            upstream: Upstream {
+                upstream_addr,
+                upstream_type,
+            },
+            rate_limit: entry.rate_limit,
+            idle_timeout_ms: entry.idle_timeout_ms,
+        })
+    }
+}
+
+/// Deserialize the `[[tunnels]]` of a client config file, names must be
+/// unique and non-empty.
+pub(crate) fn deserialize_tunnels<'de, D>(deserializer: D) -> Result<Vec<TunnelConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<TunnelEntry>::deserialize(deserializer)?;
+    let mut names = HashSet::new();
+    let mut tunnels = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if entry.name.is_empty() {
+            return Err(serde::de::Error::custom("tunnel name must not be empty"));
+        }
+        if !names.insert(entry.name.clone()) {
+            return Err(serde::de::Error::custom(format!(
+                "duplicate tunnel name: {}",
+                entry.name
+            )));
+        }
+        let tunnel = TunnelConfig::try_from(entry)
+            .map_err(|e| serde::de::Error::custom(format!("{e:#}")))?;
+        tunnels.push(tunnel);
+    }
+    Ok(tunnels)
+}
+
+/// Resolve a secret given as `env:NAME` or `file:PATH`, `key` names the
+/// setting in errors. Values from config files are resolved by `from_file`,
+/// this is for values from other sources such as command line flags.
+pub fn resolve_secret(key: &str, value: &str) -> Result<String> {
+    if let Some(name) = value.strip_prefix("env:") {
+        match std::env::var(name) {
+            Ok(secret) => Ok(secret),
+            Err(_) => {
+                log_and_bail!("{key}: environment variable {name} is not set");
+            }
+        }
+    } else if let Some(path) = value.strip_prefix("file:") {
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("{key}: failed to read secret file: {path}"))?;
+        Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn parse(toml: &str) -> Result<ClientConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    fn parse_err(toml: &str) -> String {
+        parse(toml).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parse_tunnels() {
+        let config = parse(
+            r#"
+            [[tunnels]]
+            name = "web"
+            mode = "out"
+            protocol = "TCP"
+            local = "8080"
+            upstream = "example.com:80"
+            idle_timeout_ms = 5000
+            rate_limit = { upload_bytes_per_sec = 1024 }
+
+            [[tunnels]]
+            name = "dns"
+            mode = "IN"
+            protocol = "udp"
+            local = "0.0.0.0:5353"
+            "#,
+        )
+        .unwrap();
+
+        let [web, dns] = config.tunnels.as_slice() else {
+            panic!("expected 2 tunnels: {:?}", config.tunnels);
+        };
+        assert_eq!(web.name, "web");
+        assert_eq!(web.mode, TunnelMode::Out);
+        assert_eq!(
+            web.local_server_addr,
+            Some("127.0.0.1:8080".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(web.upstream.upstream_type, UpstreamType::Tcp);
+        assert_eq!(
+            web.upstream.upstream_addr,
+            Some("example.com:80".parse().unwrap())
+        );
+        assert_eq!(web.idle_timeout_ms, Some(5000));
+        assert_eq!(
+            web.rate_limit,
+            Some(RateLimit {
+                upload_bytes_per_sec: 1024,
+                download_bytes_per_sec: 0,
+            })
+        );
+
+        assert_eq!(dns.mode, TunnelMode::In);
+        assert_eq!(dns.upstream.upstream_type, UpstreamType::Udp);
+        assert_eq!(dns.upstream.upstream_addr, None);
+        assert_eq!(dns.rate_limit, None);
+    }
+
+    #[test]
+    fn reject_invalid_tunnels() {
+        let tunnel = |mode: &str, protocol: &str, local: &str| {
+            format!(
+                "[[tunnels]]\nname = \"t\"\nmode = \"{mode}\"\nprotocol = \"{protocol}\"\nlocal = \"{local}\"\n"
+            )
+        };
+
+        let e = parse_err(&(tunnel("OUT", "tcp", "80") + "port = 1"));
+        assert!(e.contains("unknown field `port`"), "{e}");
+
+        let e = parse_err(&tunnel("BOTH", "tcp", "80"));
+        assert!(e.contains("invalid mode 'BOTH'"), "{e}");
+
+        let e = parse_err(&tunnel("OUT", "quic", "80"));
+        assert!(e.contains("invalid protocol 'quic'"), "{e}");
+
+        let e = parse_err(&tunnel("OUT", "tcp", "ANY"));
+        assert!(e.contains("'ANY' is not allowed as local address"), "{e}");
+
+        let e = parse_err(&tunnel("OUT", "tcp", "localhost:80"));
+        assert!(e.contains("tunnel t"), "{e}");
+
+        let e = parse_err("[[tunnels]]\nname = \"t\"\nmode = \"OUT\"\nprotocol = \"tcp\"\n");
+        assert!(e.contains("missing field `local`"), "{e}");
+    }
+
+    #[test]
+    fn reject_duplicate_or_empty_names() {
+        let e = parse_err(
+            r#"
+            [[tunnels]]
+            name = "web"
+            mode = "OUT"
+            protocol = "tcp"
+            local = "8080"
+
+            [[tunnels]]
+            name = "web"
+            mode = "IN"
+            protocol = "tcp"
+            local = "8081"
+            "#,
+        );
+        assert!(e.contains("duplicate tunnel name: web"), "{e}");
+
+        let e = parse_err(
+            r#"
+            [[tunnels]]
+            name = ""
+            mode = "OUT"
+            protocol = "tcp"
+            local = "8080"
+            "#,
+        );
+        assert!(e.contains("tunnel name must not be empty"), "{e}");
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        let e = parse_err("passwd = \"secret\"");
+        assert!(e.contains("unknown field `passwd`"), "{e}");
+    }
+
+    #[test]
+    fn resolve_secrets() {
+        assert_eq!(resolve_secret("password", "secret").unwrap(), "secret");
+        assert_eq!(resolve_secret("password", "").unwrap(), "");
+
+        std::env::set_var("RSTUN_CONFIG_TEST_SECRET", "from env");
+        assert_eq!(
+            resolve_secret("password", "env:RSTUN_CONFIG_TEST_SECRET").unwrap(),
+            "from env"
+        );
+        let e = resolve_secret("password", "env:RSTUN_CONFIG_TEST_UNSET").unwrap_err();
+        assert!(e.to_string().contains("RSTUN_CONFIG_TEST_UNSET"), "{e}");
+
+        let path = std::env::temp_dir().join(format!("rstun-secret-{}", std::process::id()));
+        std::fs::write(&path, "from file\r\n").unwrap();
+        let value = format!("file:{}", path.display());
+        let secret = resolve_secret("password", &value);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(secret.unwrap(), "from file");
+
+        let e = resolve_secret("users.bob.password", &value).unwrap_err();
+        assert!(format!("{e:#}").contains("users.bob.password"), "{e:#}");
+    }
+}
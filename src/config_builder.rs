@@ -0,0 +1,604 @@
+//! Typed builders for [`ClientConfig`] and [`ServerConfig`].
+//!
+//! The builders start from the defaults, every setter takes the value of one
+//! config field, and `build()` validates the result, returning a [`ConfigError`]
+//! that tells which setting is wrong:
+//!
+//! ```rust,ignore
+//! let config = ClientConfig::builder()
+//!     .server_addr("example.com:3515")
+//!     .password("secret")
+//!     .tunnel(TunnelConfig {
+//!         name: "ssh".to_string(),
+//!         mode: TunnelMode::Out,
+//!         local_server_addr: Some("127.0.0.1:2222".parse()?),
+//!         upstream: Upstream {
+//!             upstream_addr: Some("10.0.0.5:22".parse()?),
+//!             upstream_type: UpstreamType::Tcp,
+//!         },
+//!         rate_limit: None,
+//!         idle_timeout_ms: None,
+//!     })
+//!     .build()?;
+//! ```
+
+use crate::{
+    ClientConfig, PortRange, RateLimit, ServerConfig, TunnelConfig, TunnelMode, UpstreamAcl,
+    UpstreamAddr, UserConfig, MAX_UDP_PAYLOAD_SIZE, SUPPORTED_CIPHER_SUITE_STRS,
+};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::net::SocketAddr;
+
+/// A config rejected by a builder or by `validate()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The client has no server address.
+    MissingServerAddr,
+    /// The client has no tunnels.
+    NoTunnels,
+    /// CLI-style tunnel mappings could not be parsed.
+    InvalidMappings(String),
+    /// Two tunnels have the same name.
+    DuplicateTunnelName(String),
+    /// A tunnel has no local address.
+    MissingLocalAddr { tunnel: String },
+    /// An IN tunnel does not say where the server must listen.
+    MissingInUpstream { tunnel: String },
+    /// An IN tunnel listens on a domain name instead of an IP address.
+    DomainInUpstream { tunnel: String },
+    /// The cipher is not one of [`SUPPORTED_CIPHER_SUITE_STRS`].
+    UnsupportedCipher(String),
+    /// A client certificate is set without its private key.
+    MissingClientKey,
+    /// The server has no address to listen on.
+    MissingListenAddr,
+    /// The address to listen on is not a socket address.
+    InvalidListenAddr(String),
+    /// The server has neither a password, users nor a client CA.
+    MissingCredentials,
+    /// Client-certificate-only logins are enabled without a client CA.
+    ClientCertOnlyWithoutCa,
+    /// A client CA is the only credential, but the certificate alone is not
+    /// accepted, so no login can succeed.
+    ClientCaWithoutCertOnly,
+    /// A certificate is set without its private key.
+    MissingKey,
+    /// Two users have the same name.
+    DuplicateUser(String),
+    /// The dashboard credential is not in the form user:password.
+    InvalidDashboardCredential,
+    /// The maximum UDP payload size is above [`MAX_UDP_PAYLOAD_SIZE`].
+    InvalidMaxUdpPayloadSize(usize),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingServerAddr => write!(f, "server address is not set"),
+            Self::NoTunnels => write!(f, "no tunnels are configured"),
+            Self::InvalidMappings(e) => write!(f, "invalid tunnel mappings: {e}"),
+            Self::DuplicateTunnelName(name) => write!(f, "duplicate tunnel name: {name}"),
+            Self::MissingLocalAddr { tunnel } => {
+                write!(f, "tunnel {tunnel} has no local address")
+            }
+            Self::MissingInUpstream { tunnel } => write!(
+                f,
+                "IN tunnel {tunnel} must specify the address the server listens on"
+            ),
+            Self::DomainInUpstream { tunnel } => write!(
+                f,
+                "IN tunnel {tunnel} must listen on an IP address, not a domain name"
+            ),
+            Self::UnsupportedCipher(cipher) => write!(
+                f,
+                "unsupported cipher: {cipher}, expected one of {}",
+                SUPPORTED_CIPHER_SUITE_STRS.join(", ")
+            ),
+            Self::MissingClientKey => write!(f, "client_cert_path requires client_key_path"),
+            Self::MissingListenAddr => write!(f, "the address to listen on is not set"),
+            Self::InvalidListenAddr(addr) => write!(f, "invalid address to listen on: {addr}"),
+            Self::MissingCredentials => {
+                write!(f, "either password, users or client CA must be configured")
+            }
+            Self::ClientCertOnlyWithoutCa => write!(f, "client_cert_only requires client_ca_path"),
+            Self::ClientCaWithoutCertOnly => write!(
+                f,
+                "client_ca_path without password or users requires client_cert_only"
+            ),
+            Self::MissingKey => write!(f, "cert_path requires key_path"),
+            Self::DuplicateUser(user) => write!(f, "duplicate user: {user}"),
+            Self::InvalidDashboardCredential => write!(
+                f,
+                "dashboard_server_credential must be in the form user:password"
+            ),
+            Self::InvalidMaxUdpPayloadSize(size) => write!(
+                f,
+                "invalid max_udp_payload_size: {size}, expected at most {MAX_UDP_PAYLOAD_SIZE}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Generate setters assigning the config field of the same name.
+macro_rules! setters {
+    ($($(#[$doc:meta])* $field:ident: $ty:ty),* $(,)?) => {
+        $(
+            $(#[$doc])*
+            pub fn $field(mut self, value: impl Into<$ty>) -> Self {
+                self.config.$field = value.into();
+                self
+            }
+        )*
+    };
+}
+
+impl ClientConfig {
+    /// Start building a client config, see [`ClientConfigBuilder`].
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+
+    /// Split a comma-separated list of DNS servers, skipping empty entries.
+    pub fn split_servers(servers: &str) -> Vec<String> {
+        servers
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    /// Check that the config can be used to start a client.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server_addr.is_empty() {
+            return Err(ConfigError::MissingServerAddr);
+        }
+        if self.tunnels.is_empty() {
+            return Err(ConfigError::NoTunnels);
+        }
+        if !self.cipher.is_empty() && !SUPPORTED_CIPHER_SUITE_STRS.contains(&self.cipher.as_str()) {
+            return Err(ConfigError::UnsupportedCipher(self.cipher.clone()));
+        }
+        if !self.client_cert_path.is_empty() && self.client_key_path.is_empty() {
+            return Err(ConfigError::MissingClientKey);
+        }
+        if self.max_udp_payload_size > MAX_UDP_PAYLOAD_SIZE {
+            return Err(ConfigError::InvalidMaxUdpPayloadSize(
+                self.max_udp_payload_size,
+            ));
+        }
+
+        let mut names = HashSet::new();
+        for tunnel in &self.tunnels {
+            if !names.insert(tunnel.name.as_str()) {
+                return Err(ConfigError::DuplicateTunnelName(tunnel.name.clone()));
+            }
+            if tunnel.local_server_addr.is_none() {
+                return Err(ConfigError::MissingLocalAddr {
+                    tunnel: tunnel.name.clone(),
+                });
+            }
+            if tunnel.mode == TunnelMode::In {
+                match &tunnel.upstream.upstream_addr {
+                    None => {
+                        return Err(ConfigError::MissingInUpstream {
+                            tunnel: tunnel.name.clone(),
+                        })
+                    }
+                    Some(UpstreamAddr::Domain(..)) => {
+                        return Err(ConfigError::DomainInUpstream {
+                            tunnel: tunnel.name.clone(),
+                        })
+                    }
+                    Some(UpstreamAddr::Ip(_)) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builder of [`ClientConfig`], created with [`ClientConfig::builder`].
+#[derive(Debug, Default, Clone)]
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+    tcp_mappings: String,
+    udp_mappings: String,
+}
+
+impl ClientConfigBuilder {
+    setters!(
+        /// Server address in "host[:port]", a port alone means 127.0.0.1.
+        server_addr: String,
+        /// Username to log in with, leave unset for the server's shared password.
+        username: String,
+        /// Password of the user or shared password of the server.
+        password: String,
+        /// PEM certificate of a self-signed server.
+        cert_path: String,
+        /// PEM client certificate presented to the server.
+        client_cert_path: String,
+        /// PEM private key of the client certificate.
+        client_key_path: String,
+        /// Preferred cipher suite, one of [`SUPPORTED_CIPHER_SUITE_STRS`].
+        cipher: String,
+        /// Number of async worker threads, 0 for all logical CPUs.
+        workers: usize,
+        /// Wait time before retrying a failed connection (ms).
+        wait_before_retry_ms: u64,
+        /// QUIC idle timeout (ms), 0 for the default.
+        quic_timeout_ms: u64,
+        /// TCP idle timeout (ms), 0 for the default.
+        tcp_timeout_ms: u64,
+        /// UDP idle timeout (ms), 0 for the default.
+        udp_timeout_ms: u64,
+        /// Largest UDP payload (bytes) forwarded, see [`crate::UDP_PACKET_SIZE`].
+        max_udp_payload_size: usize,
+        /// Periodic endpoint migration interval (ms), 0 disables it.
+        hop_interval_ms: u64,
+        /// Carry all tunnels over one connection.
+        multiplex: bool,
+        /// Send UDP payloads as QUIC datagrams where possible.
+        udp_datagrams: bool,
+        /// Throughput limit of the channel-based tunnels.
+        channel_rate_limit: Option<RateLimit>,
+        /// DNS-over-TLS servers (domain names).
+        dot_servers: Vec<String>,
+        /// Plain DNS servers (IP addresses).
+        dns_servers: Vec<String>,
+        /// Address of the Prometheus metrics endpoint.
+        metrics_server: String,
+    );
+
+    /// DNS-over-TLS servers as a comma-separated list.
+    pub fn dot_server_list(self, servers: &str) -> Self {
+        self.dot_servers(ClientConfig::split_servers(servers))
+    }
+
+    /// Plain DNS servers as a comma-separated list.
+    pub fn dns_server_list(self, servers: &str) -> Self {
+        self.dns_servers(ClientConfig::split_servers(servers))
+    }
+
+    /// Add a tunnel, named after its position if its name is empty.
+    pub fn tunnel(mut self, mut tunnel: TunnelConfig) -> Self {
+        if tunnel.name.is_empty() {
+            tunnel.name = self.config.tunnels.len().to_string();
+        }
+        self.config.tunnels.push(tunnel);
+        self
+    }
+
+    /// Add tunnels parsed from CLI-style mappings after the other tunnels,
+    /// see [`ClientConfig::create`].
+    pub fn mappings(mut self, tcp_addr_mappings: &str, udp_addr_mappings: &str) -> Self {
+        self.tcp_mappings = tcp_addr_mappings.to_string();
+        self.udp_mappings = udp_addr_mappings.to_string();
+        self
+    }
+
+    /// Validate the config and fill in the defaults, see
+    /// [`ClientConfig::normalize`].
+    pub fn build(self) -> Result<ClientConfig, ConfigError> {
+        let mut config = self.config;
+        if !self.tcp_mappings.is_empty() || !self.udp_mappings.is_empty() {
+            let mut mapped = ClientConfig::default();
+            mapped
+                .set_mappings(&self.tcp_mappings, &self.udp_mappings)
+                .map_err(|e| ConfigError::InvalidMappings(format!("{e:#}")))?;
+            for mut tunnel in mapped.tunnels {
+                tunnel.name = config.tunnels.len().to_string();
+                config.tunnels.push(tunnel);
+            }
+        }
+        if config.cipher.is_empty() {
+            config.cipher = SUPPORTED_CIPHER_SUITE_STRS[0].to_string();
+        }
+
+        config.validate()?;
+        config.normalize();
+        Ok(config)
+    }
+}
+
+impl ServerConfig {
+    /// Start building a server config, see [`ServerConfigBuilder`].
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    /// Check that the config can be used to start a server.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.addr.is_empty() {
+            return Err(ConfigError::MissingListenAddr);
+        }
+        if self.addr.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::InvalidListenAddr(self.addr.clone()));
+        }
+        if self.password.is_empty() && self.users.is_empty() && self.client_ca_path.is_empty() {
+            return Err(ConfigError::MissingCredentials);
+        }
+        if self.client_cert_only && self.client_ca_path.is_empty() {
+            return Err(ConfigError::ClientCertOnlyWithoutCa);
+        }
+        if self.password.is_empty() && self.users.is_empty() && !self.client_cert_only {
+            return Err(ConfigError::ClientCaWithoutCertOnly);
+        }
+        if !self.cert_path.is_empty() && self.key_path.is_empty() {
+            return Err(ConfigError::MissingKey);
+        }
+        if !self.dashboard_server.is_empty() && !self.dashboard_server_credential.contains(':') {
+            return Err(ConfigError::InvalidDashboardCredential);
+        }
+        if self.max_udp_payload_size > MAX_UDP_PAYLOAD_SIZE {
+            return Err(ConfigError::InvalidMaxUdpPayloadSize(
+                self.max_udp_payload_size,
+            ));
+        }
+
+        let mut usernames = HashSet::new();
+        for user in &self.users {
+            if !usernames.insert(user.username.as_str()) {
+                return Err(ConfigError::DuplicateUser(user.username.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builder of [`ServerConfig`], created with [`ServerConfig::builder`].
+#[derive(Debug, Default, Clone)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    setters!(
+        /// Address ([ip:]port) to listen on, a port alone means 127.0.0.1.
+        addr: String,
+        /// Shared password for clients logging in without a username.
+        password: String,
+        /// Path to the certificate PEM, a self-signed one is generated if unset.
+        cert_path: String,
+        /// Path to the private key PEM of the certificate.
+        key_path: String,
+        /// Path to a CA certificate PEM clients must present a certificate of.
+        client_ca_path: String,
+        /// Accept a verified client certificate without a password.
+        client_cert_only: bool,
+        /// QUIC idle timeout (ms).
+        quic_timeout_ms: u64,
+        /// TCP idle timeout (ms).
+        tcp_timeout_ms: u64,
+        /// UDP idle timeout (ms).
+        udp_timeout_ms: u64,
+        /// Largest UDP payload (bytes) forwarded, see [`crate::UDP_PACKET_SIZE`].
+        max_udp_payload_size: usize,
+        /// Default TCP upstream of OUT tunnels.
+        default_tcp_upstream: Option<UpstreamAddr>,
+        /// Default UDP upstream of OUT tunnels.
+        default_udp_upstream: Option<UpstreamAddr>,
+        /// Rules restricting the upstreams dialed for clients.
+        upstream_acl: UpstreamAcl,
+        /// Ports IN tunnels may listen on, empty allows any port.
+        in_port_ranges: Vec<PortRange>,
+        /// Throughput limit shared by all clients.
+        rate_limit: RateLimit,
+        /// Throughput limit of each tunnel.
+        tunnel_rate_limit: RateLimit,
+        /// Path to the JSON file persisting the traffic usage of users.
+        quota_state_path: String,
+        /// Maximum number of concurrent sessions, 0 for unlimited.
+        max_sessions: usize,
+        /// Maximum number of concurrent sessions per source IP, 0 for unlimited.
+        max_sessions_per_ip: usize,
+        /// Maximum number of concurrent sessions per user, 0 for unlimited.
+        max_sessions_per_user: usize,
+        /// Maximum number of concurrent streams per connection.
+        max_streams_per_conn: u32,
+        /// Maximum number of new streams per second per connection, 0 for unlimited.
+        max_new_streams_per_sec: u32,
+        /// Failed logins after which a source IP is banned, 0 disables it.
+        max_login_failures: u32,
+        /// How long a source IP stays banned (s).
+        login_ban_secs: u64,
+        /// Address of the admin HTTP API.
+        dashboard_server: String,
+        /// Credential of the admin HTTP API in the form user:password.
+        dashboard_server_credential: String,
+        /// Address of the Prometheus metrics endpoint.
+        metrics_server: String,
+    );
+
+    /// Add a user account.
+    pub fn user(mut self, user: UserConfig) -> Self {
+        self.config.users.push(user);
+        self
+    }
+
+    /// Validate the config, completing a port-only address and filling in
+    /// the defaults, see [`ServerConfig::normalize`].
+    pub fn build(self) -> Result<ServerConfig, ConfigError> {
+        let mut config = self.config;
+        if !config.addr.is_empty() && !config.addr.contains(':') {
+            config.addr = format!("127.0.0.1:{}", config.addr);
+        }
+        config.validate()?;
+        config.normalize();
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> ClientConfigBuilder {
+        ClientConfig::builder()
+            .server_addr("127.0.0.1:3515")
+            .mappings("OUT^8000^ANY", "")
+    }
+
+    fn server() -> ServerConfigBuilder {
+        ServerConfig::builder().addr("3515").password("secret")
+    }
+
+    fn user(name: &str) -> UserConfig {
+        UserConfig {
+            username: name.to_string(),
+            password: "secret".to_string(),
+            ..UserConfig::default()
+        }
+    }
+
+    #[test]
+    fn build_client_config() {
+        let config = client()
+            .dot_server_list("dns.google, ,1.1.1.1")
+            .build()
+            .unwrap();
+        assert_eq!(config.dot_servers, ["dns.google", "1.1.1.1"]);
+        assert_eq!(config.tunnels.len(), 1);
+        assert_eq!(config.tunnels[0].name, "0");
+        assert_eq!(config.max_udp_payload_size, crate::UDP_PACKET_SIZE);
+        assert_eq!(config.cipher, SUPPORTED_CIPHER_SUITE_STRS[0]);
+    }
+
+    #[test]
+    fn reject_invalid_client_configs() {
+        let cases = [
+            (
+                ClientConfig::builder().mappings("OUT^8000^ANY", ""),
+                ConfigError::MissingServerAddr,
+            ),
+            (
+                ClientConfig::builder().server_addr("3515"),
+                ConfigError::NoTunnels,
+            ),
+            (
+                client().cipher("rot13"),
+                ConfigError::UnsupportedCipher("rot13".to_string()),
+            ),
+            (
+                client().client_cert_path("client.pem"),
+                ConfigError::MissingClientKey,
+            ),
+            (
+                client().max_udp_payload_size(MAX_UDP_PAYLOAD_SIZE + 1),
+                ConfigError::InvalidMaxUdpPayloadSize(MAX_UDP_PAYLOAD_SIZE + 1),
+            ),
+        ];
+        for (builder, error) in cases {
+            assert_eq!(builder.build().unwrap_err(), error);
+        }
+        assert!(matches!(
+            client().mappings("IN^8000", "").build(),
+            Err(ConfigError::InvalidMappings(_))
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_client_tunnels() {
+        let tunnel = client().build().unwrap().tunnels.remove(0);
+        let named = |name: &str| TunnelConfig {
+            name: name.to_string(),
+            ..tunnel.clone()
+        };
+        assert_eq!(
+            client()
+                .tunnel(named("web"))
+                .tunnel(named("web"))
+                .build()
+                .unwrap_err(),
+            ConfigError::DuplicateTunnelName("web".to_string())
+        );
+        assert_eq!(
+            client()
+                .tunnel(TunnelConfig {
+                    local_server_addr: None,
+                    ..named("web")
+                })
+                .build()
+                .unwrap_err(),
+            ConfigError::MissingLocalAddr {
+                tunnel: "web".to_string()
+            }
+        );
+        assert_eq!(
+            client()
+                .tunnel(TunnelConfig {
+                    mode: TunnelMode::In,
+                    ..named("web")
+                })
+                .build()
+                .unwrap_err(),
+            ConfigError::MissingInUpstream {
+                tunnel: "web".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn build_server_config() {
+        let config = server()
+            .user(user("alice"))
+            .max_udp_payload_size(0usize)
+            .build()
+            .unwrap();
+        assert_eq!(config.addr, "127.0.0.1:3515");
+        assert_eq!(config.max_udp_payload_size, crate::UDP_PACKET_SIZE);
+        assert!(ServerConfig::builder()
+            .addr("3515")
+            .client_ca_path("ca.pem")
+            .client_cert_only(true)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_server_configs() {
+        let cases = [
+            (
+                ServerConfig::builder().password("secret"),
+                ConfigError::MissingListenAddr,
+            ),
+            (
+                server().addr("localhost:3515"),
+                ConfigError::InvalidListenAddr("localhost:3515".to_string()),
+            ),
+            (
+                ServerConfig::builder().addr("3515"),
+                ConfigError::MissingCredentials,
+            ),
+            (
+                server().client_cert_only(true),
+                ConfigError::ClientCertOnlyWithoutCa,
+            ),
+            (
+                ServerConfig::builder()
+                    .addr("3515")
+                    .client_ca_path("ca.pem"),
+                ConfigError::ClientCaWithoutCertOnly,
+            ),
+            (server().cert_path("cert.pem"), ConfigError::MissingKey),
+            (
+                server().dashboard_server("127.0.0.1:3516"),
+                ConfigError::InvalidDashboardCredential,
+            ),
+            (
+                server().max_udp_payload_size(MAX_UDP_PAYLOAD_SIZE + 1),
+                ConfigError::InvalidMaxUdpPayloadSize(MAX_UDP_PAYLOAD_SIZE + 1),
+            ),
+            (
+                server().user(user("alice")).user(user("alice")),
+                ConfigError::DuplicateUser("alice".to_string()),
+            ),
+        ];
+        for (builder, error) in cases {
+            assert_eq!(builder.build().unwrap_err(), error);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Admin HTTP API of the server.
+//!
+//! Serves JSON snapshots of the server bookkeeping, protected by HTTP Basic
+//! authentication with [`ServerConfig::dashboard_server_credential`]:
+//!
+//! - `GET /api/sessions`: authenticated sessions, see [`SessionInfo`]
+//! - `GET /api/listeners`: listeners opened for IN tunnels, see [`ListenerInfo`]
+//! - `GET /api/totals`: server-wide counters, see [`ServerTotals`]
+//! - `POST /api/sessions/{id}/close`: close a session
+//! - `POST /api/users/{user}/revoke`: close all sessions of a user and refuse
+//!   its logins, 404 if the user has no sessions and is not in the config
+//! - `POST /api/users/{user}/restore`: allow a revoked user to log in again
+//! - `POST /api/reload`: reload the config and certificates, see
+//!   [`Server::reload`]
+//!
+//! The `{user}` segment is percent-encoded, so that certificate identities such
+//! as `Jane%20Doe` can be addressed. The body of a `close` or `revoke` request, if any, is the reason reported to
+//! the client.
+//!
+//! [`ServerConfig::dashboard_server_credential`]: crate::ServerConfig::dashboard_server_credential
+//! [`SessionInfo`]: crate::SessionInfo
+//! [`ListenerInfo`]: crate::ListenerInfo
+//! [`ServerTotals`]: crate::ServerTotals
+
+use crate::util::http::{self, HttpRequest};
+use crate::Server;
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use rs_utilities::log_and_bail;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CLOSE_REASON: &str = "closed by admin";
+const DEFAULT_REVOKE_REASON: &str = "revoked by admin";
+
+pub(crate) struct Dashboard {
+    server: Server,
+    credential: String,
+}
+
+impl Dashboard {
+    /// Bind the dashboard on `addr` and serve it in the background.
+    pub async fn start(server: Server, addr: &str, credential: &str) -> Result<()> {
+        if !credential.contains(':') {
+            log_and_bail!("dashboard credential must be in the form user:password");
+        }
+
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("failed to bind dashboard on address: {addr}"))?;
+        info!("dashboard is bound on address: {}", listener.local_addr()?);
+
+        let dashboard = Arc::new(Dashboard {
+            server,
+            credential: credential.to_string(),
+        });
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let dashboard = dashboard.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = dashboard.handle(stream).await {
+                                debug!("dashboard request from {addr} failed: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("dashboard failed to accept connection: {e}");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, http::read_request(&mut stream))
+            .await
+            .context("dashboard request timed out")??;
+
+        if !request.has_basic_auth(&self.credential) {
+            warn!(
+                "unauthorized dashboard request from {}: {} {}",
+                stream.peer_addr()?,
+                request.method,
+                request.path
+            );
+            return http::write_response(
+                &mut stream,
+                401,
+                "text/plain",
+                &[("WWW-Authenticate", "Basic realm=\"rstund\"")],
+                b"unauthorized",
+            )
+            .await;
+        }
+
+        self.route(&request, &mut stream).await
+    }
+
+    async fn route(&self, request: &HttpRequest, stream: &mut TcpStream) -> Result<()> {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["api", "sessions"]) => Self::write_json(stream, &self.server.sessions()).await,
+            ("GET", ["api", "listeners"]) => {
+                Self::write_json(stream, &self.server.listeners()).await
+            }
+            ("GET", ["api", "totals"]) => Self::write_json(stream, &self.server.totals()).await,
+            ("POST", ["api", "sessions", id, "close"]) => {
+                let Ok(id) = id.parse::<u64>() else {
+                    return http::write_response(stream, 400, "text/plain", &[], b"invalid id")
+                        .await;
+                };
+                let reason = Self::reason(request, DEFAULT_CLOSE_REASON);
+                if self.server.close_session(id, &reason) {
+                    Self::write_json(stream, &json!({ "closed": 1 })).await
+                } else {
+                    http::write_response(stream, 404, "text/plain", &[], b"no such session").await
+                }
+            }
+            ("POST", ["api", "users", user, "revoke"]) => {
+                let Some(user) = http::percent_decode(user) else {
+                    return http::write_response(stream, 400, "text/plain", &[], b"invalid user")
+                        .await;
+                };
+                let reason = Self::reason(request, DEFAULT_REVOKE_REASON);
+                match self.server.revoke_user(&user, &reason) {
+                    Some(closed) => Self::write_json(stream, &json!({ "closed": closed })).await,
+                    None => {
+                        http::write_response(stream, 404, "text/plain", &[], b"no such user").await
+                    }
+                }
+            }
+            ("POST", ["api", "users", user, "restore"]) => {
+                let Some(user) = http::percent_decode(user) else {
+                    return http::write_response(stream, 400, "text/plain", &[], b"invalid user")
+                        .await;
+                };
+                let restored = self.server.restore_user(&user);
+                Self::write_json(stream, &json!({ "restored": restored })).await
+            }
+            ("POST", ["api", "reload"]) => match self.server.reload() {
+                Ok(report) => Self::write_json(stream, &report).await,
+                Err(e) => {
+                    let body = format!("failed to reload config: {e:#}");
+                    http::write_response(stream, 500, "text/plain", &[], body.as_bytes()).await
+                }
+            },
+            ("GET", _) | ("POST", _) => {
+                http::write_response(stream, 404, "text/plain", &[], b"not found").await
+            }
+            _ => http::write_response(stream, 405, "text/plain", &[], b"method not allowed").await,
+        }
+    }
+
+    /// The reason in the body of `request`, `default` if the body is empty.
+    fn reason(request: &HttpRequest, default: &str) -> String {
+        let reason = String::from_utf8_lossy(&request.body).trim().to_string();
+        if reason.is_empty() {
+            default.to_string()
+        } else {
+            reason
+        }
+    }
+
+    async fn write_json<T: Serialize>(stream: &mut TcpStream, value: &T) -> Result<()> {
+        let body = serde_json::to_vec(value)?;
+        http::write_response(stream, 200, "application/json", &[], &body).await
+    }
+}
@@ -6,26 +6,44 @@
 //!
 //! Binaries rstunc (client) and rstund (server) are provided under src/bin.
 
+mod acl;
+mod admission;
+mod auth;
 mod client;
+mod config;
+mod config_builder;
+mod dashboard;
+mod login_guard;
+mod metrics;
+mod mux;
 mod pem_util;
+mod quota;
 mod server;
+mod server_event;
 mod tcp;
 mod tunnel_info_bridge;
 mod tunnel_message;
 mod udp;
+mod upstream_addr;
 mod util;
 
+pub use acl::{AclAction, AclRule, IpNet, PortRange, UpstreamAcl};
+pub use admission::AdmissionStats;
 use anyhow::{Context, Result};
+pub use auth::{AuthDecision, AuthRequest, Authenticator, Credentials, DefaultAuthenticator};
 use byte_pool::BytePool;
 pub use client::Client;
 pub use client::ClientState;
+pub use config::resolve_secret;
+pub use config_builder::{ClientConfigBuilder, ConfigError, ServerConfigBuilder};
 use lazy_static::lazy_static;
 use log::warn;
 use rs_utilities::log_and_bail;
 use rustls::crypto::ring::cipher_suite;
 use serde::Deserialize;
 use serde::Serialize;
-pub use server::Server;
+pub use server::{ListenerInfo, ReloadReport, Server, ServerTotals, SessionInfo};
+pub use server_event::ServerEvent;
 use std::fmt::Display;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
@@ -34,8 +52,10 @@ use std::{net::SocketAddr, ops::Deref};
 pub use tcp::tcp_server::TcpServer;
 pub use tcp::{AsyncStream, StreamMessage, StreamReceiver, StreamRequest, StreamSender};
 use tunnel_message::LoginInfo;
+pub use tunnel_message::{LoginErrorCode, LoginFailure};
 use udp::udp_server::UdpServer;
 pub use udp::{UdpMessage, UdpPacket, UdpReceiver, UdpSender};
+pub use upstream_addr::UpstreamAddr;
 
 extern crate bincode;
 extern crate pretty_env_logger;
@@ -44,8 +64,15 @@ extern crate pretty_env_logger;
 pub const TUNNEL_MODE_IN: &str = "IN";
 /// Human-readable tunnel direction used in CLI/config strings.
 pub const TUNNEL_MODE_OUT: &str = "OUT";
-/// Maximum UDP payload size (bytes) used by this crate.
+/// Default maximum UDP payload size (bytes).
+///
+/// `max_udp_payload_size` of [`ClientConfig`] and [`ServerConfig`] bounds the
+/// payloads forwarded by each side, larger datagrams are dropped. It may be
+/// set up to [`MAX_UDP_PAYLOAD_SIZE`], 0 stands for this default on both sides
+/// and is replaced by `normalize()`.
 pub const UDP_PACKET_SIZE: usize = 1500;
+/// Largest configurable UDP payload size (bytes), the most an IPv4 datagram carries.
+pub const MAX_UDP_PAYLOAD_SIZE: usize = 65507;
 
 lazy_static! {
     static ref BUFFER_POOL: BytePool::<Vec<u8>> = BytePool::<Vec<u8>>::new();
@@ -125,7 +152,8 @@ impl Deref for SelectedCipherSuite {
 #[derive(Debug)]
 pub struct TcpTunnelOutInfo {
     conn: quinn::Connection,
-    upstream_addr: SocketAddr,
+    upstream_addr: UpstreamAddr,
+    user: String,
 }
 
 /// Info about an inbound TCP tunnel (client accepts local TCP and forwards to server).
@@ -133,13 +161,15 @@ pub struct TcpTunnelOutInfo {
 pub struct TcpTunnelInInfo {
     conn: quinn::Connection,
     tcp_server: TcpServer,
+    user: String,
 }
 
 /// Info about an outbound UDP tunnel (client connects to server, server sends to upstream).
 #[derive(Debug)]
 pub struct UdpTunnelOutInfo {
     conn: quinn::Connection,
-    upstream_addr: SocketAddr,
+    upstream_addr: UpstreamAddr,
+    user: String,
 }
 
 /// Info about an inbound UDP tunnel (client accepts local UDP and forwards to server).
@@ -147,6 +177,23 @@ pub struct UdpTunnelOutInfo {
 pub struct UdpTunnelInInfo {
     conn: quinn::Connection,
     udp_server: UdpServer,
+    user: String,
+}
+
+/// Info about a channel-based OUT tunnel whose upstreams are chosen per stream by the client.
+#[derive(Debug)]
+pub struct DynamicUpstreamInfo {
+    conn: quinn::Connection,
+    user: String,
+}
+
+/// Info about many tunnels carried by one connection, each with the id its
+/// streams are tagged with.
+#[derive(Debug)]
+pub struct MultiplexedInfo {
+    conn: quinn::Connection,
+    user: String,
+    tunnels: Vec<(mux::TunnelId, TunnelType)>,
 }
 
 /// Negotiated tunnel role and transport type after authentication.
@@ -161,9 +208,65 @@ pub enum TunnelType {
     /// UDP IN mode: server spawns a local UDP socket for the client.
     UdpIn(UdpTunnelInInfo),
     /// Channel-based TCP OUT: upstream decided dynamically by the client.
-    DynamicUpstreamTcpOut(quinn::Connection),
+    DynamicUpstreamTcpOut(DynamicUpstreamInfo),
     /// Channel-based UDP OUT: upstream decided dynamically by the client.
-    DynamicUpstreamUdpOut(quinn::Connection),
+    DynamicUpstreamUdpOut(DynamicUpstreamInfo),
+    /// Network-based tunnels multiplexed over one connection.
+    Multiplexed(MultiplexedInfo),
+}
+
+impl TunnelType {
+    /// Authenticated user that owns the tunnel, empty for shared-password logins.
+    pub fn user(&self) -> &str {
+        match self {
+            Self::TcpOut(info) => &info.user,
+            Self::TcpIn(info) => &info.user,
+            Self::UdpOut(info) => &info.user,
+            Self::UdpIn(info) => &info.user,
+            Self::DynamicUpstreamTcpOut(info) | Self::DynamicUpstreamUdpOut(info) => &info.user,
+            Self::Multiplexed(info) => &info.user,
+        }
+    }
+
+    /// Short name of the tunnel type, e.g. TCP_OUT.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::TcpOut(_) => "TCP_OUT",
+            Self::TcpIn(_) => "TCP_IN",
+            Self::UdpOut(_) => "UDP_OUT",
+            Self::UdpIn(_) => "UDP_IN",
+            Self::DynamicUpstreamTcpOut(_) => "TCP_DYNAMIC",
+            Self::DynamicUpstreamUdpOut(_) => "UDP_DYNAMIC",
+            Self::Multiplexed(_) => "MULTIPLEXED",
+        }
+    }
+
+    /// Upstream of OUT tunnels or listen address of IN tunnels, None for
+    /// channel-based tunnels whose upstreams are chosen per stream and for
+    /// multiplexed tunnels.
+    pub(crate) fn upstream_addr(&self) -> Option<UpstreamAddr> {
+        match self {
+            Self::TcpOut(info) => Some(info.upstream_addr.clone()),
+            Self::TcpIn(info) => Some(info.tcp_server.addr().into()),
+            Self::UdpOut(info) => Some(info.upstream_addr.clone()),
+            Self::UdpIn(info) => Some(info.udp_server.addr().into()),
+            Self::DynamicUpstreamTcpOut(_)
+            | Self::DynamicUpstreamUdpOut(_)
+            | Self::Multiplexed(_) => None,
+        }
+    }
+
+    /// Connection the tunnel runs on.
+    pub(crate) fn conn(&self) -> &quinn::Connection {
+        match self {
+            Self::TcpOut(info) => &info.conn,
+            Self::TcpIn(info) => &info.conn,
+            Self::UdpOut(info) => &info.conn,
+            Self::UdpIn(info) => &info.conn,
+            Self::DynamicUpstreamTcpOut(info) | Self::DynamicUpstreamUdpOut(info) => &info.conn,
+            Self::Multiplexed(info) => &info.conn,
+        }
+    }
 }
 
 /// Direction of a tunnel: Inbound or Outbound relative to the client.
@@ -201,15 +304,16 @@ impl Display for UpstreamType {
 /// Upstream endpoint definition.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Upstream {
-    /// Destination address on the peer side (None means use server default in OUT mode).
-    pub upstream_addr: Option<SocketAddr>,
+    /// Destination address on the peer side (None means use server default in OUT mode),
+    /// domain names are resolved by the server.
+    pub upstream_addr: Option<UpstreamAddr>,
     /// Transport type to use when forwarding to the upstream.
     pub upstream_type: UpstreamType,
 }
 
 impl Display for Upstream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.upstream_addr {
+        match &self.upstream_addr {
             Some(addr) => write!(f, "{}", addr),
             None => write!(f, "PeerDefault"),
         }
@@ -219,32 +323,96 @@ impl Display for Upstream {
 /// A single tunnel specification.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct TunnelConfig {
+    /// Name of the tunnel used in logs and reported info, not sent to the server.
+    #[serde(skip)]
+    pub name: String,
     /// Direction of the tunnel, relative to the client.
     pub mode: TunnelMode,
     /// Local listen address for NetworkBased tunnels (Some) or None for ChannelBased.
     pub local_server_addr: Option<SocketAddr>,
     /// Upstream config on the server side.
     pub upstream: Upstream,
+    /// Throughput limit of the tunnel, enforced by the client.
+    pub rate_limit: Option<RateLimit>,
+    /// Idle timeout of the tunnel's streams (ms), overrides the TCP or UDP
+    /// timeout of [`ClientConfig`].
+    #[serde(skip)]
+    pub idle_timeout_ms: Option<u64>,
+}
+
+/// Throughput limit in bytes per second for each direction, 0 means unlimited.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Client to server direction.
+    #[serde(default)]
+    pub upload_bytes_per_sec: u64,
+    /// Server to client direction.
+    #[serde(default)]
+    pub download_bytes_per_sec: u64,
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    /// Parse UPLOAD:DOWNLOAD, e.g. 512K:4M; rates take an optional K, M or G
+    /// suffix (powers of 1024) and 0 means unlimited.
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((upload, download)) = s.split_once(':') else {
+            log_and_bail!("invalid rate limit '{s}', expected UPLOAD:DOWNLOAD");
+        };
+
+        let parse_rate = |rate: &str| -> Result<u64> {
+            let rate = rate.trim();
+            let (digits, multiplier) = match rate.char_indices().last() {
+                Some((i, 'K' | 'k')) => (&rate[..i], 1 << 10),
+                Some((i, 'M' | 'm')) => (&rate[..i], 1 << 20),
+                Some((i, 'G' | 'g')) => (&rate[..i], 1 << 30),
+                _ => (rate, 1),
+            };
+            digits
+                .parse::<u64>()
+                .map(|rate| rate.saturating_mul(multiplier))
+                .with_context(|| format!("invalid rate limit: {s}"))
+        };
+
+        Ok(RateLimit {
+            upload_bytes_per_sec: parse_rate(upload)?,
+            download_bytes_per_sec: parse_rate(download)?,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub(crate) enum Tunnel {
+pub enum Tunnel {
     /// Tunnel driven by local networking (binds on a local address).
     NetworkBased(TunnelConfig),
     /// Tunnel driven by in-process channels (no local binding).
     ChannelBased(UpstreamType),
+    /// Many network-based tunnels sharing one connection, each stream is
+    /// tagged with the index of its tunnel, see [`ClientConfig::multiplex`].
+    Multiplexed(Vec<TunnelConfig>),
 }
 
 /// Client-side runtime configuration.
-#[derive(Debug, Default, Clone)]
+///
+/// Deserializable from a TOML file with [`ClientConfig::from_file`], keys are
+/// the field names and tunnels are listed as named `[[tunnels]]` tables.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct ClientConfig {
     /// Path to a PEM certificate for server identity (self-signed use-case).
     pub cert_path: String,
+    /// Path to a PEM client certificate presented to the server (empty for none).
+    pub client_cert_path: String,
+    /// Path to the PEM private key of the client certificate.
+    pub client_key_path: String,
     /// Preferred TLS cipher suite string (see SUPPORTED_CIPHER_SUITE_STRS).
     pub cipher: String,
     /// Server address in "host:port".
     pub server_addr: String,
-    /// Shared password for authentication.
+    /// Username for authentication, empty to log in with the server's shared password.
+    pub username: String,
+    /// Password for authentication.
     pub password: String,
     /// Wait time before retrying a failed connection.
     pub wait_before_retry_ms: u64,
@@ -254,49 +422,234 @@ pub struct ClientConfig {
     pub tcp_timeout_ms: u64,
     /// UDP idle timeout (ms).
     pub udp_timeout_ms: u64,
+    /// Largest UDP payload (bytes) forwarded, see [`UDP_PACKET_SIZE`].
+    pub max_udp_payload_size: usize,
     /// Periodic endpoint migration interval (ms); 0 disables.
     pub hop_interval_ms: u64,
     /// Tunnel definitions to start.
+    #[serde(deserialize_with = "config::deserialize_tunnels")]
     pub tunnels: Vec<TunnelConfig>,
+    /// Carry all tunnels over one connection instead of one connection per
+    /// tunnel, falls back to one connection per tunnel if the server can't.
+    pub multiplex: bool,
+    /// Send UDP payloads as unreliable QUIC datagrams if the server supports
+    /// them, payloads too large for a datagram still go over the stream.
+    pub udp_datagrams: bool,
+    /// Throughput limit of the channel-based tunnels, enforced by the client.
+    pub channel_rate_limit: Option<RateLimit>,
     /// DNS-over-TLS servers (domain names). Takes precedence over dns_servers if non-empty.
     pub dot_servers: Vec<String>,
     /// Plain DNS servers (IP addresses).
     pub dns_servers: Vec<String>,
     /// Number of async worker threads.
     pub workers: usize,
+    /// Address of the Prometheus metrics endpoint, e.g. 127.0.0.1:9090 (empty disables it).
+    pub metrics_server: String,
 }
 
 /// Server-side runtime configuration.
-#[derive(Debug, Clone)]
+///
+/// Deserializable from a TOML file with [`ServerConfig::from_file`], keys are
+/// the field names and omitted keys take their default values.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerConfig {
     /// Bind address for the server (host:port).
     pub addr: String,
-    /// Shared password for authentication.
+    /// Shared password for clients logging in without a username (empty disables it).
     pub password: String,
+    /// Per-user accounts, looked up by the username sent by the client.
+    pub users: Vec<UserConfig>,
     /// Path to certificate PEM.
     pub cert_path: String,
     /// Path to private key PEM.
     pub key_path: String,
+    /// Path to a CA certificate PEM; when set, clients must present a certificate signed by it.
+    pub client_ca_path: String,
+    /// Accept a verified client certificate as sufficient authentication, without a password.
+    pub client_cert_only: bool,
     /// QUIC idle timeout (ms).
     pub quic_timeout_ms: u64,
     /// TCP idle timeout (ms).
     pub tcp_timeout_ms: u64,
     /// UDP idle timeout (ms).
     pub udp_timeout_ms: u64,
+    /// Largest UDP payload (bytes) forwarded, see [`UDP_PACKET_SIZE`].
+    pub max_udp_payload_size: usize,
 
     /// for TunnelOut only
-    pub default_tcp_upstream: Option<SocketAddr>,
+    pub default_tcp_upstream: Option<UpstreamAddr>,
     /// for TunnelOut only
-    pub default_udp_upstream: Option<SocketAddr>,
+    pub default_udp_upstream: Option<UpstreamAddr>,
+    /// Rules restricting the upstreams dialed for OUT and channel-based tunnels.
+    pub upstream_acl: UpstreamAcl,
+    /// Ports IN tunnels may listen on, empty allows any port.
+    pub in_port_ranges: Vec<PortRange>,
+    /// Throughput limit shared by all clients of the server.
+    pub rate_limit: RateLimit,
+    /// Throughput limit applied to each tunnel separately.
+    pub tunnel_rate_limit: RateLimit,
+    /// Path to the JSON file persisting the traffic usage of users (empty keeps it in memory).
+    pub quota_state_path: String,
+    /// Maximum number of concurrent sessions, 0 for unlimited.
+    pub max_sessions: usize,
+    /// Maximum number of concurrent sessions from one source IP, 0 for unlimited.
+    pub max_sessions_per_ip: usize,
+    /// Maximum number of concurrent sessions of one user, 0 for unlimited.
+    pub max_sessions_per_user: usize,
+    /// Maximum number of concurrent streams on a connection, 0 for the default of 1024.
+    pub max_streams_per_conn: u32,
+    /// Maximum number of new streams per second on a connection, 0 for unlimited.
+    pub max_new_streams_per_sec: u32,
+    /// Failed logins after which a source IP is banned, 0 disables the protection.
+    pub max_login_failures: u32,
+    /// How long a source IP stays banned (s).
+    pub login_ban_secs: u64,
 
-    /// 0.0.0.0:3515
+    /// Address of the admin HTTP API, e.g. 127.0.0.1:3516 (empty disables it).
     pub dashboard_server: String,
-    /// user:password
+    /// Credential of the admin HTTP API in the form user:password.
     pub dashboard_server_credential: String,
+    /// Address of the Prometheus metrics endpoint, e.g. 127.0.0.1:9090 (empty disables it).
+    pub metrics_server: String,
+}
+
+/// A user account on the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// Name the client logs in with.
+    pub username: String,
+    /// Password of the user.
+    pub password: String,
+    /// Disabled users are rejected at login.
+    #[serde(default = "default_user_enabled")]
+    pub enabled: bool,
+    /// Ports the user may claim for IN tunnels, overrides [`ServerConfig::in_port_ranges`].
+    #[serde(default)]
+    pub in_port_ranges: Option<Vec<PortRange>>,
+    /// Throughput limit shared by all tunnels of the user.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Traffic quota of the user, counted over all of the user's connections.
+    #[serde(default)]
+    pub quota: Option<TrafficQuota>,
+    /// Default TCP upstream for OUT tunnels of the user, overrides
+    /// [`ServerConfig::default_tcp_upstream`].
+    #[serde(default)]
+    pub default_tcp_upstream: Option<UpstreamAddr>,
+    /// Default UDP upstream for OUT tunnels of the user, overrides
+    /// [`ServerConfig::default_udp_upstream`].
+    #[serde(default)]
+    pub default_udp_upstream: Option<UpstreamAddr>,
+}
+
+/// Traffic quota in bytes (upload plus download), 0 means unlimited.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficQuota {
+    /// Bytes allowed per calendar day.
+    #[serde(default)]
+    pub daily_bytes: u64,
+    /// Bytes allowed per calendar month.
+    #[serde(default)]
+    pub monthly_bytes: u64,
+}
+
+fn default_user_enabled() -> bool {
+    true
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            username: String::new(),
+            password: String::new(),
+            enabled: default_user_enabled(),
+            in_port_ranges: None,
+            rate_limit: None,
+            quota: None,
+            default_tcp_upstream: None,
+            default_udp_upstream: None,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: String::new(),
+            password: String::new(),
+            users: Vec::new(),
+            cert_path: String::new(),
+            key_path: String::new(),
+            client_ca_path: String::new(),
+            client_cert_only: false,
+            quic_timeout_ms: 40000,
+            tcp_timeout_ms: 30000,
+            udp_timeout_ms: 5000,
+            max_udp_payload_size: UDP_PACKET_SIZE,
+            default_tcp_upstream: None,
+            default_udp_upstream: None,
+            upstream_acl: UpstreamAcl::default(),
+            in_port_ranges: Vec::new(),
+            rate_limit: RateLimit::default(),
+            tunnel_rate_limit: RateLimit::default(),
+            quota_state_path: String::new(),
+            max_sessions: 0,
+            max_sessions_per_ip: 0,
+            max_sessions_per_user: 0,
+            max_streams_per_conn: 1024,
+            max_new_streams_per_sec: 0,
+            max_login_failures: 5,
+            login_ban_secs: 600,
+            dashboard_server: String::new(),
+            dashboard_server_credential: String::new(),
+            metrics_server: String::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Replace zero values with their defaults.
+    pub fn normalize(&mut self) {
+        if self.max_udp_payload_size == 0 {
+            self.max_udp_payload_size = UDP_PACKET_SIZE;
+        }
+    }
+
+    /// Find the account of the given user.
+    pub(crate) fn find_user(&self, username: &str) -> Option<&UserConfig> {
+        self.users.iter().find(|u| u.username == username)
+    }
+
+    /// Default upstream of the given user for OUT tunnels of `upstream_type`.
+    pub(crate) fn default_upstream_of(
+        &self,
+        username: &str,
+        upstream_type: &UpstreamType,
+    ) -> Option<UpstreamAddr> {
+        let user = self.find_user(username);
+        match upstream_type {
+            UpstreamType::Tcp => user
+                .and_then(|u| u.default_tcp_upstream.clone())
+                .or_else(|| self.default_tcp_upstream.clone()),
+            UpstreamType::Udp => user
+                .and_then(|u| u.default_udp_upstream.clone())
+                .or_else(|| self.default_udp_upstream.clone()),
+        }
+    }
+
+    /// Ports the given user may claim for IN tunnels, empty allows any port.
+    pub(crate) fn in_port_ranges_of(&self, username: &str) -> &[PortRange] {
+        self.find_user(username)
+            .and_then(|u| u.in_port_ranges.as_deref())
+            .unwrap_or(&self.in_port_ranges)
+    }
 }
 
 impl ClientConfig {
-    /// Create a ClientConfig by parsing CLI-style mapping strings.
+    /// Create a ClientConfig by parsing CLI-style mapping strings, a shorthand
+    /// for [`ClientConfig::builder`].
     ///
     /// - tcp_addr_mappings / udp_addr_mappings: comma-separated entries in the form
     ///   MODE^SRC^DEST where MODE is IN|OUT, SRC is [ip:]port, DEST is [ip:]port or ANY
@@ -315,59 +668,110 @@ impl ClientConfig {
         dns: &str,
         workers: usize,
         wait_before_retry_ms: u64,
-        mut quic_timeout_ms: u64,
-        mut tcp_timeout_ms: u64,
-        mut udp_timeout_ms: u64,
-        mut hop_interval_ms: u64,
+        quic_timeout_ms: u64,
+        tcp_timeout_ms: u64,
+        udp_timeout_ms: u64,
+        hop_interval_ms: u64,
     ) -> Result<ClientConfig> {
-        if tcp_addr_mappings.is_empty() && udp_addr_mappings.is_empty() {
-            log_and_bail!("must specify either --tcp-mappings or --udp-mappings, or both");
-        }
+        let config = ClientConfig::builder()
+            .server_addr(server_addr)
+            .password(password)
+            .cert_path(cert)
+            .cipher(cipher)
+            .mappings(tcp_addr_mappings, udp_addr_mappings)
+            .dot_server_list(dot)
+            .dns_server_list(dns)
+            .workers(workers)
+            .wait_before_retry_ms(wait_before_retry_ms)
+            .quic_timeout_ms(quic_timeout_ms)
+            .tcp_timeout_ms(tcp_timeout_ms)
+            .udp_timeout_ms(udp_timeout_ms)
+            .hop_interval_ms(hop_interval_ms)
+            .build()?;
+        Ok(config)
+    }
+
+    /// Replace the tunnels with the ones parsed from CLI-style mapping
+    /// strings, see [`ClientConfig::create`]. Tunnels are named after their
+    /// position, starting at 0.
+    pub fn set_mappings(&mut self, tcp_addr_mappings: &str, udp_addr_mappings: &str) -> Result<()> {
+        self.tunnels.clear();
+        parse_addr_mappings(tcp_addr_mappings, UpstreamType::Tcp, &mut self.tunnels)?;
+        parse_addr_mappings(udp_addr_mappings, UpstreamType::Udp, &mut self.tunnels)?;
+        Ok(())
+    }
 
-        if quic_timeout_ms == 0 {
-            quic_timeout_ms = 30000;
+    /// Replace zero values with their defaults, complete a port-only server
+    /// address and raise a too low hop interval.
+    pub fn normalize(&mut self) {
+        if !self.server_addr.contains(':') {
+            self.server_addr = format!("127.0.0.1:{}", self.server_addr);
+        }
+        if self.workers == 0 {
+            self.workers = num_cpus::get();
+        }
+        if self.quic_timeout_ms == 0 {
+            self.quic_timeout_ms = 30000;
+        }
+        if self.tcp_timeout_ms == 0 {
+            self.tcp_timeout_ms = 30000;
         }
-        if tcp_timeout_ms == 0 {
-            tcp_timeout_ms = 30000;
+        if self.udp_timeout_ms == 0 {
+            self.udp_timeout_ms = 5000;
         }
-        if udp_timeout_ms == 0 {
-            udp_timeout_ms = 5000;
+        if self.max_udp_payload_size == 0 {
+            self.max_udp_payload_size = UDP_PACKET_SIZE;
         }
-        if hop_interval_ms != 0 && hop_interval_ms < 5000 {
-            warn!("Endpoint migration interval: {hop_interval_ms} ms is too low and has been forcibly set to 5000 ms to prevent potential network failures due to excessive port or NAT resource exhaustion."
-                    );
-            hop_interval_ms = 5000;
+        if self.hop_interval_ms != 0 && self.hop_interval_ms < 5000 {
+            warn!("Endpoint migration interval: {} ms is too low and has been forcibly set to 5000 ms to prevent potential network failures due to excessive port or NAT resource exhaustion.",
+                    self.hop_interval_ms);
+            self.hop_interval_ms = 5000;
         }
+    }
+}
 
-        let mut config = ClientConfig {
-            cert_path: cert.to_string(),
-            cipher: cipher.to_string(),
-            server_addr: if !server_addr.contains(':') {
-                format!("127.0.0.1:{server_addr}")
-            } else {
-                server_addr.to_string()
-            },
-            password: password.to_string(),
-            workers: if workers > 0 {
-                workers
-            } else {
-                num_cpus::get()
-            },
-            wait_before_retry_ms,
-            quic_timeout_ms,
-            tcp_timeout_ms,
-            udp_timeout_ms,
-            hop_interval_ms,
-            dot_servers: dot.split(',').map(|s| s.to_string()).collect(),
-            dns_servers: dns.split(',').map(|s| s.to_string()).collect(),
-            ..ClientConfig::default()
-        };
+impl TunnelConfig {
+    /// Idle timeout of the tunnel's streams, falling back to the timeout of
+    /// `config` for the tunnel's upstream type.
+    pub(crate) fn idle_timeout_ms(&self, config: &ClientConfig) -> u64 {
+        self.idle_timeout_ms
+            .unwrap_or(match self.upstream.upstream_type {
+                UpstreamType::Tcp => config.tcp_timeout_ms,
+                UpstreamType::Udp => config.udp_timeout_ms,
+            })
+    }
+}
 
-        parse_addr_mappings(tcp_addr_mappings, UpstreamType::Tcp, &mut config.tunnels)?;
-        parse_addr_mappings(udp_addr_mappings, UpstreamType::Udp, &mut config.tunnels)?;
+/// Parse a tunnel address given as [ip:]port, or ANY (returned as None).
+pub(crate) fn parse_tunnel_addr(addr: &str) -> Result<Option<SocketAddr>> {
+    if addr == "ANY" {
+        return Ok(None);
+    }
 
-        Ok(config)
+    // Handle port-only case
+    let port = addr.parse::<u16>();
+    if let Ok(port) = port {
+        return Ok(Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port,
+        )));
     }
+
+    // Parse full SocketAddr
+    Ok(Some(addr.parse().with_context(|| {
+        format!("Invalid address format '{addr}', expected IP:PORT or PORT")
+    })?))
+}
+
+/// Parse an upstream address given as [ip:]port, domain:port, or ANY (returned as None).
+pub(crate) fn parse_upstream_addr(addr: &str) -> Result<Option<UpstreamAddr>> {
+    if addr == "ANY" || addr.parse::<u16>().is_ok() {
+        return Ok(parse_tunnel_addr(addr)?.map(UpstreamAddr::Ip));
+    }
+
+    Ok(Some(addr.parse().with_context(|| {
+        format!("Invalid address format '{addr}', expected IP:PORT, DOMAIN:PORT or PORT")
+    })?))
 }
 
 fn parse_addr_mappings(
@@ -390,33 +794,14 @@ fn parse_addr_mappings(
             log_and_bail!("Invalid tunnel type, expected OUT or IN");
         }
 
-        let parse_addr = |addr: &str| -> Result<Option<SocketAddr>> {
-            if addr == "ANY" {
-                return Ok(None);
-            }
-
-            // Handle port-only case
-            let port = addr.parse::<u16>();
-            if let Ok(port) = port {
-                return Ok(Some(SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                    port,
-                )));
-            }
-
-            // Parse full SocketAddr
-            Ok(Some(addr.parse().with_context(|| {
-                format!("Invalid address format '{addr}', expected IP:PORT or PORT")
-            })?))
-        };
-
-        let local_server_addr = parse_addr(parts[1])?;
+        let local_server_addr = parse_tunnel_addr(parts[1])?;
         if local_server_addr.is_none() {
             log_and_bail!("'ANY' is not allowed as local_server_addr");
         }
-        let upstream_addr = parse_addr(parts[2])?;
+        let upstream_addr = parse_upstream_addr(parts[2])?;
 
         v.push(TunnelConfig {
+            name: v.len().to_string(),
             mode: if tunnel_mode == "IN" {
                 TunnelMode::In
             } else {
@@ -427,6 +812,8 @@ fn parse_addr_mappings(
                 upstream_type: upstream_type.clone(),
             },
             local_server_addr,
+            rate_limit: None,
+            idle_timeout_ms: None,
         });
     }
 
@@ -513,7 +900,7 @@ pub mod android {
         jworkers: jint,
         jwaitBeforeRetryMs: jint,
         jquicTimeoutMs: jint,
-        jhopTimeoutMs: jint,
+        jhopIntervalMs: jint,
     ) -> jlong {
         let server_addr = convert_jstring(&mut env, jserverAddr);
         let tcp_mappings = convert_jstring(&mut env, jtcpMappings);
@@ -524,22 +911,21 @@ pub mod android {
         let cert_file_path = convert_jstring(&mut env, jcertFilePath);
         let cipher = convert_jstring(&mut env, jcipher);
 
-        match ClientConfig::create(
-            &server_addr,
-            &password,
-            &cert_file_path,
-            &cipher,
-            &tcp_mappings,
-            &udp_mappings,
-            &dot_server,
-            &dns_server,
-            jworkers as usize,
-            jwaitBeforeRetryMs as u64,
-            jquicTimeoutMs as u64,
-            0, // tcp_timeout_ms - use default
-            0, // udp_timeout_ms - use default
-            jhopTimeoutMs as u64,
-        ) {
+        // tcp and udp idle timeouts use the defaults
+        match ClientConfig::builder()
+            .server_addr(server_addr)
+            .password(password)
+            .cert_path(cert_file_path)
+            .cipher(cipher)
+            .mappings(&tcp_mappings, &udp_mappings)
+            .dot_server_list(&dot_server)
+            .dns_server_list(&dns_server)
+            .workers(jworkers as usize)
+            .wait_before_retry_ms(jwaitBeforeRetryMs as u64)
+            .quic_timeout_ms(jquicTimeoutMs as u64)
+            .hop_interval_ms(jhopIntervalMs as u64)
+            .build()
+        {
             Ok(client_config) => {
                 let client = Client::new(client_config);
                 let client = Box::into_raw(Box::new(client));
@@ -0,0 +1,211 @@
+//! Brute-force protection for logins.
+//!
+//! Failed logins are counted per source IP. Each failure delays the response
+//! exponentially, and once an IP reaches the configured number of failures it
+//! is banned for a while: the server then refuses its connections before the
+//! QUIC handshake. Failures are forgotten after a successful login, or when an
+//! IP has had no failures for as long as a ban lasts.
+
+use crate::ServerConfig;
+use log::warn;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const BASE_FAILURE_DELAY: Duration = Duration::from_millis(500);
+const MAX_FAILURE_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub(crate) struct LoginGuard {
+    max_failures: AtomicU32,
+    ban_secs: AtomicU64,
+    records: Mutex<HashMap<IpAddr, FailureRecord>>,
+}
+
+impl LoginGuard {
+    pub fn new(config: &ServerConfig) -> Self {
+        LoginGuard {
+            max_failures: AtomicU32::new(config.max_login_failures),
+            ban_secs: AtomicU64::new(config.login_ban_secs),
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Apply the settings of a reloaded `config`, current bans are kept.
+    pub fn update_limits(&self, config: &ServerConfig) {
+        self.max_failures
+            .store(config.max_login_failures, Ordering::Relaxed);
+        self.ban_secs
+            .store(config.login_ban_secs, Ordering::Relaxed);
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_failures.load(Ordering::Relaxed) > 0
+    }
+
+    fn ban_duration(&self) -> Duration {
+        Duration::from_secs(self.ban_secs.load(Ordering::Relaxed))
+    }
+
+    /// Return true if connections from `ip` must be refused.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+
+        let records = self.records.lock().unwrap();
+        records
+            .get(&ip.to_canonical())
+            .and_then(|record| record.banned_until)
+            .is_some_and(|banned_until| now < banned_until)
+    }
+
+    /// Record a failed login from `ip`, returns how long to delay the response.
+    pub fn record_failure(&self, ip: IpAddr) -> Duration {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) -> Duration {
+        if !self.is_enabled() {
+            return Duration::ZERO;
+        }
+
+        let ip = ip.to_canonical();
+        let ban_duration = self.ban_duration();
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(ip).or_insert(FailureRecord {
+            failures: 0,
+            last_failure: now,
+            banned_until: None,
+        });
+        if now.duration_since(record.last_failure) > ban_duration {
+            record.failures = 0;
+        }
+        record.failures += 1;
+        record.last_failure = now;
+        let delay = BASE_FAILURE_DELAY
+            .saturating_mul(1 << record.failures.saturating_sub(1).min(16))
+            .min(MAX_FAILURE_DELAY);
+
+        if record.failures >= self.max_failures.load(Ordering::Relaxed) {
+            warn!(
+                "[audit] banned {ip} for {}s after {} failed logins",
+                ban_duration.as_secs(),
+                record.failures
+            );
+            // start over once the ban has expired
+            record.failures = 0;
+            record.banned_until = Some(now + ban_duration);
+        }
+        delay
+    }
+
+    /// Forget the failures of `ip` after a successful login.
+    pub fn record_success(&self, ip: IpAddr) {
+        if self.is_enabled() {
+            self.records.lock().unwrap().remove(&ip.to_canonical());
+        }
+    }
+
+    /// Drop records whose ban has expired and whose failures are outdated.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let ban_duration = self.ban_duration();
+        self.records.lock().unwrap().retain(|_, record| {
+            record.banned_until.is_some_and(|until| now < until)
+                || now.duration_since(record.last_failure) <= ban_duration
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(max_login_failures: u32) -> LoginGuard {
+        LoginGuard::new(&ServerConfig {
+            max_login_failures,
+            login_ban_secs: 600,
+            ..ServerConfig::default()
+        })
+    }
+
+    #[test]
+    fn delay_grows_until_ban() {
+        let guard = guard(4);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        let delays: Vec<_> = (0..4).map(|_| guard.record_failure_at(ip, now)).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000].map(Duration::from_millis));
+        assert!(guard.is_banned_at(ip, now));
+        assert!(!guard.is_banned_at("10.0.0.2".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn failures_start_over_after_ban() {
+        let guard = guard(2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        guard.record_failure_at(ip, now);
+        guard.record_failure_at(ip, now);
+
+        let later = now + Duration::from_secs(601);
+        assert!(!guard.is_banned_at(ip, later));
+        assert_eq!(
+            guard.record_failure_at(ip, later),
+            Duration::from_millis(500)
+        );
+        assert!(!guard.is_banned_at(ip, later));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let guard = guard(100);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        let last = (0..30).map(|_| guard.record_failure_at(ip, now)).last();
+        assert_eq!(last, Some(MAX_FAILURE_DELAY));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_share_a_record() {
+        let guard = guard(2);
+        let now = Instant::now();
+        guard.record_failure_at("10.0.0.1".parse().unwrap(), now);
+        guard.record_failure_at("::ffff:10.0.0.1".parse().unwrap(), now);
+        assert!(guard.is_banned_at("10.0.0.1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let guard = guard(2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        guard.record_failure_at(ip, now);
+        guard.record_success(ip);
+        assert_eq!(guard.record_failure_at(ip, now), Duration::from_millis(500));
+        assert!(!guard.is_banned_at(ip, now));
+    }
+
+    #[test]
+    fn disabled_guard_never_delays() {
+        let guard = guard(0);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(guard.record_failure(ip), Duration::ZERO);
+        assert!(!guard.is_banned(ip));
+    }
+}
@@ -0,0 +1,303 @@
+//! Prometheus metrics of rstund and rstunc.
+//!
+//! Counters are process-wide and updated by the tunnel code as traffic flows,
+//! QUIC path stats are sampled from the live connections when scraped. The
+//! metrics are served in the Prometheus text format on `GET /metrics`.
+
+use crate::util::http;
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use quinn::{Connection, PathStats};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A counter, or a gauge when decremented.
+#[derive(Debug)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts an active TCP stream or UDP flow while alive.
+#[derive(Debug)]
+pub(crate) struct ActiveGuard(&'static Counter);
+
+impl ActiveGuard {
+    pub fn new(gauge: &'static Counter) -> Self {
+        gauge.inc();
+        ActiveGuard(gauge)
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Process-wide metrics. "sent" is traffic from local sockets into the tunnel,
+/// "received" is traffic from the tunnel to local sockets.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    pub tcp_streams: Counter,
+    pub udp_flows: Counter,
+    pub tcp_sent_bytes: Counter,
+    pub tcp_received_bytes: Counter,
+    pub udp_sent_bytes: Counter,
+    pub udp_received_bytes: Counter,
+    pub udp_sent_datagrams: Counter,
+    pub udp_received_datagrams: Counter,
+    pub udp_oversize_datagrams: Counter,
+    pub login_successes: Counter,
+    pub login_failures: Counter,
+    pub upstream_connect_failures: Counter,
+    pub upstream_connect_timeouts: Counter,
+}
+
+pub(crate) static METRICS: Metrics = Metrics {
+    tcp_streams: Counter::new(),
+    udp_flows: Counter::new(),
+    tcp_sent_bytes: Counter::new(),
+    tcp_received_bytes: Counter::new(),
+    udp_sent_bytes: Counter::new(),
+    udp_received_bytes: Counter::new(),
+    udp_sent_datagrams: Counter::new(),
+    udp_received_datagrams: Counter::new(),
+    udp_oversize_datagrams: Counter::new(),
+    login_successes: Counter::new(),
+    login_failures: Counter::new(),
+    upstream_connect_failures: Counter::new(),
+    upstream_connect_timeouts: Counter::new(),
+};
+
+/// Returns the live connections to report path stats for, each with the
+/// value of its `connection` label.
+pub(crate) type ConnectionsFn = dyn Fn() -> Vec<(String, Connection)> + Send + Sync;
+
+pub(crate) struct MetricsServer;
+
+impl MetricsServer {
+    /// Bind the metrics endpoint on `addr` and serve it in the background.
+    pub async fn start(addr: &str, connections: Arc<ConnectionsFn>) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("failed to bind metrics on address: {addr}"))?;
+        info!(
+            "metrics are served on: http://{}/metrics",
+            listener.local_addr()?
+        );
+
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let connections = connections.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = Self::handle(stream, connections.as_ref()).await {
+                                debug!("metrics request from {addr} failed: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("metrics failed to accept connection: {e}");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn handle(mut stream: TcpStream, connections: &ConnectionsFn) -> Result<()> {
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, http::read_request(&mut stream))
+            .await
+            .context("metrics request timed out")??;
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => {
+                let body = render(&connections());
+                http::write_response(
+                    &mut stream,
+                    200,
+                    "text/plain; version=0.0.4",
+                    &[],
+                    body.as_bytes(),
+                )
+                .await
+            }
+            ("GET", _) => {
+                http::write_response(&mut stream, 404, "text/plain", &[], b"not found").await
+            }
+            _ => {
+                http::write_response(&mut stream, 405, "text/plain", &[], b"method not allowed")
+                    .await
+            }
+        }
+    }
+}
+
+/// Render all metrics in the Prometheus text format.
+fn render(connections: &[(String, Connection)]) -> String {
+    let m = &METRICS;
+    let mut out = String::new();
+
+    write_metric(
+        &mut out,
+        "rstun_connections",
+        "gauge",
+        "Active QUIC connections.",
+        &[("", connections.len() as u64)],
+    );
+    write_metric(
+        &mut out,
+        "rstun_tcp_streams",
+        "gauge",
+        "Active TCP streams.",
+        &[("", m.tcp_streams.get())],
+    );
+    write_metric(
+        &mut out,
+        "rstun_udp_flows",
+        "gauge",
+        "Active UDP flows.",
+        &[("", m.udp_flows.get())],
+    );
+    write_metric(
+        &mut out,
+        "rstun_bytes_total",
+        "counter",
+        "Payload bytes sent into and received from the tunnel.",
+        &[
+            (
+                "protocol=\"tcp\",direction=\"sent\"",
+                m.tcp_sent_bytes.get(),
+            ),
+            (
+                "protocol=\"tcp\",direction=\"received\"",
+                m.tcp_received_bytes.get(),
+            ),
+            (
+                "protocol=\"udp\",direction=\"sent\"",
+                m.udp_sent_bytes.get(),
+            ),
+            (
+                "protocol=\"udp\",direction=\"received\"",
+                m.udp_received_bytes.get(),
+            ),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "rstun_udp_datagrams_total",
+        "counter",
+        "UDP datagrams sent into and received from the tunnel.",
+        &[
+            ("direction=\"sent\"", m.udp_sent_datagrams.get()),
+            ("direction=\"received\"", m.udp_received_datagrams.get()),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "rstun_udp_oversize_datagrams_total",
+        "counter",
+        "UDP datagrams dropped for exceeding the maximum payload size.",
+        &[("", m.udp_oversize_datagrams.get())],
+    );
+    write_metric(
+        &mut out,
+        "rstun_logins_total",
+        "counter",
+        "Logins by result.",
+        &[
+            ("result=\"success\"", m.login_successes.get()),
+            ("result=\"failure\"", m.login_failures.get()),
+        ],
+    );
+    write_metric(
+        &mut out,
+        "rstun_upstream_connect_failures_total",
+        "counter",
+        "Failed connects to upstreams by reason.",
+        &[
+            ("reason=\"error\"", m.upstream_connect_failures.get()),
+            ("reason=\"timeout\"", m.upstream_connect_timeouts.get()),
+        ],
+    );
+
+    let labels: Vec<String> = connections
+        .iter()
+        .map(|(name, _)| format!("connection=\"{}\"", escape_label(name)))
+        .collect();
+    let path_stats: Vec<_> = connections
+        .iter()
+        .map(|(_, conn)| conn.stats().path)
+        .collect();
+    let per_conn = |value: fn(&PathStats) -> u64| -> Vec<(&str, u64)> {
+        labels
+            .iter()
+            .zip(&path_stats)
+            .map(|(label, stats)| (label.as_str(), value(stats)))
+            .collect()
+    };
+    write_metric(
+        &mut out,
+        "rstun_quic_rtt_microseconds",
+        "gauge",
+        "Smoothed RTT of the QUIC path.",
+        &per_conn(|stats| stats.rtt.as_micros() as u64),
+    );
+    write_metric(
+        &mut out,
+        "rstun_quic_cwnd_bytes",
+        "gauge",
+        "Congestion window of the QUIC path.",
+        &per_conn(|stats| stats.cwnd),
+    );
+    write_metric(
+        &mut out,
+        "rstun_quic_lost_packets_total",
+        "counter",
+        "Packets lost on the QUIC path.",
+        &per_conn(|stats| stats.lost_packets),
+    );
+
+    out
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
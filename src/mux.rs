@@ -0,0 +1,133 @@
+//! Multiplexing of many tunnels over one QUIC connection.
+//!
+//! Every bi-stream of a multiplexed connection starts with the id of the
+//! tunnel it belongs to, which is the index of the tunnel in the login
+//! request. The side accepting streams reads the id and hands the stream to
+//! the handler of that tunnel, streams of unknown tunnels are reset.
+
+use crate::acl::UpstreamAcl;
+use crate::tcp::tcp_tunnel::TcpTunnel;
+use crate::udp::datagram::Datagrams;
+use crate::udp::udp_tunnel::UdpTunnel;
+use crate::util::rate_limiter::TrafficLimiter;
+use crate::{UpstreamAddr, UpstreamType};
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use quinn::{Connection, RecvStream, SendStream, VarInt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Index of a tunnel in a multiplexed login.
+pub(crate) type TunnelId = u16;
+
+/// Error code used to reset streams tagged with an unknown tunnel id.
+const UNKNOWN_TUNNEL_ERROR_CODE: u32 = 2;
+/// Time the opener of a stream has to send the tunnel id.
+const TUNNEL_ID_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the accepting side serves the streams of one tunnel.
+pub(crate) struct StreamHandler {
+    pub upstream_type: UpstreamType,
+    /// Upstream every stream of the tunnel is forwarded to.
+    pub upstream_addr: UpstreamAddr,
+    pub upstream_acl: Option<Arc<UpstreamAcl>>,
+    pub limiter: TrafficLimiter,
+    pub timeout_ms: u64,
+}
+
+/// Open a bi-stream, tagged with `tunnel_id` on multiplexed connections.
+pub(crate) async fn open_bi(
+    conn: &Connection,
+    tunnel_id: Option<TunnelId>,
+) -> Result<(SendStream, RecvStream)> {
+    let (mut quic_send, quic_recv) = conn.open_bi().await?;
+    if let Some(tunnel_id) = tunnel_id {
+        quic_send.write_u16(tunnel_id).await?;
+    }
+    Ok((quic_send, quic_recv))
+}
+
+/// Accept the streams of a multiplexed connection and serve each with the
+/// handler of its tunnel, until the connection is closed. Streams are accepted
+/// no faster than the stream limit of `stream_limiter` allows, whatever tunnel
+/// they are tagged with. UDP flows may use `datagrams` and drop upstream
+/// datagrams larger than `max_udp_payload_size`.
+pub(crate) async fn accept_streams(
+    conn: &Connection,
+    handlers: HashMap<TunnelId, StreamHandler>,
+    stream_limiter: &TrafficLimiter,
+    datagrams: Option<Arc<Datagrams>>,
+    max_udp_payload_size: usize,
+) {
+    let remote_addr = conn.remote_address();
+    let handlers = Arc::new(handlers);
+    loop {
+        match conn.accept_bi().await {
+            Err(quinn::ConnectionError::TimedOut) => {
+                info!("connection timeout: {remote_addr}");
+                break;
+            }
+            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                debug!("connection closed: {remote_addr}");
+                break;
+            }
+            Err(e) => {
+                error!("failed to accept_bi: {remote_addr}, err: {e}");
+                break;
+            }
+            Ok((mut quic_send, mut quic_recv)) => {
+                stream_limiter.acquire_stream().await;
+                let handlers = handlers.clone();
+                let datagrams = datagrams.clone();
+                tokio::spawn(async move {
+                    let tunnel_id =
+                        match tokio::time::timeout(TUNNEL_ID_TIMEOUT, quic_recv.read_u16()).await {
+                            Ok(Ok(tunnel_id)) => tunnel_id,
+                            _ => {
+                                warn!("failed to read tunnel id: {remote_addr}");
+                                return;
+                            }
+                        };
+                    let Some(handler) = handlers.get(&tunnel_id) else {
+                        warn!("stream of unknown tunnel {tunnel_id}: {remote_addr}");
+                        let error_code = VarInt::from_u32(UNKNOWN_TUNNEL_ERROR_CODE);
+                        quic_send.reset(error_code).ok();
+                        quic_recv.stop(error_code).ok();
+                        return;
+                    };
+
+                    match handler.upstream_type {
+                        UpstreamType::Tcp => {
+                            TcpTunnel::accept_stream(
+                                remote_addr,
+                                quic_send,
+                                quic_recv,
+                                Some(handler.upstream_addr.clone()),
+                                handler.upstream_acl.clone(),
+                                handler.limiter.clone(),
+                                handler.timeout_ms,
+                            )
+                            .await
+                        }
+                        UpstreamType::Udp => {
+                            UdpTunnel::process(
+                                quic_send,
+                                quic_recv,
+                                datagrams,
+                                Some(handler.upstream_addr.clone()),
+                                handler.upstream_acl.clone(),
+                                handler.limiter.clone(),
+                                handler.timeout_ms,
+                                max_udp_payload_size,
+                            )
+                            .await
+                            .ok();
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
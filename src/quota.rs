@@ -0,0 +1,302 @@
+//! Per-user traffic accounting for daily and monthly quotas.
+//!
+//! The server samples the QUIC-level byte counters of every authenticated
+//! connection and adds the deltas to the usage of its user. Usage is kept per
+//! calendar day and month (local time) and periodically written to a JSON state
+//! file, so totals survive restarts.
+
+use crate::tunnel_message::{quota_close_reason, CloseCode, LoginErrorCode, LoginFailure};
+use crate::TrafficQuota;
+use anyhow::{Context, Result};
+use chrono::Datelike;
+use log::{error, info, warn};
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(5);
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time left from `now` until the start of the next local day.
+fn until_next_day(now: chrono::DateTime<chrono::Local>) -> Duration {
+    until_local(now, now.date_naive().succ_opt())
+}
+
+/// Time left from `now` until the start of the next local month.
+fn until_next_month(now: chrono::DateTime<chrono::Local>) -> Duration {
+    let next_month = now
+        .date_naive()
+        .with_day(1)
+        .and_then(|first| first.checked_add_months(chrono::Months::new(1)));
+    until_local(now, next_month)
+}
+
+fn until_local(now: chrono::DateTime<chrono::Local>, date: Option<chrono::NaiveDate>) -> Duration {
+    date.and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|start| start.and_local_timezone(chrono::Local).earliest())
+        .and_then(|start| (start - now).to_std().ok())
+        .unwrap_or(Duration::from_secs(24 * 3600))
+}
+
+/// A traffic quota a user has exhausted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct QuotaExhausted {
+    /// Human-readable reason.
+    pub reason: String,
+    /// Time left until the quota is reset.
+    pub resets_in: Duration,
+}
+
+impl QuotaExhausted {
+    /// The failure of a login rejected because of the exhausted quota.
+    pub fn login_failure(&self) -> LoginFailure {
+        LoginFailure::new(LoginErrorCode::QuotaExceeded, &self.reason)
+            .with_retry_after(self.resets_in)
+    }
+}
+
+impl std::fmt::Display for QuotaExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+/// Traffic of a user in the current day and month.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct UserUsage {
+    day: String,
+    daily_bytes: u64,
+    month: String,
+    monthly_bytes: u64,
+}
+
+impl UserUsage {
+    /// Reset the counters of periods that have ended.
+    fn roll_over(&mut self) {
+        let now = chrono::Local::now();
+        let day = now.format("%Y-%m-%d").to_string();
+        if self.day != day {
+            self.day = day;
+            self.daily_bytes = 0;
+        }
+        let month = now.format("%Y-%m").to_string();
+        if self.month != month {
+            self.month = month;
+            self.monthly_bytes = 0;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct QuotaTracker {
+    state_path: String,
+    usage: Mutex<HashMap<String, UserUsage>>,
+    dirty: AtomicBool,
+}
+
+impl QuotaTracker {
+    /// Create a tracker persisting to `state_path`, empty to keep usage in memory only.
+    pub fn new(state_path: &str) -> Self {
+        QuotaTracker {
+            state_path: state_path.to_string(),
+            usage: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Load the usage saved by a previous run, a missing state file is not an error.
+    pub fn load(&self) -> Result<()> {
+        if self.state_path.is_empty() || !std::path::Path::new(&self.state_path).exists() {
+            return Ok(());
+        }
+
+        let content = std::fs::read_to_string(&self.state_path).context(format!(
+            "failed to read quota state file: {}",
+            self.state_path
+        ))?;
+        let usage: HashMap<String, UserUsage> = serde_json::from_str(&content)
+            .context(format!("invalid quota state file: {}", self.state_path))?;
+        info!(
+            "loaded traffic usage of {} users from {}",
+            usage.len(),
+            self.state_path
+        );
+        *self.usage.lock().unwrap() = usage;
+        Ok(())
+    }
+
+    /// Write the usage to the state file if it changed since the last save.
+    pub fn save(&self) -> Result<()> {
+        if self.state_path.is_empty() || !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let content = serde_json::to_string_pretty(&*self.usage.lock().unwrap())?;
+        // write to a temporary file first so a crash never leaves a truncated state file
+        let tmp_path = format!("{}.tmp", self.state_path);
+        std::fs::write(&tmp_path, content)
+            .and_then(|_| std::fs::rename(&tmp_path, &self.state_path))
+            .inspect_err(|_| self.dirty.store(true, Ordering::Relaxed))
+            .context(format!(
+                "failed to write quota state file: {}",
+                self.state_path
+            ))
+    }
+
+    /// Save the usage periodically in the background.
+    pub fn start_persisting(self: &Arc<Self>) {
+        if self.state_path.is_empty() {
+            return;
+        }
+
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = tracker.save() {
+                    error!("{e:?}");
+                }
+            }
+        });
+    }
+
+    /// Add `bytes` to the usage of `user`.
+    pub fn add_usage(&self, user: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(user.to_string()).or_default();
+        usage.roll_over();
+        usage.daily_bytes += bytes;
+        usage.monthly_bytes += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Fail if `user` has exhausted `quota`, telling how long it takes until
+    /// the exhausted quota is reset.
+    pub fn check(&self, user: &str, quota: &TrafficQuota) -> Result<(), QuotaExhausted> {
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(user.to_string()).or_default();
+        usage.roll_over();
+
+        // the monthly quota goes first, it takes longer to be reset
+        let now = chrono::Local::now();
+        if quota.monthly_bytes > 0 && usage.monthly_bytes >= quota.monthly_bytes {
+            return Err(QuotaExhausted {
+                reason: format!(
+                    "monthly traffic quota exhausted: {}/{} bytes",
+                    usage.monthly_bytes, quota.monthly_bytes
+                ),
+                resets_in: until_next_month(now),
+            });
+        }
+        if quota.daily_bytes > 0 && usage.daily_bytes >= quota.daily_bytes {
+            return Err(QuotaExhausted {
+                reason: format!(
+                    "daily traffic quota exhausted: {}/{} bytes",
+                    usage.daily_bytes, quota.daily_bytes
+                ),
+                resets_in: until_next_day(now),
+            });
+        }
+        Ok(())
+    }
+
+    /// Account the traffic of `conn` to `user` until the connection is closed,
+    /// closing it once `quota` is exhausted.
+    pub fn start_accounting(
+        self: &Arc<Self>,
+        conn: Connection,
+        user: String,
+        quota: Option<TrafficQuota>,
+    ) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut accounted_bytes = 0;
+            let mut interval = tokio::time::interval(ACCOUNTING_INTERVAL);
+            loop {
+                let closed = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = conn.closed() => true,
+                };
+
+                let stats = conn.stats();
+                let total_bytes = stats.udp_rx.bytes + stats.udp_tx.bytes;
+                tracker.add_usage(&user, total_bytes - accounted_bytes);
+                accounted_bytes = total_bytes;
+                if closed {
+                    break;
+                }
+
+                if let Some(Err(e)) = quota.as_ref().map(|quota| tracker.check(&user, quota)) {
+                    warn!(
+                        "closing connection of user {user}: {}, {e}",
+                        conn.remote_address()
+                    );
+                    conn.close(
+                        CloseCode::QuotaExceeded.to_varint(),
+                        &quota_close_reason(&e.reason, e.resets_in),
+                    );
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn time_until_period_ends() {
+        let now = chrono::Local
+            .with_ymd_and_hms(2026, 1, 31, 23, 0, 0)
+            .earliest()
+            .unwrap();
+        assert_eq!(until_next_day(now), Duration::from_secs(3600));
+        assert_eq!(until_next_month(now), Duration::from_secs(3600));
+
+        let now = chrono::Local
+            .with_ymd_and_hms(2026, 1, 30, 12, 0, 0)
+            .earliest()
+            .unwrap();
+        assert_eq!(until_next_day(now), Duration::from_secs(12 * 3600));
+        assert_eq!(until_next_month(now), Duration::from_secs(36 * 3600));
+    }
+
+    #[test]
+    fn check_reports_the_later_reset() {
+        let tracker = QuotaTracker::new("");
+        let quota = TrafficQuota {
+            daily_bytes: 100,
+            monthly_bytes: 1000,
+        };
+        assert!(tracker.check("alice", &quota).is_ok());
+
+        tracker.add_usage("alice", 100);
+        let daily = tracker.check("alice", &quota).unwrap_err();
+        assert_eq!(daily.reason, "daily traffic quota exhausted: 100/100 bytes");
+        assert!(daily.resets_in <= Duration::from_secs(24 * 3600));
+
+        tracker.add_usage("alice", 900);
+        let monthly = tracker.check("alice", &quota).unwrap_err();
+        assert_eq!(
+            monthly.reason,
+            "monthly traffic quota exhausted: 1000/1000 bytes"
+        );
+        assert!(monthly.resets_in >= daily.resets_in);
+
+        let failure = monthly.login_failure();
+        assert_eq!(failure.code, LoginErrorCode::QuotaExceeded);
+        assert_eq!(failure.message, monthly.reason);
+        assert_eq!(failure.retry_after_secs, Some(monthly.resets_in.as_secs()));
+    }
+}
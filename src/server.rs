@@ -4,14 +4,30 @@
 //! tunnel server. The server can bind to a specific address, authenticate
 //! clients, and serve TCP/UDP tunnels as negotiated by the client.
 
+use crate::admission::{AdmissionControl, SessionPermit};
+use crate::auth::{
+    self, AuthDecision, AuthRequest, Authenticator, Credentials, DefaultAuthenticator,
+};
+use crate::dashboard::Dashboard;
+use crate::login_guard::LoginGuard;
+use crate::metrics::{MetricsServer, METRICS};
+use crate::mux::{self, StreamHandler, TunnelId};
+use crate::quota::QuotaTracker;
+use crate::server_event::{ServerEvent, ServerEventBridge};
 use crate::tcp::tcp_tunnel::TcpTunnel;
 use crate::tcp::{StreamMessage, StreamSender};
-use crate::tunnel_message::TunnelMessage;
+use crate::tunnel_message::{
+    finish_stream, CloseCode, Features, Hello, LoginErrorCode, LoginFailure, Negotiated,
+    TunnelMessage, TunnelStatus,
+};
+use crate::udp::datagram::Datagrams;
 use crate::udp::udp_server::{UdpMessage, UdpSender};
 use crate::udp::{udp_server::UdpServer, udp_tunnel::UdpTunnel};
+use crate::util::rate_limiter::{LimitBuckets, TrafficLimiter};
 use crate::{
-    pem_util, ServerConfig, TcpServer, TcpTunnelInInfo, TcpTunnelOutInfo, Tunnel, TunnelConfig,
-    TunnelMode, TunnelType, UdpTunnelInInfo, UdpTunnelOutInfo, UpstreamType,
+    pem_util, AdmissionStats, DynamicUpstreamInfo, MultiplexedInfo, PortRange, ServerConfig,
+    TcpServer, TcpTunnelInInfo, TcpTunnelOutInfo, Tunnel, TunnelConfig, TunnelMode, TunnelType,
+    UdpTunnelInInfo, UdpTunnelOutInfo, UpstreamAcl, UpstreamAddr, UpstreamType,
     SUPPORTED_CIPHER_SUITES,
 };
 use anyhow::{Context, Result};
@@ -19,52 +35,168 @@ use log::{debug, error, info, warn};
 use quinn::crypto::rustls::QuicServerConfig;
 use quinn::IdleTimeout;
 use quinn::VarInt;
-use quinn::{congestion, Connection, Endpoint, SendStream, TransportConfig};
+use quinn::{congestion, Connection, Endpoint, TransportConfig};
 use rs_utilities::log_and_bail;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, Once};
+use std::time::Instant;
 use tokio::net::TcpStream;
 use tokio::time::Duration;
 
+/// How often the cert and key files are checked for modifications.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Certificates expiring sooner than this are logged as warnings.
+const CERT_EXPIRY_WARNING: Duration = Duration::from_secs(14 * 86400);
+
+#[derive(Debug, Clone)]
+struct ConnectedSession {
+    id: u64,
+    conn: Connection,
+    user: String,
+    tunnel_type: &'static str,
+    upstream: Option<UpstreamAddr>,
+    start_time: Instant,
+}
+
 #[derive(Debug, Clone)]
 struct ConnectedTcpInSession {
+    session_id: u64,
     conn: Connection,
+    addr: SocketAddr,
     sender: StreamSender<TcpStream>,
 }
 
 #[derive(Debug, Clone)]
 struct ConnectedUdpInSession {
+    session_id: u64,
     conn: Connection,
+    addr: SocketAddr,
     sender: UdpSender,
 }
 
-#[derive(Debug)]
+/// Snapshot of an authenticated session, see [`Server::sessions`].
+#[derive(Serialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    /// Authenticated user, empty for shared-password logins.
+    pub user: String,
+    /// TCP_OUT, TCP_IN, UDP_OUT, UDP_IN, TCP_DYNAMIC or UDP_DYNAMIC.
+    pub tunnel_type: String,
+    /// Upstream of OUT tunnels or listen address of IN tunnels.
+    pub upstream: Option<UpstreamAddr>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rtt_ms: u64,
+    pub uptime_secs: u64,
+}
+
+/// A listener opened on the server for an IN tunnel, see [`Server::listeners`].
+#[derive(Serialize, Debug, Clone)]
+pub struct ListenerInfo {
+    pub session_id: u64,
+    /// TCP or UDP.
+    pub protocol: String,
+    pub addr: SocketAddr,
+    /// Address of the client the listener forwards to.
+    pub remote_addr: SocketAddr,
+}
+
+/// Server-wide counters, see [`Server::totals`].
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerTotals {
+    pub uptime_secs: u64,
+    pub active_sessions: usize,
+    /// Sessions authenticated since the server started.
+    pub total_sessions: u64,
+    pub listeners: usize,
+    /// Bytes received over QUIC by all sessions since the server started.
+    pub rx_bytes: u64,
+    /// Bytes sent over QUIC by all sessions since the server started.
+    pub tx_bytes: u64,
+    pub admission: AdmissionStats,
+}
+
+/// Outcome of a config reload, see [`Server::reload`].
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ReloadReport {
+    /// Changed settings that were not applied because they only take effect
+    /// after a restart.
+    pub restart_required: Vec<String>,
+}
+
+type ConfigLoader = dyn Fn() -> Result<ServerConfig> + Send + Sync;
+
 struct State {
     config: ServerConfig,
+    config_loader: Option<Arc<ConfigLoader>>,
     endpoint: Option<Endpoint>,
+    sessions: Vec<ConnectedSession>,
     tcp_sessions: Vec<ConnectedTcpInSession>,
     udp_sessions: Vec<ConnectedUdpInSession>,
+    next_session_id: u64,
+    closed_rx_bytes: u64,
+    closed_tx_bytes: u64,
+    start_time: Instant,
+    global_buckets: LimitBuckets,
+    user_buckets: HashMap<String, LimitBuckets>,
+    revoked_users: HashSet<String>,
+    shutting_down: bool,
+    events: ServerEventBridge,
+    quota_tracker: Arc<QuotaTracker>,
+    admission: Arc<AdmissionControl>,
+    login_guard: Arc<LoginGuard>,
+    authenticator: Arc<dyn Authenticator>,
+    custom_authenticator: bool,
 }
 
 impl State {
-    pub fn new(config: ServerConfig) -> Self {
+    pub fn new(mut config: ServerConfig) -> Self {
+        config.normalize();
         State {
+            global_buckets: LimitBuckets::for_server(&config.rate_limit),
+            quota_tracker: Arc::new(QuotaTracker::new(&config.quota_state_path)),
+            admission: Arc::new(AdmissionControl::new(&config)),
+            login_guard: Arc::new(LoginGuard::new(&config)),
+            authenticator: Arc::new(DefaultAuthenticator::new(&config)),
+            custom_authenticator: false,
             config,
+            config_loader: None,
             endpoint: None,
+            sessions: Vec::new(),
             tcp_sessions: Vec::new(),
             udp_sessions: Vec::new(),
+            next_session_id: 0,
+            closed_rx_bytes: 0,
+            closed_tx_bytes: 0,
+            start_time: Instant::now(),
+            user_buckets: HashMap::new(),
+            revoked_users: HashSet::new(),
+            shutting_down: false,
+            events: ServerEventBridge::default(),
         }
     }
 }
 
-#[derive(Debug)]
 /// QUIC-based tunnel server. Binds to an address, authenticates clients, and
 /// serves TCP/UDP tunnels as negotiated.
 pub struct Server {
     inner_state: Arc<Mutex<State>>,
 }
 
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server").finish_non_exhaustive()
+    }
+}
+
 macro_rules! inner_state {
     ($self:ident, $field:ident) => {
         (*$self.inner_state.lock().unwrap()).$field
@@ -79,6 +211,23 @@ impl Server {
         }
     }
 
+    /// Replace the authenticator deciding logins, [`DefaultAuthenticator`] by default.
+    pub fn set_authenticator(&mut self, authenticator: impl Authenticator + 'static) {
+        let mut state = self.inner_state.lock().unwrap();
+        state.authenticator = Arc::new(authenticator);
+        state.custom_authenticator = true;
+    }
+
+    /// Install the function [`Server::reload`] gets the new config from,
+    /// typically re-reading the config file. Without a loader, reloading only
+    /// re-reads the certificates.
+    pub fn set_config_loader(
+        &self,
+        loader: impl Fn() -> Result<ServerConfig> + Send + Sync + 'static,
+    ) {
+        inner_state!(self, config_loader) = Some(Arc::new(loader));
+    }
+
     /// Bind the server endpoint and return the actual bound address.
     pub fn bind(&mut self) -> Result<SocketAddr> {
         let mut state = self.inner_state.lock().unwrap();
@@ -89,6 +238,7 @@ impl Server {
             .context(format!("invalid address: {}", config.addr))?;
 
         let quinn_server_cfg = Self::load_quinn_server_config(&config)?;
+        state.quota_tracker.load()?;
         let endpoint = quinn::Endpoint::server(quinn_server_cfg, addr).inspect_err(|e| {
             error!("failed to bind tunnel server on address: {addr}, err: {e}");
         })?;
@@ -99,43 +249,95 @@ impl Server {
             config.quic_timeout_ms
         );
 
-        let ep = endpoint.clone();
+        state.endpoint = Some(endpoint);
+        Self::watch_certificates(self.inner_state.clone());
+        Ok(addr)
+    }
+
+    /// Reload the certificates whenever the cert or key file is modified, the
+    /// old ones are kept if the new pair cannot be loaded.
+    fn watch_certificates(state: Arc<Mutex<State>>) {
         tokio::spawn(async move {
+            let mut last_seen = None;
+            let mut interval = tokio::time::interval(CERT_CHECK_INTERVAL);
             loop {
-                tokio::time::sleep(Duration::from_secs(3600 * 24)).await;
-                match Self::load_quinn_server_config(&config) {
-                    Ok(quinn_server_cfg) => {
-                        info!("updated quinn server config!");
-                        ep.set_server_config(Some(quinn_server_cfg));
+                interval.tick().await;
+                let config = state.lock().unwrap().config.clone();
+                let (cert_path, key_path) = (config.cert_path.clone(), config.key_path.clone());
+                if cert_path.is_empty() {
+                    continue;
+                }
+
+                let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified());
+                let (Ok(cert_modified), Ok(key_modified)) =
+                    (modified(&cert_path), modified(&key_path))
+                else {
+                    // the files may be missing while they are being replaced
+                    continue;
+                };
+                let seen = Some((cert_path.clone(), cert_modified, key_path, key_modified));
+                if last_seen.is_none() {
+                    // loaded by bind()
+                    last_seen = seen;
+                    continue;
+                }
+                if seen == last_seen {
+                    continue;
+                }
+                last_seen = seen;
+
+                // reading the files and building the TLS config blocks, so it
+                // runs on the blocking pool without holding the state lock
+                let loaded =
+                    tokio::task::spawn_blocking(move || Self::load_quinn_server_config(&config))
+                        .await;
+                match loaded {
+                    Ok(Ok(quinn_server_cfg)) => {
+                        let endpoint = state.lock().unwrap().endpoint.clone();
+                        if let Some(endpoint) = endpoint {
+                            info!("reloaded certificate: {cert_path}");
+                            endpoint.set_server_config(Some(quinn_server_cfg));
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        error!("failed to reload certificate, keeping the old one: {e:#}");
                     }
                     Err(e) => {
-                        error!("failed to load quinn server config:{e}");
+                        error!("failed to reload certificate, keeping the old one: {e}");
                     }
                 }
             }
         });
-
-        state.endpoint = Some(endpoint);
-        Ok(addr)
     }
 
     fn load_quinn_server_config(config: &ServerConfig) -> Result<quinn::ServerConfig> {
         let (certs, key) =
             Self::read_certs_and_key(config.cert_path.as_str(), config.key_path.as_str())
                 .context("failed to read certificate or key")?;
+        if !config.cert_path.is_empty() {
+            Self::log_cert_expiry(&config.cert_path, &certs);
+        }
 
         let default_provider = rustls::crypto::ring::default_provider();
-        let provider = rustls::crypto::CryptoProvider {
+        let provider = Arc::new(rustls::crypto::CryptoProvider {
             cipher_suites: SUPPORTED_CIPHER_SUITES.into(),
             ..default_provider
-        };
+        });
 
-        let tls_server_cfg = rustls::ServerConfig::builder_with_provider(provider.into())
+        let tls_server_cfg_builder = rustls::ServerConfig::builder_with_provider(provider.clone())
             .with_protocol_versions(&[&rustls::version::TLS13])
-            .unwrap()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
             .unwrap();
+        let tls_server_cfg = if config.client_ca_path.is_empty() {
+            tls_server_cfg_builder.with_no_client_auth()
+        } else {
+            let verifier = Self::load_client_cert_verifier(&config.client_ca_path, provider)?;
+            tls_server_cfg_builder.with_client_cert_verifier(verifier)
+        }
+        .with_single_cert(certs, key)
+        .context(format!(
+            "certificate {} does not match key {}",
+            config.cert_path, config.key_path
+        ))?;
 
         let mut transport_cfg = TransportConfig::default();
         transport_cfg.stream_receive_window(VarInt::from_u32(1024 * 1024));
@@ -148,7 +350,11 @@ impl Server {
             transport_cfg
                 .keep_alive_interval(Some(Duration::from_millis(config.quic_timeout_ms * 2 / 3)));
         }
-        transport_cfg.max_concurrent_bidi_streams(VarInt::from_u32(1024));
+        let max_streams = match config.max_streams_per_conn {
+            0 => 1024,
+            max_streams => max_streams,
+        };
+        transport_cfg.max_concurrent_bidi_streams(VarInt::from_u32(max_streams));
 
         let quic_server_cfg = Arc::new(QuicServerConfig::try_from(tls_server_cfg)?);
         let mut quinn_server_cfg = quinn::ServerConfig::with_crypto(quic_server_cfg);
@@ -156,8 +362,340 @@ impl Server {
         Ok(quinn_server_cfg)
     }
 
+    /// Log when the end-entity certificate in `certs` expires, as a warning
+    /// if it has expired or expires soon.
+    fn log_cert_expiry(cert_path: &str, certs: &[CertificateDer]) {
+        let Some(cert) = certs.first() else {
+            return;
+        };
+        let not_after = match x509_parser::parse_x509_certificate(cert) {
+            Ok((_, cert)) => cert.validity().not_after,
+            Err(e) => {
+                warn!("failed to parse certificate {cert_path}: {e}");
+                return;
+            }
+        };
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let secs_left = not_after.timestamp() - now;
+        if secs_left <= 0 {
+            warn!("certificate {cert_path} expired on {not_after}");
+        } else if secs_left < CERT_EXPIRY_WARNING.as_secs() as i64 {
+            warn!(
+                "certificate {cert_path} expires on {not_after}, in {} days",
+                secs_left / 86400
+            );
+        } else {
+            info!("certificate {cert_path} expires on {not_after}");
+        }
+    }
+
+    fn load_client_cert_verifier(
+        client_ca_path: &str,
+        provider: Arc<rustls::crypto::CryptoProvider>,
+    ) -> Result<Arc<dyn ClientCertVerifier>> {
+        let certs = pem_util::load_certificates_from_pem(client_ca_path)
+            .context(format!("failed to read client CA file: {client_ca_path}"))?;
+        let mut roots = RootCertStore::empty();
+        for cert in certs {
+            roots.add(cert).context(format!(
+                "failed to add client CA certificate from file: {client_ca_path}"
+            ))?;
+        }
+
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .context(format!("invalid client CA file: {client_ca_path}"))
+    }
+
+    /// Install a listener called with every [`ServerEvent`], replacing the
+    /// previous one. The listener is called on the tokio worker threads and
+    /// must not block.
+    pub fn set_on_event_listener(&self, listener: impl FnMut(&ServerEvent) + 'static + Send) {
+        inner_state!(self, events).set_listener(listener);
+    }
+
+    /// Counters of the session admission control.
+    pub fn admission_stats(&self) -> AdmissionStats {
+        inner_state!(self, admission).stats()
+    }
+
+    /// Snapshots of the authenticated sessions.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let state = self.inner_state.lock().unwrap();
+        state
+            .sessions
+            .iter()
+            .map(|sess| {
+                let stats = sess.conn.stats();
+                SessionInfo {
+                    id: sess.id,
+                    remote_addr: sess.conn.remote_address(),
+                    user: sess.user.clone(),
+                    tunnel_type: sess.tunnel_type.to_string(),
+                    upstream: sess.upstream.clone(),
+                    rx_bytes: stats.udp_rx.bytes,
+                    tx_bytes: stats.udp_tx.bytes,
+                    rtt_ms: sess.conn.rtt().as_millis() as u64,
+                    uptime_secs: sess.start_time.elapsed().as_secs(),
+                }
+            })
+            .collect()
+    }
+
+    /// Listeners opened for IN tunnels.
+    pub fn listeners(&self) -> Vec<ListenerInfo> {
+        let state = self.inner_state.lock().unwrap();
+        let tcp_listeners = state.tcp_sessions.iter().map(|sess| ListenerInfo {
+            session_id: sess.session_id,
+            protocol: "TCP".to_string(),
+            addr: sess.addr,
+            remote_addr: sess.conn.remote_address(),
+        });
+        let udp_listeners = state.udp_sessions.iter().map(|sess| ListenerInfo {
+            session_id: sess.session_id,
+            protocol: "UDP".to_string(),
+            addr: sess.addr,
+            remote_addr: sess.conn.remote_address(),
+        });
+        tcp_listeners.chain(udp_listeners).collect()
+    }
+
+    /// Server-wide counters.
+    pub fn totals(&self) -> ServerTotals {
+        let state = self.inner_state.lock().unwrap();
+        let (rx_bytes, tx_bytes) = state.sessions.iter().fold(
+            (state.closed_rx_bytes, state.closed_tx_bytes),
+            |(rx_bytes, tx_bytes), sess| {
+                let stats = sess.conn.stats();
+                (rx_bytes + stats.udp_rx.bytes, tx_bytes + stats.udp_tx.bytes)
+            },
+        );
+        ServerTotals {
+            uptime_secs: state.start_time.elapsed().as_secs(),
+            active_sessions: state.sessions.len(),
+            total_sessions: state.next_session_id,
+            listeners: state.tcp_sessions.len() + state.udp_sessions.len(),
+            rx_bytes,
+            tx_bytes,
+            admission: state.admission.stats(),
+        }
+    }
+
+    /// Close the session `id` with `reason`, returns false if there is no such session.
+    ///
+    /// Listeners of IN tunnels opened by the session are shut down as well.
+    pub fn close_session(&self, id: u64, reason: &str) -> bool {
+        let Some(conn) = self
+            .inner_state
+            .lock()
+            .unwrap()
+            .sessions
+            .iter()
+            .find(|sess| sess.id == id)
+            .map(|sess| sess.conn.clone())
+        else {
+            return false;
+        };
+
+        warn!(
+            "[audit] closing session {id}: {}, reason: {reason}",
+            conn.remote_address()
+        );
+        conn.close(CloseCode::AdminClosed.to_varint(), reason.as_bytes());
+        Self::clear_expired_sessions(self.inner_state.clone());
+        true
+    }
+
+    /// Close all sessions of `user` with `reason` and refuse its logins until
+    /// [`Server::restore_user`] is called, returns the number of closed sessions.
+    /// Returns None and revokes nothing if `user` has no sessions and is not in
+    /// the config.
+    pub fn revoke_user(&self, user: &str, reason: &str) -> Option<usize> {
+        let conns: Vec<Connection> = {
+            let mut state = self.inner_state.lock().unwrap();
+            let conns: Vec<Connection> = state
+                .sessions
+                .iter()
+                .filter(|sess| sess.user == user)
+                .map(|sess| sess.conn.clone())
+                .collect();
+            if conns.is_empty() && state.config.find_user(user).is_none() {
+                return None;
+            }
+            state.revoked_users.insert(user.to_string());
+            conns
+        };
+
+        warn!(
+            "[audit] revoked user {user}, closing {} sessions, reason: {reason}",
+            conns.len()
+        );
+        for conn in &conns {
+            conn.close(CloseCode::UserRevoked.to_varint(), reason.as_bytes());
+        }
+        Self::clear_expired_sessions(self.inner_state.clone());
+        Some(conns.len())
+    }
+
+    /// Allow a user revoked with [`Server::revoke_user`] to log in again,
+    /// returns false if the user was not revoked.
+    pub fn restore_user(&self, user: &str) -> bool {
+        let restored = inner_state!(self, revoked_users).remove(user);
+        if restored {
+            warn!("[audit] restored user {user}");
+        }
+        restored
+    }
+
+    /// Reject new logins with [`LoginErrorCode::ShuttingDown`], so that
+    /// clients back off instead of failing, existing sessions are kept.
+    /// Called before the process exits, saves the quota usage so far.
+    pub fn shutdown(&self) {
+        warn!("shutting down, new logins are rejected");
+        inner_state!(self, shutting_down) = true;
+        self.save_quota_usage();
+    }
+
+    /// Write the traffic usage of the users to the quota state file, if it
+    /// changed since it was last saved. Call it right before the process exits
+    /// so that no usage counted since the last periodic save is lost.
+    pub fn save_quota_usage(&self) {
+        let quota_tracker = inner_state!(self, quota_tracker).clone();
+        if let Err(e) = quota_tracker.save() {
+            error!("{e:?}");
+        }
+    }
+
+    /// Get a new config from the loader installed with
+    /// [`Server::set_config_loader`] and apply it, see [`Server::reload_config`].
+    pub fn reload(&self) -> Result<ReloadReport> {
+        let loader = inner_state!(self, config_loader).clone();
+        let config = match loader {
+            Some(loader) => loader()?,
+            None => inner_state!(self, config).clone(),
+        };
+        self.reload_config(config)
+    }
+
+    /// Apply `config` to new logins and re-read the certificates, existing
+    /// sessions are kept as they are.
+    ///
+    /// Users, ACLs, limits, timeouts and default upstreams take effect right
+    /// away. The bind address, the dashboard and metrics addresses, the
+    /// dashboard credential and the quota state path keep their old values and
+    /// are listed in the returned report if they changed. Nothing is applied
+    /// if the certificates cannot be loaded.
+    pub fn reload_config(&self, mut config: ServerConfig) -> Result<ReloadReport> {
+        config.normalize();
+        let quinn_server_cfg = Self::load_quinn_server_config(&config)
+            .inspect_err(|e| error!("failed to reload config: {e}"))?;
+
+        let mut guard = self.inner_state.lock().unwrap();
+        let state = &mut *guard;
+        let old = &state.config;
+        let mut report = ReloadReport::default();
+        macro_rules! keep_restart_required {
+            ($($field:ident),*) => {
+                $(
+                    if config.$field != old.$field {
+                        report.restart_required.push(stringify!($field).to_string());
+                        config.$field = old.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep_restart_required!(
+            addr,
+            dashboard_server,
+            dashboard_server_credential,
+            metrics_server,
+            quota_state_path
+        );
+
+        if config.rate_limit != old.rate_limit {
+            state.global_buckets = LimitBuckets::for_server(&config.rate_limit);
+        }
+        // users whose limit changed get new buckets, the old ones stay with
+        // their current tunnels
+        let user_rate_limit =
+            |config: &ServerConfig, user: &str| config.find_user(user).and_then(|u| u.rate_limit);
+        state.user_buckets.retain(|user, _| {
+            user_rate_limit(&state.config, user) == user_rate_limit(&config, user)
+        });
+        state.config = config;
+
+        state.admission.update_limits(&state.config);
+        state.login_guard.update_limits(&state.config);
+        if !state.custom_authenticator {
+            state.authenticator = Arc::new(DefaultAuthenticator::new(&state.config));
+        }
+        if let Some(endpoint) = state.endpoint.as_ref() {
+            endpoint.set_server_config(Some(quinn_server_cfg));
+        }
+
+        info!("reloaded config, {} users", state.config.users.len());
+        if !report.restart_required.is_empty() {
+            warn!(
+                "changes to {} only take effect after a restart",
+                report.restart_required.join(", ")
+            );
+        }
+        Ok(report)
+    }
+
+    /// Reload the config whenever the process receives SIGHUP, see
+    /// [`Server::reload`]. Must be called from within a tokio runtime.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&self) -> Result<()> {
+        let mut signals = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .context("failed to listen for SIGHUP")?;
+        let server = Server {
+            inner_state: self.inner_state.clone(),
+        };
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                info!("received SIGHUP, reloading config");
+                server.reload().ok();
+            }
+        });
+        Ok(())
+    }
+
     /// Start accepting client connections and serving tunnels.
     pub async fn serve(&self) -> Result<()> {
+        let config = inner_state!(self, config).clone();
+        if !config.dashboard_server.is_empty() {
+            let server = Server {
+                inner_state: self.inner_state.clone(),
+            };
+            Dashboard::start(
+                server,
+                &config.dashboard_server,
+                &config.dashboard_server_credential,
+            )
+            .await?;
+        }
+
+        if !config.metrics_server.is_empty() {
+            let state = self.inner_state.clone();
+            MetricsServer::start(
+                &config.metrics_server,
+                Arc::new(move || {
+                    state
+                        .lock()
+                        .unwrap()
+                        .sessions
+                        .iter()
+                        .map(|sess| (sess.id.to_string(), sess.conn.clone()))
+                        .collect()
+                }),
+            )
+            .await?;
+        }
+
         let state = self.inner_state.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(2));
@@ -166,20 +704,85 @@ impl Server {
                 Self::clear_expired_sessions(state.clone());
             }
         });
+        inner_state!(self, quota_tracker).start_persisting();
 
-        let endpoint = inner_state!(self, endpoint).take().context("failed")?;
+        let endpoint = inner_state!(self, endpoint).clone().context("failed")?;
         while let Some(client_conn) = endpoint.accept().await {
+            let remote_addr = client_conn.remote_address();
+            if inner_state!(self, login_guard).is_banned(remote_addr.ip()) {
+                debug!("refused connection from banned address: {remote_addr}");
+                client_conn.refuse();
+                continue;
+            }
+            let Some(mut permit) = inner_state!(self, admission).admit(remote_addr.ip()) else {
+                debug!("refused connection, session limit reached: {remote_addr}");
+                client_conn.refuse();
+                continue;
+            };
+
             let state = self.inner_state.clone();
             let config = inner_state!(self, config).clone();
+            let quota_tracker = inner_state!(self, quota_tracker).clone();
+            let login_guard = inner_state!(self, login_guard).clone();
+            let authenticator = inner_state!(self, authenticator).clone();
+            let events = inner_state!(self, events).clone();
+            let upstream_acl = if config.upstream_acl.is_empty() {
+                None
+            } else {
+                Some(Arc::new(config.upstream_acl.clone()))
+            };
             tokio::spawn(async move {
                 let client_conn = client_conn.await?;
-                let tun_type = Self::authenticate_connection(&config, client_conn).await?;
+                let (tun_type, features) = Self::authenticate_connection(
+                    &state,
+                    &config,
+                    authenticator.as_ref(),
+                    &quota_tracker,
+                    &login_guard,
+                    &mut permit,
+                    client_conn,
+                )
+                .await?;
+                permit.release_on_close(tun_type.conn().clone());
+                let session_id = Self::register_session(&state, &tun_type);
+                let conn = tun_type.conn().clone();
+                let datagrams = features
+                    .contains(Features::DATAGRAM)
+                    .then(|| Datagrams::start(&conn));
+                let user = tun_type.user().to_string();
+                let start_time = Instant::now();
+                events.post(ServerEvent::SessionAuthenticated {
+                    session_id,
+                    remote_addr: conn.remote_address(),
+                    user: user.clone(),
+                    tunnel_type: tun_type.name().to_string(),
+                });
+                let limiter = Self::create_traffic_limiter(&state, tun_type.user())
+                    .with_stream_rate(config.max_new_streams_per_sec);
+                if !tun_type.user().is_empty() {
+                    let quota = config.find_user(tun_type.user()).and_then(|u| u.quota);
+                    quota_tracker.start_accounting(
+                        tun_type.conn().clone(),
+                        tun_type.user().to_string(),
+                        quota,
+                    );
+                }
 
+                // multiplexed tunnels are reported one by one in serve_multiplexed
+                if !matches!(tun_type, TunnelType::Multiplexed(_)) {
+                    events.post(ServerEvent::TunnelStarted {
+                        session_id,
+                        tunnel_type: tun_type.name().to_string(),
+                        upstream: tun_type.upstream_addr(),
+                    });
+                }
                 match tun_type {
                     TunnelType::TcpOut(info) => {
                         TcpTunnel::start_accepting(
                             &info.conn,
                             Some(info.upstream_addr),
+                            upstream_acl,
+                            limiter,
                             config.tcp_timeout_ms,
                         )
                         .await;
@@ -188,67 +791,85 @@ impl Server {
                     TunnelType::UdpOut(info) => {
                         UdpTunnel::start_accepting(
                             &info.conn,
+                            datagrams,
                             Some(info.upstream_addr),
+                            upstream_acl,
+                            limiter,
                             config.udp_timeout_ms,
+                            config.max_udp_payload_size,
                         )
                         .await
                     }
 
-                    TunnelType::TcpIn(mut info) => {
-                        state
-                            .lock()
-                            .unwrap()
-                            .tcp_sessions
-                            .push(ConnectedTcpInSession {
-                                conn: info.conn.clone(),
-                                sender: info.tcp_server.clone_sender(),
-                            });
-
-                        let mut tcp_receiver = info.tcp_server.take_receiver();
-
-                        TcpTunnel::start_serving(
-                            false,
-                            &info.conn,
-                            &mut tcp_receiver,
-                            &mut None,
+                    TunnelType::TcpIn(info) => {
+                        Self::serve_tcp_in(
+                            state.clone(),
+                            session_id,
+                            info,
+                            None,
+                            limiter,
                             config.tcp_timeout_ms,
                         )
                         .await;
-
-                        info.tcp_server.shutdown().await.ok();
                     }
 
-                    TunnelType::UdpIn(mut info) => {
-                        state
-                            .lock()
-                            .unwrap()
-                            .udp_sessions
-                            .push(ConnectedUdpInSession {
-                                conn: info.conn.clone(),
-                                sender: info.udp_server.clone_sender(),
-                            });
-
-                        let mut udp_receiver = info.udp_server.take_receiver();
-                        let udp_sender = info.udp_server.clone_sender();
-
-                        UdpTunnel::start_serving(
-                            &info.conn,
-                            &udp_sender,
-                            &mut udp_receiver,
+                    TunnelType::UdpIn(info) => {
+                        Self::serve_udp_in(
+                            state.clone(),
+                            session_id,
+                            info,
+                            None,
+                            datagrams,
+                            limiter,
                             config.udp_timeout_ms,
                         )
                         .await;
-
-                        info.udp_server.shutdown().await.ok();
                     }
-                    TunnelType::DynamicUpstreamTcpOut(conn) => {
-                        TcpTunnel::start_accepting(&conn, None, config.tcp_timeout_ms).await;
+                    TunnelType::DynamicUpstreamTcpOut(info) => {
+                        TcpTunnel::start_accepting(
+                            &info.conn,
+                            None,
+                            upstream_acl,
+                            limiter,
+                            config.tcp_timeout_ms,
+                        )
+                        .await;
+                    }
+                    TunnelType::DynamicUpstreamUdpOut(info) => {
+                        UdpTunnel::start_accepting(
+                            &info.conn,
+                            datagrams,
+                            None,
+                            upstream_acl,
+                            limiter,
+                            config.udp_timeout_ms,
+                            config.max_udp_payload_size,
+                        )
+                        .await
                     }
-                    TunnelType::DynamicUpstreamUdpOut(conn) => {
-                        UdpTunnel::start_accepting(&conn, None, config.udp_timeout_ms).await
+                    TunnelType::Multiplexed(info) => {
+                        Self::serve_multiplexed(
+                            &state,
+                            &config,
+                            session_id,
+                            info,
+                            upstream_acl,
+                            datagrams,
+                        )
+                        .await;
                     }
                 }
 
+                let stats = conn.stats();
+                events.post(ServerEvent::TunnelEnded {
+                    session_id,
+                    user,
+                    rx_bytes: stats.udp_rx.bytes,
+                    tx_bytes: stats.udp_tx.bytes,
+                    duration_secs: start_time.elapsed().as_secs(),
+                    reason: conn.close_reason().map(|e| e.to_string()),
+                });
+
                 Ok::<(), anyhow::Error>(())
             });
         }
@@ -257,10 +878,235 @@ impl Server {
         Ok(())
     }
 
+    /// Serve the tunnels of a multiplexed session until its connection is
+    /// closed, each tunnel is throttled by its own limiter. New streams are
+    /// limited per connection, so the tunnels share one stream bucket.
+    async fn serve_multiplexed(
+        state: &Arc<Mutex<State>>,
+        config: &ServerConfig,
+        session_id: u64,
+        info: MultiplexedInfo,
+        upstream_acl: Option<Arc<UpstreamAcl>>,
+        datagrams: Option<Arc<Datagrams>>,
+    ) {
+        let events = state.lock().unwrap().events.clone();
+        let stream_limiter =
+            TrafficLimiter::default().with_stream_rate(config.max_new_streams_per_sec);
+        let mut handlers = HashMap::new();
+        let mut in_tasks = Vec::new();
+        for (tunnel_id, tun_type) in info.tunnels {
+            events.post(ServerEvent::TunnelStarted {
+                session_id,
+                tunnel_type: tun_type.name().to_string(),
+                upstream: tun_type.upstream_addr(),
+            });
+            let limiter =
+                Self::create_traffic_limiter(state, &info.user).with_streams_of(&stream_limiter);
+            match tun_type {
+                TunnelType::TcpOut(info) => {
+                    handlers.insert(
+                        tunnel_id,
+                        StreamHandler {
+                            upstream_type: UpstreamType::Tcp,
+                            upstream_addr: info.upstream_addr,
+                            upstream_acl: upstream_acl.clone(),
+                            limiter,
+                            timeout_ms: config.tcp_timeout_ms,
+                        },
+                    );
+                }
+                TunnelType::UdpOut(info) => {
+                    handlers.insert(
+                        tunnel_id,
+                        StreamHandler {
+                            upstream_type: UpstreamType::Udp,
+                            upstream_addr: info.upstream_addr,
+                            upstream_acl: upstream_acl.clone(),
+                            limiter,
+                            timeout_ms: config.udp_timeout_ms,
+                        },
+                    );
+                }
+                TunnelType::TcpIn(info) => {
+                    in_tasks.push(tokio::spawn(Self::serve_tcp_in(
+                        state.clone(),
+                        session_id,
+                        info,
+                        Some(tunnel_id),
+                        limiter,
+                        config.tcp_timeout_ms,
+                    )));
+                }
+                TunnelType::UdpIn(info) => {
+                    in_tasks.push(tokio::spawn(Self::serve_udp_in(
+                        state.clone(),
+                        session_id,
+                        info,
+                        Some(tunnel_id),
+                        datagrams.clone(),
+                        limiter,
+                        config.udp_timeout_ms,
+                    )));
+                }
+                tun_type => {
+                    error!("unexpected multiplexed tunnel: {}", tun_type.name());
+                }
+            }
+        }
+
+        mux::accept_streams(
+            &info.conn,
+            handlers,
+            &stream_limiter,
+            datagrams,
+            config.max_udp_payload_size,
+        )
+        .await;
+        for task in in_tasks {
+            task.await.ok();
+        }
+    }
+
+    /// Forward the connections accepted by the listener of a TCP IN tunnel
+    /// to the client, the listener is shut down once the client is gone.
+    async fn serve_tcp_in(
+        state: Arc<Mutex<State>>,
+        session_id: u64,
+        mut info: TcpTunnelInInfo,
+        tunnel_id: Option<TunnelId>,
+        limiter: TrafficLimiter,
+        tcp_timeout_ms: u64,
+    ) {
+        let events = {
+            let mut state = state.lock().unwrap();
+            state.tcp_sessions.push(ConnectedTcpInSession {
+                session_id,
+                conn: info.conn.clone(),
+                addr: info.tcp_server.addr(),
+                sender: info.tcp_server.clone_sender(),
+            });
+            state.events.clone()
+        };
+        events.post(ServerEvent::ListenerBound {
+            session_id,
+            protocol: "TCP".to_string(),
+            addr: info.tcp_server.addr(),
+        });
+
+        let mut tcp_receiver = info.tcp_server.take_receiver();
+
+        TcpTunnel::start_serving(
+            false,
+            &info.conn,
+            tunnel_id,
+            &mut tcp_receiver,
+            &mut None,
+            limiter,
+            tcp_timeout_ms,
+        )
+        .await;
+
+        info.tcp_server.shutdown().await.ok();
+        events.post(ServerEvent::ListenerReleased {
+            session_id,
+            protocol: "TCP".to_string(),
+            addr: info.tcp_server.addr(),
+        });
+    }
+
+    /// Forward the datagrams received by the socket of a UDP IN tunnel to
+    /// the client, the socket is shut down once the client is gone.
+    async fn serve_udp_in(
+        state: Arc<Mutex<State>>,
+        session_id: u64,
+        mut info: UdpTunnelInInfo,
+        tunnel_id: Option<TunnelId>,
+        datagrams: Option<Arc<Datagrams>>,
+        limiter: TrafficLimiter,
+        udp_timeout_ms: u64,
+    ) {
+        let events = {
+            let mut state = state.lock().unwrap();
+            state.udp_sessions.push(ConnectedUdpInSession {
+                session_id,
+                conn: info.conn.clone(),
+                addr: info.udp_server.addr(),
+                sender: info.udp_server.clone_sender(),
+            });
+            state.events.clone()
+        };
+        events.post(ServerEvent::ListenerBound {
+            session_id,
+            protocol: "UDP".to_string(),
+            addr: info.udp_server.addr(),
+        });
+
+        let mut udp_receiver = info.udp_server.take_receiver();
+        let udp_sender = info.udp_server.clone_sender();
+
+        UdpTunnel::start_serving(
+            &info.conn,
+            tunnel_id,
+            datagrams,
+            &udp_sender,
+            &mut udp_receiver,
+            limiter,
+            udp_timeout_ms,
+        )
+        .await;
+
+        info.udp_server.shutdown().await.ok();
+        events.post(ServerEvent::ListenerReleased {
+            session_id,
+            protocol: "UDP".to_string(),
+            addr: info.udp_server.addr(),
+        });
+    }
+
+    /// Add an authenticated session to the bookkeeping and return its id.
+    fn register_session(state: &Arc<Mutex<State>>, tun_type: &TunnelType) -> u64 {
+        let mut state = state.lock().unwrap();
+        state.next_session_id += 1;
+        let id = state.next_session_id;
+        state.sessions.push(ConnectedSession {
+            id,
+            conn: tun_type.conn().clone(),
+            user: tun_type.user().to_string(),
+            tunnel_type: tun_type.name(),
+            upstream: tun_type.upstream_addr(),
+            start_time: Instant::now(),
+        });
+        id
+    }
+
+    /// Chain the global, per-user and per-tunnel limits for a new tunnel of
+    /// `user`, the caller adds the per-connection stream limit.
+    fn create_traffic_limiter(state: &Arc<Mutex<State>>, user: &str) -> TrafficLimiter {
+        let mut state = state.lock().unwrap();
+        let mut limiter = TrafficLimiter::default().with(state.global_buckets.clone());
+
+        let user_rate_limit = state.config.find_user(user).and_then(|u| u.rate_limit);
+        if let Some(rate_limit) = user_rate_limit {
+            let buckets = state
+                .user_buckets
+                .entry(user.to_string())
+                .or_insert_with(|| LimitBuckets::for_server(&rate_limit))
+                .clone();
+            limiter = limiter.with(buckets);
+        }
+
+        limiter.with(LimitBuckets::for_server(&state.config.tunnel_rate_limit))
+    }
+
     async fn authenticate_connection(
+        state: &Arc<Mutex<State>>,
         config: &ServerConfig,
+        authenticator: &dyn Authenticator,
+        quota_tracker: &QuotaTracker,
+        login_guard: &LoginGuard,
+        permit: &mut SessionPermit,
         conn: quinn::Connection,
-    ) -> Result<TunnelType> {
+    ) -> Result<(TunnelType, Features)> {
         let remote_addr = &conn.remote_address();
 
         info!("authenticating connection, addr:{remote_addr}");
@@ -270,26 +1116,199 @@ impl Server {
             .context(format!("login request not received in time: {remote_addr}"))?;
 
         info!("received bi_stream request: {remote_addr}");
+        let events = state.lock().unwrap().events.clone();
+        let Some(client_hello) = Hello::recv(&mut quic_recv).await? else {
+            let reason = "the client is too old, please upgrade the client".to_string();
+            Hello::reject_legacy_peer(&mut quic_send, &reason)
+                .await
+                .ok();
+            events.post(ServerEvent::LoginRejected {
+                remote_addr: *remote_addr,
+                username: String::new(),
+                reason,
+            });
+            log_and_bail!("login rejected, client predates the protocol hello: {remote_addr}");
+        };
+        // always answer with the server's hello, so that the client can tell
+        // why it is rejected
+        Hello::local().send(&mut quic_send).await?;
+        let negotiated = match Hello::local().negotiate(&client_hello, "server", "client") {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                // make sure the hello reaches the client before the connection is dropped
+                finish_stream(&mut quic_send).await;
+                events.post(ServerEvent::LoginRejected {
+                    remote_addr: *remote_addr,
+                    username: String::new(),
+                    reason: e.to_string(),
+                });
+                log_and_bail!("login rejected: {remote_addr}, {e}");
+            }
+        };
+        debug!(
+            "protocol v{}, features {}: {remote_addr}",
+            negotiated.version, negotiated.features
+        );
+
         match TunnelMessage::recv(&mut quic_recv).await? {
             TunnelMessage::ReqLogin(login_info) => {
                 info!("received ReqLogin request: {remote_addr}");
+                let report_rejected = |reason: String| {
+                    events.post(ServerEvent::LoginRejected {
+                        remote_addr: *remote_addr,
+                        username: login_info.username.clone(),
+                        reason,
+                    })
+                };
+
+                if state.lock().unwrap().shutting_down {
+                    let failure = LoginFailure::new(
+                        LoginErrorCode::ShuttingDown,
+                        "the server is shutting down",
+                    );
+                    TunnelMessage::send_failure(&mut quic_send, negotiated.version, &failure)
+                        .await
+                        .ok();
+                    report_rejected(failure.message);
+                    log_and_bail!("login rejected, shutting down: {remote_addr}");
+                }
+
+                let nonce = auth::generate_nonce()?;
+                TunnelMessage::send(&mut quic_send, &TunnelMessage::RespChallenge(nonce.clone()))
+                    .await?;
+                let proof = match TunnelMessage::recv(&mut quic_recv).await? {
+                    TunnelMessage::ReqLoginProof(proof) => proof,
+                    _ => {
+                        log_and_bail!("received unexpected message, expected login proof");
+                    }
+                };
 
-                Self::check_password(config.password.as_str(), login_info.password.as_str())?;
+                let request = AuthRequest {
+                    remote_addr: *remote_addr,
+                    tunnel: &login_info.tunnel,
+                    credentials: Credentials::new(&conn, &login_info.username, &nonce, &proof),
+                };
+                let user = match authenticator.authenticate(request).await {
+                    AuthDecision::Allow { identity } => {
+                        METRICS.login_successes.inc();
+                        login_guard.record_success(remote_addr.ip());
+                        identity
+                    }
+                    AuthDecision::Deny { reason } => {
+                        METRICS.login_failures.inc();
+                        // slow down password guessing, see LoginGuard
+                        let delay = login_guard.record_failure(remote_addr.ip());
+                        tokio::time::sleep(delay).await;
+                        let failure = LoginFailure::new(LoginErrorCode::AuthFailed, reason.clone());
+                        TunnelMessage::send_failure(&mut quic_send, negotiated.version, &failure)
+                            .await
+                            .ok();
+                        report_rejected(reason.clone());
+                        log_and_bail!("login failed: {remote_addr}, reason: {reason}");
+                    }
+                };
+
+                if !user.is_empty() && state.lock().unwrap().revoked_users.contains(&user) {
+                    let failure = LoginFailure::new(LoginErrorCode::AuthFailed, "user revoked");
+                    TunnelMessage::send_failure(&mut quic_send, negotiated.version, &failure)
+                        .await
+                        .ok();
+                    report_rejected("user revoked".to_string());
+                    log_and_bail!("login rejected, user {user} is revoked: {remote_addr}");
+                }
 
+                if !user.is_empty() {
+                    if let Err(e) = permit.bind_user(&user) {
+                        let failure = LoginFailure::new(LoginErrorCode::Other, e.to_string());
+                        TunnelMessage::send_failure(&mut quic_send, negotiated.version, &failure)
+                            .await
+                            .ok();
+                        report_rejected(e.to_string());
+                        log_and_bail!("login rejected: {e}");
+                    }
+                }
+
+                let quota = config.find_user(&user).and_then(|u| u.quota);
+                if let Some(Err(e)) = quota.map(|quota| quota_tracker.check(&user, &quota)) {
+                    let failure = e.login_failure();
+                    TunnelMessage::send_failure(&mut quic_send, negotiated.version, &failure)
+                        .await
+                        .ok();
+                    report_rejected(e.to_string());
+                    log_and_bail!("login rejected for user {user}: {e}");
+                }
+
+                let mut tunnel_statuses = None;
                 let tunnel_type = match login_info.tunnel {
                     Tunnel::NetworkBased(tunnel_config) => {
-                        Self::derive_tunnel_type(conn, &mut quic_send, &tunnel_config, config)
-                            .await?
+                        match Self::derive_tunnel_type(conn, &tunnel_config, config, user.clone())
+                            .await
+                        {
+                            Ok(tunnel_type) => tunnel_type,
+                            Err(e) => {
+                                let failure = LoginFailure::from_error(&e);
+                                TunnelMessage::send_failure(
+                                    &mut quic_send,
+                                    negotiated.version,
+                                    &failure,
+                                )
+                                .await
+                                .ok();
+                                report_rejected(e.to_string());
+                                log_and_bail!("login rejected: {remote_addr}, {e}");
+                            }
+                        }
+                    }
+                    Tunnel::ChannelBased(upstream_type) => {
+                        let info = DynamicUpstreamInfo {
+                            conn,
+                            user: user.clone(),
+                        };
+                        match upstream_type {
+                            UpstreamType::Tcp => TunnelType::DynamicUpstreamTcpOut(info),
+                            UpstreamType::Udp => TunnelType::DynamicUpstreamUdpOut(info),
+                        }
+                    }
+                    Tunnel::Multiplexed(tunnel_configs) => {
+                        let (tunnels, statuses) = match Self::derive_multiplexed_tunnels(
+                            &conn,
+                            &tunnel_configs,
+                            config,
+                            &user,
+                            negotiated,
+                        )
+                        .await
+                        {
+                            Ok(result) => result,
+                            Err(e) => {
+                                let failure = LoginFailure::from_error(&e);
+                                TunnelMessage::send_failure(
+                                    &mut quic_send,
+                                    negotiated.version,
+                                    &failure,
+                                )
+                                .await
+                                .ok();
+                                report_rejected(e.to_string());
+                                log_and_bail!("login rejected: {remote_addr}, {e}");
+                            }
+                        };
+                        tunnel_statuses = Some(statuses);
+                        TunnelType::Multiplexed(MultiplexedInfo {
+                            conn,
+                            user: user.clone(),
+                            tunnels,
+                        })
                     }
-                    Tunnel::ChannelBased(upstream_type) => match upstream_type {
-                        UpstreamType::Tcp => TunnelType::DynamicUpstreamTcpOut(conn),
-                        UpstreamType::Udp => TunnelType::DynamicUpstreamUdpOut(conn),
-                    },
                 };
 
                 TunnelMessage::send(&mut quic_send, &TunnelMessage::RespSuccess).await?;
-                info!("connection authenticated! addr: {remote_addr}");
-                Ok(tunnel_type)
+                if let Some(statuses) = tunnel_statuses {
+                    TunnelMessage::send(&mut quic_send, &TunnelMessage::RespTunnels(statuses))
+                        .await?;
+                }
+                info!("connection authenticated! addr: {remote_addr}, user: {user}");
+                Ok((tunnel_type, negotiated.features))
             }
 
             _ => {
@@ -298,78 +1317,195 @@ impl Server {
         }
     }
 
+    /// Derive the tunnels of a multiplexed login, a tunnel that can't be
+    /// started is reported in the returned statuses without failing the
+    /// others. Fails if none of the tunnels can be started.
+    async fn derive_multiplexed_tunnels(
+        conn: &quinn::Connection,
+        tunnel_configs: &[TunnelConfig],
+        config: &ServerConfig,
+        user: &str,
+        negotiated: Negotiated,
+    ) -> Result<(Vec<(TunnelId, TunnelType)>, Vec<TunnelStatus>)> {
+        if !negotiated.features.contains(Features::MULTIPLEX) {
+            log_and_bail!("multiplexing is not supported by the client");
+        }
+        if tunnel_configs.is_empty() || tunnel_configs.len() > TunnelId::MAX as usize + 1 {
+            log_and_bail!("invalid number of tunnels: {}", tunnel_configs.len());
+        }
+
+        let mut tunnels = Vec::new();
+        let mut statuses = Vec::new();
+        let mut failures = Vec::new();
+        for (tunnel_id, tunnel_config) in tunnel_configs.iter().enumerate() {
+            match Self::derive_tunnel_type(conn.clone(), tunnel_config, config, user.to_string())
+                .await
+            {
+                Ok(tunnel_type) => {
+                    tunnels.push((tunnel_id as TunnelId, tunnel_type));
+                    statuses.push(TunnelStatus::Started);
+                }
+                Err(e) => {
+                    let failure = LoginFailure::from_error(&e);
+                    statuses.push(TunnelStatus::failed(failure.clone(), negotiated.version));
+                    failures.push((tunnel_id, failure));
+                }
+            }
+        }
+
+        if tunnels.is_empty() {
+            // the client can only tell why if all tunnels failed for the same reason
+            let code = failures[0].1.code;
+            let code = if failures.iter().all(|(_, failure)| failure.code == code) {
+                code
+            } else {
+                LoginErrorCode::Other
+            };
+            let errors: Vec<String> = failures
+                .iter()
+                .map(|(tunnel_id, failure)| format!("tunnel {tunnel_id}: {failure}"))
+                .collect();
+            let failure = LoginFailure::new(
+                code,
+                format!("no tunnel can be started, {}", errors.join(", ")),
+            );
+            error!("{failure}");
+            return Err(failure.into());
+        }
+        Ok((tunnels, statuses))
+    }
+
+    /// Derive the tunnel of `tunnel_config`, binding the listener of IN
+    /// tunnels. Errors are meant to be sent to the client.
     async fn derive_tunnel_type(
         conn: quinn::Connection,
-        quic_send: &mut SendStream,
         tunnel_config: &TunnelConfig,
         config: &ServerConfig,
+        user: String,
     ) -> Result<TunnelType> {
-        let upstream_addr = match tunnel_config.upstream.upstream_type {
-            UpstreamType::Tcp => {
-                Self::obtain_upstream_addr(tunnel_config, &config.default_tcp_upstream)?
-            }
-            UpstreamType::Udp => {
-                Self::obtain_upstream_addr(tunnel_config, &config.default_udp_upstream)?
+        let default_upstream =
+            config.default_upstream_of(&user, &tunnel_config.upstream.upstream_type);
+        let upstream_addr = Self::obtain_upstream_addr(tunnel_config, &default_upstream)?;
+
+        let tunnel_type = match (tunnel_config.mode.clone(), upstream_addr) {
+            (TunnelMode::Out, upstream_addr) => {
+                Self::check_upstream_acl(config, &upstream_addr)?;
+                match tunnel_config.upstream.upstream_type {
+                    UpstreamType::Tcp => TunnelType::TcpOut(TcpTunnelOutInfo {
+                        conn,
+                        upstream_addr,
+                        user,
+                    }),
+
+                    UpstreamType::Udp => TunnelType::UdpOut(UdpTunnelOutInfo {
+                        conn,
+                        upstream_addr,
+                        user,
+                    }),
+                }
             }
-        };
-        let tunnel_type = match tunnel_config.mode {
-            TunnelMode::Out => match tunnel_config.upstream.upstream_type {
-                UpstreamType::Tcp => TunnelType::TcpOut(TcpTunnelOutInfo {
-                    conn,
-                    upstream_addr,
-                }),
 
-                UpstreamType::Udp => TunnelType::UdpOut(UdpTunnelOutInfo {
-                    conn,
-                    upstream_addr,
-                }),
-            },
+            (TunnelMode::In, UpstreamAddr::Domain(..)) => {
+                log_and_bail!("a domain name is not allowed for inbound tunneling");
+            }
 
-            TunnelMode::In => match tunnel_config.upstream.upstream_type {
-                UpstreamType::Tcp => {
-                    let tcp_server = match TcpServer::bind_and_start(upstream_addr).await {
-                        Ok(tcp_server) => tcp_server,
-                        Err(e) => {
-                            TunnelMessage::send_failure(
-                                quic_send,
-                                format!("udp server failed to bind at: {upstream_addr}"),
-                            )
-                            .await?;
-                            log_and_bail!("tcp_IN login rejected: {e}");
-                        }
-                    };
+            (TunnelMode::In, UpstreamAddr::Ip(upstream_addr)) => {
+                Self::check_in_port(config, &user, upstream_addr)?;
+                match tunnel_config.upstream.upstream_type {
+                    UpstreamType::Tcp => {
+                        let tcp_server = match TcpServer::bind_and_start(upstream_addr).await {
+                            Ok(tcp_server) => tcp_server,
+                            Err(e) => return Err(Self::bind_failure("tcp", upstream_addr, e)),
+                        };
 
-                    TunnelMessage::send(quic_send, &TunnelMessage::RespSuccess).await?;
-                    TunnelType::TcpIn(TcpTunnelInInfo { conn, tcp_server })
-                }
+                        TunnelType::TcpIn(TcpTunnelInInfo {
+                            conn,
+                            tcp_server,
+                            user,
+                        })
+                    }
 
-                UpstreamType::Udp => {
-                    let udp_server = match UdpServer::bind_and_start(upstream_addr).await {
-                        Ok(udp_server) => udp_server,
-                        Err(e) => {
-                            TunnelMessage::send_failure(
-                                quic_send,
-                                format!("udp server failed to bind at: {upstream_addr}"),
-                            )
-                            .await?;
-                            log_and_bail!("udp_IN login rejected: {e}");
-                        }
-                    };
+                    UpstreamType::Udp => {
+                        let udp_server = match UdpServer::bind_and_start(
+                            upstream_addr,
+                            config.max_udp_payload_size,
+                        )
+                        .await
+                        {
+                            Ok(udp_server) => udp_server,
+                            Err(e) => return Err(Self::bind_failure("udp", upstream_addr, e)),
+                        };
 
-                    TunnelMessage::send(quic_send, &TunnelMessage::RespSuccess).await?;
-                    TunnelType::UdpIn(UdpTunnelInInfo { conn, udp_server })
+                        TunnelType::UdpIn(UdpTunnelInInfo {
+                            conn,
+                            udp_server,
+                            user,
+                        })
+                    }
                 }
-            },
+            }
         };
 
         Ok(tunnel_type)
     }
 
+    fn check_in_port(config: &ServerConfig, user: &str, addr: SocketAddr) -> Result<()> {
+        let port_ranges = config.in_port_ranges_of(user);
+        if port_ranges.is_empty() || port_ranges.iter().any(|r| r.contains(addr.port())) {
+            return Ok(());
+        }
+
+        let allowed = PortRange::format_list(port_ranges);
+        let failure = LoginFailure::new(
+            LoginErrorCode::Forbidden,
+            format!(
+                "port {} is not allowed for inbound tunnels, allowed ports: {allowed}",
+                addr.port()
+            ),
+        );
+        error!("{failure}");
+        Err(failure.into())
+    }
+
+    /// Reject an OUT tunnel to an IP upstream denied by the upstream ACL, so
+    /// that the client learns it at login. Domain upstreams are checked for
+    /// every stream once they are resolved.
+    fn check_upstream_acl(config: &ServerConfig, upstream_addr: &UpstreamAddr) -> Result<()> {
+        let Some(addr) = upstream_addr.socket_addr() else {
+            return Ok(());
+        };
+        if config.upstream_acl.is_allowed(&addr) {
+            return Ok(());
+        }
+
+        let failure = LoginFailure::new(
+            LoginErrorCode::Forbidden,
+            format!("upstream is not allowed: {addr}"),
+        );
+        error!("{failure}");
+        Err(failure.into())
+    }
+
+    /// Failure of binding the listener of an IN tunnel at `addr`.
+    fn bind_failure(protocol: &str, addr: SocketAddr, e: anyhow::Error) -> anyhow::Error {
+        let code = match e.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+            Some(ErrorKind::AddrInUse) => LoginErrorCode::PortInUse,
+            Some(ErrorKind::PermissionDenied) => LoginErrorCode::Forbidden,
+            _ => LoginErrorCode::Other,
+        };
+        let failure = LoginFailure::new(
+            code,
+            format!("{protocol} server failed to bind at: {addr}, err: {e}"),
+        );
+        error!("{failure}");
+        failure.into()
+    }
+
     fn obtain_upstream_addr(
         tunnel_config: &TunnelConfig,
-        default_upstream: &Option<SocketAddr>,
-    ) -> Result<SocketAddr> {
-        Ok(match tunnel_config.upstream.upstream_addr {
+        default_upstream: &Option<UpstreamAddr>,
+    ) -> Result<UpstreamAddr> {
+        Ok(match &tunnel_config.upstream.upstream_addr {
             None => {
                 if tunnel_config.mode == TunnelMode::In {
                     log_and_bail!("explicit port is required to start inbound tunneling");
@@ -382,26 +1518,46 @@ impl Server {
                     );
                 }
 
-                default_upstream.unwrap()
+                default_upstream.clone().unwrap()
             }
 
             Some(addr) => {
                 if tunnel_config.mode == TunnelMode::In
-                    && !addr.ip().is_unspecified()
-                    && !addr.ip().is_loopback()
+                    && addr
+                        .socket_addr()
+                        .is_some_and(|addr| !addr.ip().is_unspecified() && !addr.ip().is_loopback())
                 {
-                    log_and_bail!(
-                        "only loopback or unspecified IP is allowed for inbound tunelling: {addr}, or simply specify a port without the IP part"
+                    let failure = LoginFailure::new(
+                        LoginErrorCode::Forbidden,
+                        format!("only loopback or unspecified IP is allowed for inbound tunelling: {addr}, or simply specify a port without the IP part"),
                     );
+                    error!("{failure}");
+                    return Err(failure.into());
                 }
 
-                addr
+                addr.clone()
             }
         })
     }
 
     fn clear_expired_sessions(state: Arc<Mutex<State>>) {
         let mut state = state.lock().unwrap();
+        state.login_guard.prune();
+
+        let (closed, active): (Vec<_>, Vec<_>) = std::mem::take(&mut state.sessions)
+            .into_iter()
+            .partition(|sess| sess.conn.close_reason().is_some());
+        for sess in closed {
+            let stats = sess.conn.stats();
+            state.closed_rx_bytes += stats.udp_rx.bytes;
+            state.closed_tx_bytes += stats.udp_tx.bytes;
+            debug!(
+                "session closed: {}, id: {}",
+                sess.conn.remote_address(),
+                sess.id
+            );
+        }
+        state.sessions = active;
         state.udp_sessions.retain(|sess| {
             if sess.conn.close_reason().is_some() {
                 let sess = sess.clone();
@@ -457,11 +1613,56 @@ impl Server {
 
         Ok((certs, key))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Upstream;
+
+    fn tunnel(upstream_addr: Option<&str>) -> TunnelConfig {
+        TunnelConfig {
+            name: String::new(),
+            mode: TunnelMode::Out,
+            local_server_addr: None,
+            upstream: Upstream {
+                upstream_addr: upstream_addr.map(|addr| addr.parse().unwrap()),
+                upstream_type: UpstreamType::Tcp,
+            },
+            rate_limit: None,
+            idle_timeout_ms: None,
+        }
+    }
+
+    fn check(config: &ServerConfig, tunnel: &TunnelConfig) -> Result<()> {
+        let default_upstream = config.default_upstream_of("", &tunnel.upstream.upstream_type);
+        let upstream_addr = Server::obtain_upstream_addr(tunnel, &default_upstream)?;
+        Server::check_upstream_acl(config, &upstream_addr)
+    }
 
-    fn check_password(password1: &str, password2: &str) -> Result<()> {
-        if password1 != password2 {
-            log_and_bail!("passwords don't match!");
+    #[test]
+    fn denied_ip_upstream_fails_login() {
+        let config = ServerConfig {
+            upstream_acl: "DENY^169.254.0.0/16,DENY^::ffff:10.0.0.0/104,ALLOW^ANY"
+                .parse()
+                .unwrap(),
+            default_tcp_upstream: Some("169.254.169.254:80".parse().unwrap()),
+            ..ServerConfig::default()
+        };
+
+        for upstream in [None, Some("169.254.169.254:80"), Some("10.1.2.3:22")] {
+            let e = check(&config, &tunnel(upstream)).unwrap_err();
+            let failure = LoginFailure::from_error(&e);
+            assert_eq!(failure.code, LoginErrorCode::Forbidden, "{upstream:?}");
         }
-        Ok(())
+
+        check(&config, &tunnel(Some("192.168.1.1:80"))).unwrap();
+        // domains are checked for every stream once they are resolved
+        check(&config, &tunnel(Some("example.com:80"))).unwrap();
+        check(
+            &ServerConfig::default(),
+            &tunnel(Some("169.254.169.254:80")),
+        )
+        .unwrap();
     }
 }
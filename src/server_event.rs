@@ -0,0 +1,89 @@
+//! Events reported by the server to an optional listener.
+//!
+//! The server-side counterpart of the client's info listener: an embedding
+//! application installs a listener with [`Server::set_on_event_listener`] and is
+//! called with a [`ServerEvent`] for logins, tunnels and IN listeners.
+//!
+//! [`Server::set_on_event_listener`]: crate::Server::set_on_event_listener
+
+use crate::UpstreamAddr;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// An event in the lifecycle of a session, serialized with an `event` tag.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event")]
+pub enum ServerEvent {
+    /// A client logged in, its tunnel is about to start.
+    SessionAuthenticated {
+        session_id: u64,
+        remote_addr: SocketAddr,
+        /// Authenticated user, empty for shared-password logins.
+        user: String,
+        /// TCP_OUT, TCP_IN, UDP_OUT, UDP_IN, TCP_DYNAMIC or UDP_DYNAMIC.
+        tunnel_type: String,
+    },
+    /// The tunnel of a session started serving.
+    TunnelStarted {
+        session_id: u64,
+        tunnel_type: String,
+        /// Upstream of OUT tunnels or listen address of IN tunnels.
+        upstream: Option<UpstreamAddr>,
+    },
+    /// The tunnel of a session ended.
+    TunnelEnded {
+        session_id: u64,
+        user: String,
+        /// Bytes received over QUIC during the session.
+        rx_bytes: u64,
+        /// Bytes sent over QUIC during the session.
+        tx_bytes: u64,
+        duration_secs: u64,
+        /// Why the connection was closed, if it was.
+        reason: Option<String>,
+    },
+    /// A login was rejected.
+    LoginRejected {
+        remote_addr: SocketAddr,
+        /// Username sent by the client, empty for shared-password logins.
+        username: String,
+        reason: String,
+    },
+    /// A listener was bound for an IN tunnel.
+    ListenerBound {
+        session_id: u64,
+        /// TCP or UDP.
+        protocol: String,
+        addr: SocketAddr,
+    },
+    /// The listener of an IN tunnel was shut down.
+    ListenerReleased {
+        session_id: u64,
+        protocol: String,
+        addr: SocketAddr,
+    },
+}
+
+type Listener = dyn FnMut(&ServerEvent) + 'static + Send;
+
+#[derive(Clone, Default)]
+/// Posts server events to a user-provided listener, if installed.
+pub(crate) struct ServerEventBridge {
+    listener: Arc<Mutex<Option<Box<Listener>>>>,
+}
+
+impl ServerEventBridge {
+    /// Install `listener`, replacing the previous one. Clones of the bridge
+    /// share the listener.
+    pub(crate) fn set_listener(&self, listener: impl FnMut(&ServerEvent) + 'static + Send) {
+        *self.listener.lock().unwrap() = Some(Box::new(listener));
+    }
+
+    /// Post `event` to the installed listener (if any).
+    pub(crate) fn post(&self, event: ServerEvent) {
+        if let Some(listener) = self.listener.lock().unwrap().as_mut() {
+            listener(&event);
+        }
+    }
+}
@@ -5,6 +5,7 @@
 //! It is used by the tunneling implementation to manage incoming and outgoing
 //! TCP connections.
 
+use crate::UpstreamAddr;
 use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
@@ -25,10 +26,11 @@ impl AsyncStream for TcpStream {
     }
 }
 
-/// Request to process an inbound stream and optionally its intended destination.
+/// Request to process an inbound stream and optionally its intended destination,
+/// domain names are resolved by the server.
 pub struct StreamRequest<S: AsyncStream> {
     pub stream: S,
-    pub dst_addr: Option<SocketAddr>,
+    pub dst_addr: Option<UpstreamAddr>,
 }
 
 /// Messages passed between TCP server and tunnel task.
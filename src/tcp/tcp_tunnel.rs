@@ -8,13 +8,14 @@
 //!
 //! ```rust,ignore
 //! use quinn::Connection;
-//! use std::net::SocketAddr;
+//! use rstun::UpstreamAddr;
 //!
-//! async fn example(conn: &Connection, addr: SocketAddr) {
+//! async fn example(conn: &Connection, addr: UpstreamAddr) {
 //!     // Serving TCP connections over QUIC.
 //!     // TcpTunnel::start_serving::<YourAsyncStreamType>(
 //!     //     true,    // tunnel_out: true for OUT mode, false for IN mode
 //!     //     conn,
+//!     //     None,    // tunnel id, only set on multiplexed connections
 //!     //     &mut your_stream_receiver,
 //!     //     &mut None, // no pending request initially
 //!     //     5000,      // stream timeout in milliseconds
@@ -24,17 +25,27 @@
 //!     // TcpTunnel::start_accepting(
 //!     //     conn,
 //!     //     Some(addr), // upstream TCP address
+//!     //     None,       // no upstream ACL
+//!     //     TrafficLimiter::default(), // no rate limit
 //!     //     5000,       // stream timeout in milliseconds
 //!     // ).await;
 //! }
 //! ```
 
+use crate::acl::{UpstreamAcl, DENIED_STREAM_ERROR_CODE};
+use crate::metrics::METRICS;
+use crate::mux::{self, TunnelId};
 use crate::tcp::StreamMessage;
 use crate::tcp::{AsyncStream, StreamReceiver, StreamRequest};
+use crate::util::rate_limiter::TrafficLimiter;
+use crate::util::resolver;
 use crate::util::stream_util::StreamUtil;
-use log::{debug, error, info};
+use crate::UpstreamAddr;
+use log::{debug, error, info, warn};
+use quinn::VarInt;
 use std::borrow::BorrowMut;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpStream;
 
@@ -44,27 +55,35 @@ impl TcpTunnel {
     /// Serve outbound or inbound TCP by bridging accepted streams to QUIC.
     ///
     /// - `tunnel_out`: true for OUT mode logs, false for IN mode.
+    /// - `tunnel_id`: tags every stream on multiplexed connections.
     /// - `pending_request`: used to retry the last request on transient errors.
+    /// - `limiter`: throttles every stream of the tunnel.
     pub async fn start_serving<S: AsyncStream>(
         tunnel_out: bool,
         conn: &quinn::Connection,
+        tunnel_id: Option<TunnelId>,
         stream_receiver: &mut StreamReceiver<S>,
         pending_request: &mut Option<StreamRequest<S>>,
+        limiter: TrafficLimiter,
         stream_timeout_ms: u64,
     ) {
         loop {
             let request = match pending_request.take() {
                 Some(request) => request,
-                None => match stream_receiver.borrow_mut().recv().await {
-                    Some(StreamMessage::Request(request)) => request,
-                    _ => break,
+                None => tokio::select! {
+                    message = stream_receiver.borrow_mut().recv() => match message {
+                        Some(StreamMessage::Request(request)) => request,
+                        _ => break,
+                    },
+                    _ = conn.closed() => break,
                 },
             };
 
-            match conn.open_bi().await {
+            limiter.acquire_stream().await;
+            match mux::open_bi(conn, tunnel_id).await {
                 Ok((mut quic_send, quic_recv)) => {
                     if let Err(e) =
-                        StreamUtil::write_socket_addr(&mut quic_send, &request.dst_addr, false)
+                        StreamUtil::write_upstream_addr(&mut quic_send, &request.dst_addr, false)
                             .await
                     {
                         error!("failed to send dst addr: {e}");
@@ -75,6 +94,7 @@ impl TcpTunnel {
                         if tunnel_out { "OUT" } else { "IN" },
                         request.stream,
                         (quic_send, quic_recv),
+                        limiter.clone(),
                         stream_timeout_ms,
                     )
                 }
@@ -89,9 +109,15 @@ impl TcpTunnel {
     }
 
     /// Accept peer QUIC streams and connect to the upstream TCP endpoint.
+    ///
+    /// Domain upstreams are resolved and their addresses tried in order.
+    /// Streams whose upstream is denied by `upstream_acl` are reset, all
+    /// streams are throttled by `limiter`.
     pub async fn start_accepting(
         conn: &quinn::Connection,
-        upstream_addr: Option<SocketAddr>,
+        upstream_addr: Option<UpstreamAddr>,
+        upstream_acl: Option<Arc<UpstreamAcl>>,
+        limiter: TrafficLimiter,
         stream_timeout_ms: u64,
     ) {
         let remote_addr = &conn.remote_address();
@@ -111,39 +137,96 @@ impl TcpTunnel {
                     error!("failed to open accept_bi: {remote_addr}, err: {e}");
                     break;
                 }
-                Ok((quic_send, mut quic_recv)) => tokio::spawn(async move {
-                    let dst_addr = match upstream_addr {
-                        Some(dst_addr) => dst_addr,
-                        None => {
-                            match StreamUtil::read_socket_addr(&mut quic_recv, stream_timeout_ms)
-                                .await
-                            {
-                                Ok(dst_addr) => dst_addr,
-                                Err(e) => {
-                                    log::error!("failed to read dst address: {e}");
-                                    return;
-                                }
-                            }
-                        }
-                    };
-
-                    match tokio::time::timeout(
-                        Duration::from_secs(5),
-                        TcpStream::connect(&dst_addr),
-                    )
-                    .await
-                    {
-                        Ok(Ok(request)) => StreamUtil::start_flowing(
-                            "OUT",
-                            request,
-                            (quic_send, quic_recv),
-                            stream_timeout_ms,
-                        ),
-                        Ok(Err(e)) => error!("failed to connect to {dst_addr}, err: {e}"),
-                        Err(_) => error!("timeout connecting to {dst_addr}"),
-                    }
-                }),
+                Ok((quic_send, quic_recv)) => {
+                    limiter.acquire_stream().await;
+                    tokio::spawn(Self::accept_stream(
+                        *remote_addr,
+                        quic_send,
+                        quic_recv,
+                        upstream_addr.clone(),
+                        upstream_acl.clone(),
+                        limiter.clone(),
+                        stream_timeout_ms,
+                    ));
+                }
             };
         }
     }
+
+    /// Connect one accepted QUIC stream to its upstream, which is read from
+    /// the stream if `upstream_addr` is None.
+    pub(crate) async fn accept_stream(
+        remote_addr: SocketAddr,
+        mut quic_send: quinn::SendStream,
+        mut quic_recv: quinn::RecvStream,
+        upstream_addr: Option<UpstreamAddr>,
+        upstream_acl: Option<Arc<UpstreamAcl>>,
+        limiter: TrafficLimiter,
+        stream_timeout_ms: u64,
+    ) {
+        let dst_addr = match upstream_addr {
+            Some(dst_addr) => dst_addr,
+            None => match StreamUtil::read_upstream_addr(&mut quic_recv, stream_timeout_ms).await {
+                Ok(dst_addr) => dst_addr,
+                Err(e) => {
+                    log::error!("failed to read dst address: {e}");
+                    return;
+                }
+            },
+        };
+
+        let resolved_addrs = match resolver::resolve(&dst_addr).await {
+            Ok(resolved_addrs) => resolved_addrs,
+            Err(e) => {
+                METRICS.upstream_connect_failures.inc();
+                error!("{e:#}");
+                return;
+            }
+        };
+
+        // every resolved address must pass the acl, so that a
+        // domain can't be used to reach a denied network
+        let allowed_addrs: Vec<SocketAddr> = resolved_addrs
+            .into_iter()
+            .filter(|addr| upstream_acl.as_ref().is_none_or(|acl| acl.is_allowed(addr)))
+            .collect();
+        if allowed_addrs.is_empty() {
+            warn!("upstream denied by acl: {remote_addr} → {dst_addr}");
+            quic_send
+                .reset(VarInt::from_u32(DENIED_STREAM_ERROR_CODE))
+                .ok();
+            quic_recv
+                .stop(VarInt::from_u32(DENIED_STREAM_ERROR_CODE))
+                .ok();
+            return;
+        }
+
+        if let Some(request) = Self::connect_upstream(&allowed_addrs).await {
+            StreamUtil::start_flowing(
+                "OUT",
+                request,
+                (quic_send, quic_recv),
+                limiter,
+                stream_timeout_ms,
+            )
+        }
+    }
+
+    /// Connect to the first reachable address of `addrs`, trying them in order.
+    async fn connect_upstream(addrs: &[SocketAddr]) -> Option<TcpStream> {
+        for addr in addrs {
+            match tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Some(stream),
+                Ok(Err(e)) => {
+                    METRICS.upstream_connect_failures.inc();
+                    error!("failed to connect to {addr}, err: {e}");
+                }
+                Err(_) => {
+                    METRICS.upstream_connect_timeouts.inc();
+                    error!("timeout connecting to {addr}");
+                }
+            }
+        }
+        None
+    }
 }
@@ -23,6 +23,8 @@ pub(crate) enum TunnelInfoType {
     TunnelState,
     TunnelLog,
     TunnelTraffic,
+    /// A login rejected by the server, carries a [`crate::LoginFailure`].
+    LoginFailure,
 }
 
 #[derive(Serialize)]
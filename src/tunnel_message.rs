@@ -3,35 +3,260 @@
 //! This module defines the messages used for controlling the tunnel
 //! lifecycle and for coordinating per-packet operations between
 //! client and server.
-use crate::{Tunnel, TunnelMode};
+use crate::{Tunnel, TunnelMode, UpstreamAddr, BUFFER_POOL, MAX_UDP_PAYLOAD_SIZE};
 use anyhow::Result;
 use anyhow::{bail, Context};
 use bincode::config::{self, Configuration};
+use byte_pool::Block;
 use enum_as_inner::EnumAsInner;
-use quinn::{RecvStream, SendStream};
+use quinn::{RecvStream, SendStream, VarInt};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// Magic bytes opening a [`Hello`], they tell apart peers predating the hello
+/// exchange, which start the login stream with a length-prefixed message.
+const HELLO_MAGIC: [u8; 4] = *b"RSTN";
+/// Length of an encoded [`Hello`]: magic, version, min_version and features.
+const HELLO_LEN: usize = 4 + 2 + 2 + 8;
+/// Version of the protocol spoken after the hello exchange, bump it whenever
+/// the encoding of [`TunnelMessage`] changes incompatibly.
+pub(crate) const PROTOCOL_VERSION: u16 = 2;
+/// Oldest protocol version this build can talk to.
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Index of `RespFailure` in the `TunnelMessage` of peers predating the hello exchange.
+const LEGACY_RESP_FAILURE_INDEX: u32 = 2;
+/// First protocol version whose peers understand `RespLoginFailure`.
+const LOGIN_FAILURE_VERSION: u16 = 2;
+/// Longest time to wait for the peer to receive the last message of a
+/// rejected login before the connection is dropped.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bitmap of optional protocol features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Features(u64);
+
+impl Features {
+    /// Many tunnels carried by one connection, see [`Tunnel::Multiplexed`].
+    pub const MULTIPLEX: Features = Features(1);
+    /// UDP payloads carried in QUIC datagrams, see [`crate::ClientConfig::udp_datagrams`].
+    pub const DATAGRAM: Features = Features(1 << 1);
+    /// Features supported by this build.
+    pub const SUPPORTED: Features = Features(Features::MULTIPLEX.0 | Features::DATAGRAM.0);
+
+    /// Whether all of `other` is supported.
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features supported by both sides.
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    /// Features without those of `other`.
+    pub fn difference(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+}
+
+impl Display for Features {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// First message on the login stream, sent by both sides before `ReqLogin`.
+///
+/// Unlike [`TunnelMessage`] its encoding is fixed, so peers of any version can
+/// read it and tell whether they can talk to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Hello {
+    /// Newest protocol version the sender speaks.
+    pub version: u16,
+    /// Oldest protocol version the sender speaks.
+    pub min_version: u16,
+    /// Optional features the sender supports.
+    pub features: Features,
+}
+
+/// Protocol version and features agreed on in the hello exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Negotiated {
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Hello {
+    /// Hello of this build.
+    pub fn local() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: Features::SUPPORTED,
+        }
+    }
+
+    /// The hello without the `features` this side chose not to use.
+    pub fn without(mut self, features: Features) -> Self {
+        self.features = self.features.difference(features);
+        self
+    }
+
+    /// Send the hello via the given QUIC send stream.
+    pub async fn send(&self, quic_send: &mut SendStream) -> Result<()> {
+        quic_send.write_all(&self.encode()).await?;
+        Ok(())
+    }
+
+    fn encode(&self) -> [u8; HELLO_LEN] {
+        let mut buf = [0u8; HELLO_LEN];
+        buf[..4].copy_from_slice(&HELLO_MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.min_version.to_be_bytes());
+        buf[8..].copy_from_slice(&self.features.0.to_be_bytes());
+        buf
+    }
+
+    /// Decode the hello following the magic bytes.
+    fn decode(buf: &[u8; HELLO_LEN - 4]) -> Hello {
+        Hello {
+            version: u16::from_be_bytes([buf[0], buf[1]]),
+            min_version: u16::from_be_bytes([buf[2], buf[3]]),
+            features: Features(u64::from_be_bytes(buf[4..].try_into().unwrap())),
+        }
+    }
+
+    /// Receive the hello of the peer, None if the peer predates the hello exchange.
+    pub async fn recv(quic_recv: &mut RecvStream) -> Result<Option<Hello>> {
+        let mut magic = [0u8; 4];
+        quic_recv
+            .read_exact(&mut magic)
+            .await
+            .context("read hello failed")?;
+        if magic != HELLO_MAGIC {
+            return Ok(None);
+        }
+
+        let mut buf = [0u8; HELLO_LEN - 4];
+        quic_recv
+            .read_exact(&mut buf)
+            .await
+            .context("read hello failed")?;
+        Ok(Some(Hello::decode(&buf)))
+    }
+
+    /// Agree on the protocol version and features to use with `peer`, the
+    /// error tells which side (`local_name` or `peer_name`) must be upgraded.
+    pub fn negotiate(&self, peer: &Hello, local_name: &str, peer_name: &str) -> Result<Negotiated> {
+        if peer.version < self.min_version {
+            bail!(
+                "{peer_name} speaks protocol v{} but {local_name} requires v{} or newer, please upgrade the {peer_name}",
+                peer.version,
+                self.min_version
+            );
+        }
+        if self.version < peer.min_version {
+            bail!(
+                "{peer_name} requires protocol v{} or newer but {local_name} speaks v{}, please upgrade the {local_name}",
+                peer.min_version,
+                self.version
+            );
+        }
+        Ok(Negotiated {
+            version: self.version.min(peer.version),
+            features: self.features.intersection(peer.features),
+        })
+    }
+
+    /// Reply to a peer predating the hello exchange with a failure it can
+    /// decode, such peers expect a `RespFailure` right after their `ReqLogin`.
+    pub async fn reject_legacy_peer(quic_send: &mut SendStream, msg: &str) -> Result<()> {
+        let msg = Self::encode_legacy_failure(msg)?;
+        quic_send.write_u32(msg.len() as u32).await?;
+        quic_send.write_all(&msg).await?;
+        finish_stream(quic_send).await;
+        Ok(())
+    }
+
+    fn encode_legacy_failure(msg: &str) -> Result<Vec<u8>> {
+        // bincode encodes an enum variant as its index followed by its fields
+        bincode::serde::encode_to_vec((LEGACY_RESP_FAILURE_INDEX, msg), config::standard())
+            .context("serialize message failed")
+    }
+}
+
+/// Finish `quic_send` and wait until the peer has received all data sent on
+/// it, so that a rejection is not lost when the connection is dropped.
+pub(crate) async fn finish_stream(quic_send: &mut SendStream) {
+    if quic_send.finish().is_ok() {
+        tokio::time::timeout(FINISH_TIMEOUT, quic_send.stopped())
+            .await
+            .ok();
+    }
+}
+
 #[derive(EnumAsInner, Serialize, Deserialize, Debug, Clone)]
 /// Control/data messages used during login and per-packet coordination.
 pub enum TunnelMessage {
     /// Client → Server: authenticate and declare desired tunnel.
     ReqLogin(LoginInfo),
+    /// Server → Client: nonce the client must prove knowledge of its password against.
+    RespChallenge(Vec<u8>),
+    /// Client → Server: HMAC proof answering RespChallenge.
+    ReqLoginProof(Vec<u8>),
     /// Client → Server: mark the peer address for an upcoming UDP datagram.
     ReqUdpStart(UdpPeerAddr),
     /// Server → Client: failure with reason.
     RespFailure(String),
     /// Server ↔ Client: success acknowledgement.
     RespSuccess,
+    /// Server → Client: outcome of each tunnel of a multiplexed login, in the
+    /// order of the request, sent after RespSuccess.
+    RespTunnels(Vec<TunnelStatus>),
+    /// Server → Client: login failure with its cause, replaces RespFailure
+    /// from protocol v2 on.
+    RespLoginFailure(LoginFailure),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Outcome of starting one tunnel of a multiplexed login.
+pub(crate) enum TunnelStatus {
+    Started,
+    /// Failure sent to peers speaking a protocol older than v2.
+    LegacyFailed(String),
+    Failed(LoginFailure),
+}
+
+impl TunnelStatus {
+    /// Status of a tunnel that failed with `failure`, encoded for peers
+    /// speaking protocol `version`.
+    pub fn failed(failure: LoginFailure, version: u16) -> Self {
+        if version >= LOGIN_FAILURE_VERSION {
+            TunnelStatus::Failed(failure)
+        } else {
+            TunnelStatus::LegacyFailed(failure.message)
+        }
+    }
+
+    /// Why the tunnel failed, None if it started.
+    pub fn failure(&self) -> Option<LoginFailure> {
+        match self {
+            TunnelStatus::Started => None,
+            TunnelStatus::LegacyFailed(message) => {
+                Some(LoginFailure::new(LoginErrorCode::Other, message.clone()))
+            }
+            TunnelStatus::Failed(failure) => Some(failure.clone()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-/// Login payload containing password and requested tunnel description.
+/// Login payload containing the username and requested tunnel description.
 pub(crate) struct LoginInfo {
-    pub password: String,
+    pub username: String,
     pub tunnel: Tunnel,
 }
 
@@ -42,16 +267,17 @@ impl LoginInfo {
             Tunnel::ChannelBased(upstream_type) => {
                 format!("{upstream_type}_ChannelBased →  {remote_addr}")
             }
+            Tunnel::Multiplexed(tunnels) => {
+                format!("MULTIPLEXED({} tunnels) →  {remote_addr}", tunnels.len())
+            }
             Tunnel::NetworkBased(cfg) => {
                 let upstream = &cfg.upstream;
-                let upstream_str = if let Some(upstream) = upstream.upstream_addr {
-                    if upstream.ip().is_loopback() {
+                let upstream_str = match &upstream.upstream_addr {
+                    Some(UpstreamAddr::Ip(upstream)) if upstream.ip().is_loopback() => {
                         format!("{}:{}", remote_addr.ip(), upstream.port())
-                    } else {
-                        format!("{upstream}")
                     }
-                } else {
-                    String::from("PeerDefault")
+                    Some(upstream) => format!("{upstream}"),
+                    None => String::from("PeerDefault"),
                 };
 
                 match cfg.mode {
@@ -77,7 +303,162 @@ impl LoginInfo {
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 /// UDP peer address wrapper used in ReqUdpStart.
-pub struct UdpPeerAddr(pub Option<SocketAddr>);
+pub struct UdpPeerAddr(pub Option<UpstreamAddr>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Application error codes the server closes client connections with.
+pub(crate) enum CloseCode {
+    /// The user has exhausted a traffic quota, the reason is built by
+    /// [`quota_close_reason`].
+    QuotaExceeded = 2,
+    /// An admin closed the session.
+    AdminClosed = 3,
+    /// An admin revoked the user.
+    UserRevoked = 4,
+}
+
+impl CloseCode {
+    pub fn to_varint(self) -> VarInt {
+        VarInt::from_u32(self as u32)
+    }
+
+    pub fn from_varint(code: VarInt) -> Option<Self> {
+        match code.into_inner() {
+            2 => Some(CloseCode::QuotaExceeded),
+            3 => Some(CloseCode::AdminClosed),
+            4 => Some(CloseCode::UserRevoked),
+            _ => None,
+        }
+    }
+}
+
+impl Display for CloseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseCode::QuotaExceeded => write!(f, "quota exceeded"),
+            CloseCode::AdminClosed => write!(f, "closed by admin"),
+            CloseCode::UserRevoked => write!(f, "user revoked"),
+        }
+    }
+}
+
+/// Length of the prefix of a [`CloseCode::QuotaExceeded`] close reason, the
+/// seconds until the quota is reset as a big-endian u64.
+const QUOTA_RESET_PREFIX_LEN: usize = 8;
+
+/// Close reason of a connection whose quota is exhausted: the seconds until
+/// the quota is reset followed by the human-readable `reason`.
+pub(crate) fn quota_close_reason(reason: &str, resets_in: Duration) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(QUOTA_RESET_PREFIX_LEN + reason.len());
+    buf.extend_from_slice(&resets_in.as_secs().to_be_bytes());
+    buf.extend_from_slice(reason.as_bytes());
+    buf
+}
+
+/// Time until the quota is reset and the human-readable reason of a close
+/// reason built by [`quota_close_reason`], None if it is too short.
+pub(crate) fn parse_quota_close_reason(reason: &[u8]) -> Option<(Duration, String)> {
+    let (secs, reason) = reason.split_first_chunk::<QUOTA_RESET_PREFIX_LEN>()?;
+    Some((
+        Duration::from_secs(u64::from_be_bytes(*secs)),
+        String::from_utf8_lossy(reason).into_owned(),
+    ))
+}
+
+/// Why the server rejected a login.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginErrorCode {
+    /// The credentials are wrong, or the user is disabled or revoked.
+    AuthFailed,
+    /// The port requested for an IN tunnel is taken.
+    PortInUse,
+    /// The upstream or the port of an IN tunnel is not allowed.
+    Forbidden,
+    /// The user has exhausted a traffic quota.
+    QuotaExceeded,
+    /// The server is shutting down and accepts no new logins.
+    ShuttingDown,
+    /// Client and server have no protocol version in common.
+    VersionMismatch,
+    /// Any other failure, e.g. an invalid request or too many sessions.
+    Other,
+}
+
+impl LoginErrorCode {
+    /// Whether logging in again can't succeed without a change of the
+    /// client's or the server's config. An exhausted quota is not, it is
+    /// reset when its period ends.
+    pub fn is_permanent(self) -> bool {
+        matches!(
+            self,
+            LoginErrorCode::AuthFailed
+                | LoginErrorCode::Forbidden
+                | LoginErrorCode::VersionMismatch
+        )
+    }
+}
+
+impl Display for LoginErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginErrorCode::AuthFailed => write!(f, "authentication failed"),
+            LoginErrorCode::PortInUse => write!(f, "port in use"),
+            LoginErrorCode::Forbidden => write!(f, "forbidden"),
+            LoginErrorCode::QuotaExceeded => write!(f, "quota exceeded"),
+            LoginErrorCode::ShuttingDown => write!(f, "server shutting down"),
+            LoginErrorCode::VersionMismatch => write!(f, "protocol version mismatch"),
+            LoginErrorCode::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// A rejected login, the error the client's login fails with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LoginFailure {
+    pub code: LoginErrorCode,
+    /// Human-readable reason.
+    pub message: String,
+    /// Seconds to wait before logging in again, e.g. until an exhausted quota
+    /// is reset.
+    pub retry_after_secs: Option<u64>,
+}
+
+impl LoginFailure {
+    pub fn new(code: LoginErrorCode, message: impl Into<String>) -> Self {
+        LoginFailure {
+            code,
+            message: message.into(),
+            retry_after_secs: None,
+        }
+    }
+
+    /// Tell the client to wait `delay` before logging in again.
+    pub fn with_retry_after(mut self, delay: Duration) -> Self {
+        self.retry_after_secs = Some(delay.as_secs());
+        self
+    }
+
+    /// Time to wait before logging in again, if the server said.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after_secs.map(Duration::from_secs)
+    }
+
+    /// The failure `e` was created from, else one with code `Other` and the
+    /// message of `e`.
+    pub(crate) fn from_error(e: &anyhow::Error) -> Self {
+        e.downcast_ref::<LoginFailure>()
+            .cloned()
+            .unwrap_or_else(|| LoginFailure::new(LoginErrorCode::Other, e.to_string()))
+    }
+}
+
+impl Display for LoginFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for LoginFailure {}
 
 impl Display for LoginInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -85,6 +466,9 @@ impl Display for LoginInfo {
             Tunnel::ChannelBased(upstream_type) => {
                 f.write_str(format!("{upstream_type}_ChannelBased").as_str())
             }
+            Tunnel::Multiplexed(tunnels) => {
+                f.write_str(format!("MULTIPLEXED({})", tunnels.len()).as_str())
+            }
             Tunnel::NetworkBased(cfg) => {
                 f.write_str(format!("{}_{}", cfg.upstream.upstream_type, cfg.mode).as_str())
             }
@@ -96,11 +480,17 @@ impl Display for TunnelMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::ReqLogin(login_info) => f.write_str(login_info.to_string().as_str()),
+            Self::RespChallenge(_) => f.write_str("challenge"),
+            Self::ReqLoginProof(_) => f.write_str("login_proof"),
             Self::ReqUdpStart(udp_peer_addr) => {
                 f.write_str(format!("udp_start:{udp_peer_addr:?}").as_str())
             }
             Self::RespFailure(msg) => f.write_str(format!("fail:{msg}").as_str()),
             Self::RespSuccess => f.write_str("succeeded"),
+            Self::RespTunnels(statuses) => f.write_str(format!("tunnels:{statuses:?}").as_str()),
+            Self::RespLoginFailure(failure) => {
+                f.write_str(format!("fail:{}:{failure}", failure.code).as_str())
+            }
         }
     }
 }
@@ -132,26 +522,36 @@ impl TunnelMessage {
         Ok(())
     }
 
-    /// Convenience to send a failure response and flush.
-    pub async fn send_failure(quic_send: &mut SendStream, msg: String) -> Result<()> {
-        let msg = TunnelMessage::RespFailure(msg);
+    /// Send a login failure and finish the stream, peers speaking a protocol
+    /// `version` older than v2 only get its message.
+    pub async fn send_failure(
+        quic_send: &mut SendStream,
+        version: u16,
+        failure: &LoginFailure,
+    ) -> Result<()> {
+        let msg = if version >= LOGIN_FAILURE_VERSION {
+            TunnelMessage::RespLoginFailure(failure.clone())
+        } else {
+            TunnelMessage::RespFailure(failure.message.clone())
+        };
         Self::send(quic_send, &msg).await?;
-        quic_send.flush().await?;
-        tokio::time::sleep(Duration::from_millis(200)).await;
+        finish_stream(quic_send).await;
         Ok(())
     }
 
-    /// Receive a raw datagram payload into the provided buffer.
-    pub async fn recv_raw(quic_recv: &mut RecvStream, data: &mut [u8]) -> Result<u16> {
+    /// Receive a raw datagram payload into a buffer of its size, payloads
+    /// larger than [`MAX_UDP_PAYLOAD_SIZE`] are rejected.
+    pub async fn recv_raw(quic_recv: &mut RecvStream) -> Result<Block<'static, Vec<u8>>> {
         let msg_len = quic_recv.read_u16().await? as usize;
-        if msg_len > data.len() {
+        if msg_len > MAX_UDP_PAYLOAD_SIZE {
             bail!("message too large: {msg_len}");
         }
+        let mut data = BUFFER_POOL.alloc_and_fill(msg_len);
         quic_recv
-            .read_exact(&mut data[..msg_len])
+            .read_exact(&mut data)
             .await
             .context("read message failed")?;
-        Ok(msg_len as u16)
+        Ok(data)
     }
 
     /// Send a raw datagram payload.
@@ -166,7 +566,209 @@ impl TunnelMessage {
         match msg {
             TunnelMessage::RespSuccess => Ok(()),
             TunnelMessage::RespFailure(msg) => bail!(format!("received failure, err: {msg}")),
+            TunnelMessage::RespLoginFailure(failure) => {
+                bail!(format!("received failure, err: {failure}"))
+            }
             _ => bail!("unexpected message type"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_close_reason_round_trip() {
+        let reason = quota_close_reason(
+            "daily traffic quota exhausted: 10/10 bytes",
+            Duration::from_secs(3600),
+        );
+        assert_eq!(&reason[..QUOTA_RESET_PREFIX_LEN], &3600u64.to_be_bytes());
+        assert_eq!(
+            parse_quota_close_reason(&reason),
+            Some((
+                Duration::from_secs(3600),
+                "daily traffic quota exhausted: 10/10 bytes".to_string()
+            ))
+        );
+
+        let reason = quota_close_reason("", Duration::ZERO);
+        assert_eq!(
+            parse_quota_close_reason(&reason),
+            Some((Duration::ZERO, String::new()))
+        );
+        assert_eq!(parse_quota_close_reason(b"quota"), None);
+    }
+
+    #[test]
+    fn close_code_round_trip() {
+        for code in [
+            CloseCode::QuotaExceeded,
+            CloseCode::AdminClosed,
+            CloseCode::UserRevoked,
+        ] {
+            assert_eq!(CloseCode::from_varint(code.to_varint()), Some(code));
+        }
+        assert_eq!(CloseCode::from_varint(VarInt::from_u32(1)), None);
+    }
+
+    fn hello(version: u16, min_version: u16, features: Features) -> Hello {
+        Hello {
+            version,
+            min_version,
+            features,
+        }
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let local = Hello::local().without(Features::DATAGRAM);
+        let buf = local.encode();
+        assert_eq!(&buf[..4], b"RSTN");
+        assert_eq!(Hello::decode(buf[4..].try_into().unwrap()), local);
+        assert!(!local.features.contains(Features::DATAGRAM));
+        assert!(local.features.contains(Features::MULTIPLEX));
+    }
+
+    #[test]
+    fn negotiate_version_and_features() {
+        let local = hello(3, 1, Features::SUPPORTED);
+        let peer = hello(2, 2, Features::MULTIPLEX);
+        assert_eq!(
+            local.negotiate(&peer, "server", "client").unwrap(),
+            Negotiated {
+                version: 2,
+                features: Features::MULTIPLEX
+            }
+        );
+        assert_eq!(
+            peer.negotiate(&local, "client", "server").unwrap(),
+            Negotiated {
+                version: 2,
+                features: Features::MULTIPLEX
+            }
+        );
+    }
+
+    #[test]
+    fn negotiate_tells_who_to_upgrade() {
+        let local = hello(3, 3, Features::SUPPORTED);
+        let old_peer = hello(2, 1, Features::SUPPORTED);
+        let e = local.negotiate(&old_peer, "server", "client").unwrap_err();
+        assert!(e.to_string().ends_with("please upgrade the client"), "{e}");
+
+        let new_peer = hello(5, 4, Features::SUPPORTED);
+        let e = local.negotiate(&new_peer, "server", "client").unwrap_err();
+        assert!(e.to_string().ends_with("please upgrade the server"), "{e}");
+    }
+
+    /// `TunnelMessage` as of the peers predating the hello exchange, only the
+    /// order of the variants matters for decoding a failure.
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum BaselineTunnelMessage {
+        ReqLogin(String),
+        ReqUdpStart(Option<String>),
+        RespFailure(String),
+        RespSuccess,
+    }
+
+    fn round_trip(msg: &TunnelMessage) -> TunnelMessage {
+        let buf = bincode::serde::encode_to_vec(msg, config::standard()).unwrap();
+        bincode::serde::decode_from_slice::<TunnelMessage, _>(&buf, config::standard())
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn login_failure_round_trip() {
+        for code in [
+            LoginErrorCode::AuthFailed,
+            LoginErrorCode::PortInUse,
+            LoginErrorCode::Forbidden,
+            LoginErrorCode::QuotaExceeded,
+            LoginErrorCode::ShuttingDown,
+            LoginErrorCode::VersionMismatch,
+            LoginErrorCode::Other,
+        ] {
+            let failure = LoginFailure::new(code, format!("failed with {code}"));
+            let msg = round_trip(&TunnelMessage::RespLoginFailure(failure.clone()));
+            assert_eq!(msg.as_resp_login_failure(), Some(&failure));
+            assert_eq!(failure.retry_after(), None);
+        }
+
+        let failure = LoginFailure::new(LoginErrorCode::QuotaExceeded, "daily quota exhausted")
+            .with_retry_after(Duration::from_secs(3600));
+        let msg = round_trip(&TunnelMessage::RespLoginFailure(failure.clone()));
+        let decoded = msg.as_resp_login_failure().unwrap();
+        assert_eq!(decoded, &failure);
+        assert_eq!(decoded.retry_after_secs, Some(3600));
+        assert_eq!(decoded.retry_after(), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn permanent_login_failures() {
+        assert!(LoginErrorCode::AuthFailed.is_permanent());
+        assert!(LoginErrorCode::Forbidden.is_permanent());
+        assert!(LoginErrorCode::VersionMismatch.is_permanent());
+        assert!(!LoginErrorCode::QuotaExceeded.is_permanent());
+        assert!(!LoginErrorCode::ShuttingDown.is_permanent());
+        assert!(!LoginErrorCode::PortInUse.is_permanent());
+        assert!(!LoginErrorCode::Other.is_permanent());
+    }
+
+    #[test]
+    fn failure_of_an_error() {
+        let failure = LoginFailure::new(LoginErrorCode::PortInUse, "port 8000 is in use");
+        let e = anyhow::Error::new(failure.clone()).context("login failed");
+        assert_eq!(LoginFailure::from_error(&e), failure);
+
+        let e = anyhow::anyhow!("too many sessions");
+        assert_eq!(
+            LoginFailure::from_error(&e),
+            LoginFailure::new(LoginErrorCode::Other, "too many sessions")
+        );
+    }
+
+    /// `TunnelStatus` of protocol v1 peers.
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum V1TunnelStatus {
+        Started,
+        Failed(String),
+    }
+
+    #[test]
+    fn tunnel_status_of_each_version() {
+        let failure = LoginFailure::new(LoginErrorCode::Forbidden, "port not allowed");
+        let status = TunnelStatus::failed(failure.clone(), LOGIN_FAILURE_VERSION);
+        assert_eq!(status, TunnelStatus::Failed(failure.clone()));
+        assert_eq!(status.failure(), Some(failure.clone()));
+        assert_eq!(TunnelStatus::Started.failure(), None);
+
+        let status = TunnelStatus::failed(failure, 1);
+        let buf = bincode::serde::encode_to_vec(&status, config::standard()).unwrap();
+        let (v1_status, _) =
+            bincode::serde::decode_from_slice::<V1TunnelStatus, _>(&buf, config::standard())
+                .unwrap();
+        assert_eq!(
+            v1_status,
+            V1TunnelStatus::Failed("port not allowed".to_string())
+        );
+        assert_eq!(
+            status.failure(),
+            Some(LoginFailure::new(LoginErrorCode::Other, "port not allowed"))
+        );
+    }
+
+    #[test]
+    fn legacy_peers_decode_the_failure() {
+        let buf = Hello::encode_legacy_failure("the client is too old").unwrap();
+        let (msg, _) =
+            bincode::serde::decode_from_slice::<BaselineTunnelMessage, _>(&buf, config::standard())
+                .unwrap();
+        assert_eq!(
+            msg,
+            BaselineTunnelMessage::RespFailure("the client is too old".to_string())
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! UDP payloads carried in unreliable QUIC DATAGRAM frames (RFC 9221).
+//!
+//! Every UDP flow keeps its bi-stream, which sets up the flow and carries
+//! the payloads that can't be sent as datagrams: those larger than the peer
+//! and the path allow and those that name their destination. All other
+//! payloads are sent as datagrams prefixed with the id of the flow's stream,
+//! encoded as a QUIC variable-length integer, so that a lost packet doesn't
+//! hold back the packets sent after it.
+
+use crate::BUFFER_POOL;
+use byte_pool::Block;
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use log::{debug, trace};
+use quinn::{Connection, VarInt};
+use quinn_proto::coding::Codec;
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Number of received datagrams queued per flow, more are dropped.
+const DATAGRAM_QUEUE_LEN: usize = 32;
+
+/// Datagrams of one QUIC connection, dispatched to their flows by flow id.
+pub(crate) struct Datagrams {
+    conn: Connection,
+    flows: DashMap<u64, Sender<Block<'static, Vec<u8>>>>,
+}
+
+impl Datagrams {
+    /// Start dispatching the datagrams received on `conn`, until it is closed.
+    pub fn start(conn: &Connection) -> Arc<Self> {
+        let datagrams = Arc::new(Self {
+            conn: conn.clone(),
+            flows: DashMap::new(),
+        });
+
+        let this = datagrams.clone();
+        tokio::spawn(async move {
+            while let Ok(datagram) = this.conn.read_datagram().await {
+                this.dispatch(datagram);
+            }
+            this.flows.clear();
+        });
+
+        datagrams
+    }
+
+    fn dispatch(&self, datagram: Bytes) {
+        let Some((flow_id, datagram)) = decode(datagram) else {
+            debug!("dropped datagram without flow id");
+            return;
+        };
+        let Some(sender) = self.flows.get(&flow_id) else {
+            trace!("dropped datagram of unknown flow: {flow_id}");
+            return;
+        };
+
+        let mut payload = BUFFER_POOL.alloc_and_fill(datagram.len());
+        payload.copy_from_slice(&datagram);
+        // datagrams are unreliable anyway, drop them if the flow falls behind
+        sender.try_send(payload).ok();
+    }
+
+    /// Receive the datagrams of `flow_id` until the receiver is dropped.
+    pub fn register(self: &Arc<Self>, flow_id: u64) -> DatagramReceiver {
+        let (sender, receiver) = channel(DATAGRAM_QUEUE_LEN);
+        self.flows.insert(flow_id, sender);
+        DatagramReceiver {
+            datagrams: self.clone(),
+            flow_id,
+            receiver,
+        }
+    }
+
+    /// Send `payload` of `flow_id` as a datagram, false if it must be sent
+    /// over the stream because the peer or the path doesn't allow a datagram
+    /// of its size.
+    pub fn send(&self, flow_id: u64, payload: &[u8]) -> bool {
+        let Some(datagram) = encode(flow_id, payload) else {
+            return false;
+        };
+        if datagram.len() > self.conn.max_datagram_size().unwrap_or(0) {
+            return false;
+        }
+        self.conn.send_datagram(datagram).is_ok()
+    }
+}
+
+/// Prefix `payload` with `flow_id`, None if the id is too large for a varint.
+fn encode(flow_id: u64, payload: &[u8]) -> Option<Bytes> {
+    let flow_id = VarInt::from_u64(flow_id).ok()?;
+    // a flow id takes 8 bytes at most
+    let mut datagram = BytesMut::with_capacity(8 + payload.len());
+    flow_id.encode(&mut datagram);
+    datagram.extend_from_slice(payload);
+    Some(datagram.freeze())
+}
+
+/// Split a datagram into its flow id and payload.
+fn decode(mut datagram: Bytes) -> Option<(u64, Bytes)> {
+    let flow_id = VarInt::decode(&mut datagram).ok()?;
+    Some((flow_id.into_inner(), datagram))
+}
+
+/// Receiving end of the datagrams of one flow.
+pub(crate) struct DatagramReceiver {
+    datagrams: Arc<Datagrams>,
+    flow_id: u64,
+    receiver: Receiver<Block<'static, Vec<u8>>>,
+}
+
+impl DatagramReceiver {
+    /// Receive the next payload, waits forever if there's no receiver, so
+    /// that it can be selected on whether or not datagrams are used.
+    pub async fn recv(receiver: &mut Option<DatagramReceiver>) -> Option<Block<'static, Vec<u8>>> {
+        match receiver {
+            Some(receiver) => receiver.receiver.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for DatagramReceiver {
+    fn drop(&mut self) {
+        self.datagrams.flows.remove(&self.flow_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flow_id_round_trip() {
+        for (flow_id, header_len) in [
+            (0, 1),
+            (63, 1),
+            (64, 2),
+            (16383, 2),
+            (16384, 4),
+            (VarInt::MAX.into_inner(), 8),
+        ] {
+            let datagram = encode(flow_id, b"payload").unwrap();
+            assert_eq!(datagram.len(), header_len + 7, "{flow_id}");
+            assert_eq!(
+                decode(datagram),
+                Some((flow_id, Bytes::from_static(b"payload")))
+            );
+        }
+    }
+
+    #[test]
+    fn empty_payload() {
+        let datagram = encode(4, b"").unwrap();
+        assert_eq!(&datagram[..], [4]);
+        assert_eq!(decode(datagram), Some((4, Bytes::new())));
+    }
+
+    #[test]
+    fn invalid_flow_ids() {
+        assert_eq!(encode(VarInt::MAX.into_inner() + 1, b"payload"), None);
+        assert_eq!(decode(Bytes::new()), None);
+        // a two-byte varint cut short
+        assert_eq!(decode(Bytes::from_static(&[0x40])), None);
+    }
+}
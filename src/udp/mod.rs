@@ -1,9 +1,13 @@
 //! UDP abstractions used by the tunneling implementation.
 
+pub(crate) mod datagram;
 pub mod udp_server;
 pub mod udp_tunnel;
 
+use crate::metrics::METRICS;
+use crate::UpstreamAddr;
 use byte_pool::Block;
+use log::warn;
 use std::net::SocketAddr;
 use tokio::sync::mpsc::{Receiver, Sender};
 
@@ -26,6 +30,19 @@ pub struct UdpPacket {
     pub payload: Block<'static, Vec<u8>>,
     /// Local socket address the packet arrived on or will be sent to.
     pub local_addr: SocketAddr,
-    /// Optional peer address (None when not applicable).
-    pub peer_addr: Option<SocketAddr>,
+    /// Optional peer address (None when not applicable), domain names are
+    /// resolved by the server.
+    pub peer_addr: Option<UpstreamAddr>,
+}
+
+/// Whether a datagram read into a buffer of `max_payload_size + 1` bytes, of
+/// which `len` were filled, was cut short. Such datagrams are counted and
+/// logged, the caller drops them.
+pub(crate) fn is_oversize(len: usize, max_payload_size: usize, from: SocketAddr) -> bool {
+    if len <= max_payload_size {
+        return false;
+    }
+    METRICS.udp_oversize_datagrams.inc();
+    warn!("dropped udp datagram from {from} exceeding the maximum payload size of {max_payload_size} bytes");
+    true
 }
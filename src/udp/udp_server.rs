@@ -1,5 +1,5 @@
+use crate::udp;
 use crate::BUFFER_POOL;
-use crate::UDP_PACKET_SIZE;
 use anyhow::Result;
 use log::debug;
 use log::error;
@@ -27,7 +27,8 @@ struct State {
 
 impl UdpServer {
     /// Bind to the given address and start the UDP bridging task in background.
-    pub async fn bind_and_start(addr: SocketAddr) -> Result<Self> {
+    /// Datagrams larger than `max_payload_size` are dropped.
+    pub async fn bind_and_start(addr: SocketAddr, max_payload_size: usize) -> Result<Self> {
         let udp_socket = UdpSocket::bind(addr).await?;
         let addr = udp_socket.local_addr().unwrap();
 
@@ -43,12 +44,16 @@ impl UdpServer {
         let state_clone = state.clone();
 
         tokio::spawn(async move {
+            // one byte more than allowed tells oversize datagrams apart
+            let mut buf = BUFFER_POOL.alloc_and_fill(max_payload_size + 1);
             loop {
-                let mut payload = BUFFER_POOL.alloc_and_fill(UDP_PACKET_SIZE);
                 tokio::select! {
-                    result = udp_socket.recv_from(&mut payload) => {
+                    result = udp_socket.recv_from(&mut buf) => {
                         match result {
                             Ok((size, local_addr)) => {
+                                if udp::is_oversize(size, max_payload_size, local_addr) {
+                                    continue;
+                                }
                                 let active = {
                                     state.clone().lock().unwrap().active
                                 };
@@ -57,7 +62,9 @@ impl UdpServer {
                                     continue;
                                 }
 
-                                unsafe { payload.set_len(size); }
+                                // queue a block of the packet's size, not of the largest one
+                                let mut payload = BUFFER_POOL.alloc_and_fill(size);
+                                payload.copy_from_slice(&buf[..size]);
                                 let msg = UdpMessage::Packet(UdpPacket{payload, local_addr, peer_addr: None});
                                 match tokio::time::timeout(
                                         Duration::from_millis(50),
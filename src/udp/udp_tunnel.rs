@@ -4,51 +4,113 @@
 //! of UDP packets over a QUIC connection. It allows for bridging between local
 //! UDP servers and remote endpoints using QUIC streams.
 
+use crate::acl::{UpstreamAcl, DENIED_STREAM_ERROR_CODE};
+use crate::metrics::{ActiveGuard, METRICS};
+use crate::mux::{self, TunnelId};
 use crate::tunnel_message::{TunnelMessage, UdpPeerAddr};
-use crate::udp::{UdpMessage, UdpPacket};
-use crate::BUFFER_POOL;
-use crate::UDP_PACKET_SIZE;
+use crate::udp::datagram::{DatagramReceiver, Datagrams};
+use crate::udp::{self, UdpMessage, UdpPacket};
+use crate::util::rate_limiter::TrafficLimiter;
+use crate::util::resolver;
+use crate::{socket_addr_with_unspecified_ip_port, UpstreamAddr, BUFFER_POOL};
 use anyhow::{Context, Result};
+use byte_pool::Block;
 use dashmap::DashMap;
 use log::{debug, error, info, warn};
-use quinn::{Connection, RecvStream, SendStream};
+use quinn::{Connection, RecvStream, SendStream, VarInt};
 use rs_utilities::log_and_bail;
-use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
-    time::Duration,
-};
-use tokio::sync::mpsc::{Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 use tokio::{net::UdpSocket, sync::Mutex};
 
-type TSafe<T> = Arc<tokio::sync::Mutex<T>>;
+/// Number of packets read ahead from the stream of a flow.
+const STREAM_QUEUE_LEN: usize = 6;
+
+/// Sending half of a UDP flow.
+struct UdpFlow {
+    quic_send: Mutex<SendStream>,
+    datagrams: Option<Arc<Datagrams>>,
+    /// Id of the flow's stream, which tags its datagrams.
+    flow_id: u64,
+    /// Whether the peer has accepted the stream, before which it would drop
+    /// the datagrams of the flow.
+    accepted: AtomicBool,
+}
+
+impl UdpFlow {
+    fn new(quic_send: SendStream, datagrams: Option<Arc<Datagrams>>, accepted: bool) -> Self {
+        Self {
+            flow_id: quic_send.id().into(),
+            quic_send: Mutex::new(quic_send),
+            datagrams,
+            accepted: AtomicBool::new(accepted),
+        }
+    }
+
+    /// Send `payload` as a datagram if possible, false if it must be sent
+    /// over the stream.
+    fn send_datagram(&self, payload: &[u8]) -> bool {
+        self.accepted.load(Ordering::Relaxed)
+            && self
+                .datagrams
+                .as_ref()
+                .is_some_and(|datagrams| datagrams.send(self.flow_id, payload))
+    }
+}
+
+/// Aborts the task when dropped.
+struct TaskGuard(JoinHandle<()>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
 
 pub struct UdpTunnel;
 
 impl UdpTunnel {
     /// Bridge packets between a local UDP server and QUIC streams (OUT mode).
     /// Consumes packets from `udp_receiver` and sends them via QUIC; also
-    /// spawns tasks to relay responses back to the local UDP server.
+    /// spawns tasks to relay responses back to the local UDP server. Packets
+    /// in both directions are throttled by `limiter`. On multiplexed
+    /// connections every stream is tagged with `tunnel_id`, with `datagrams`
+    /// packets are sent as QUIC datagrams where possible.
     pub async fn start_serving(
         conn: &quinn::Connection,
+        tunnel_id: Option<TunnelId>,
+        datagrams: Option<Arc<Datagrams>>,
         udp_sender: &Sender<UdpMessage>,
         udp_receiver: &mut Receiver<UdpMessage>,
+        limiter: TrafficLimiter,
         udp_timeout_ms: u64,
     ) {
         debug!("start serving udp via: {}", conn.remote_address());
         let stream_map = Arc::new(DashMap::new());
-        while let Some(UdpMessage::Packet(packet)) = udp_receiver.recv().await {
-            let quic_send = match UdpTunnel::open_stream(
+        loop {
+            let packet = tokio::select! {
+                message = udp_receiver.recv() => match message {
+                    Some(UdpMessage::Packet(packet)) => packet,
+                    _ => break,
+                },
+                _ = conn.closed() => break,
+            };
+            let flow = match UdpTunnel::open_stream(
                 conn.clone(),
+                tunnel_id,
+                datagrams.clone(),
                 udp_sender.clone(),
                 packet.local_addr,
                 stream_map.clone(),
+                limiter.clone(),
                 udp_timeout_ms,
             )
             .await
             {
-                Ok(quic_send) => quic_send,
+                Ok(flow) => flow,
                 Err(e) => {
                     error!("{e}");
                     if conn.close_reason().is_some() {
@@ -59,9 +121,18 @@ impl UdpTunnel {
                 }
             };
 
+            METRICS.udp_sent_datagrams.inc();
+            METRICS.udp_sent_bytes.add(packet.payload.len() as u64);
+            limiter.acquire_send(packet.payload.len()).await;
+
+            // packets naming their destination always go over the stream
+            if packet.peer_addr.is_none() && flow.send_datagram(&packet.payload) {
+                continue;
+            }
+
             // send the packet using an async task
             tokio::spawn(async move {
-                let mut quic_send = quic_send.lock().await;
+                let mut quic_send = flow.quic_send.lock().await;
                 let payload_len = packet.payload.len();
 
                 TunnelMessage::send(
@@ -86,41 +157,66 @@ impl UdpTunnel {
     }
 
     /// Open (or reuse) a QUIC stream for a specific local UDP socket address.
+    #[allow(clippy::too_many_arguments)]
     async fn open_stream(
         conn: Connection,
+        tunnel_id: Option<TunnelId>,
+        datagrams: Option<Arc<Datagrams>>,
         udp_sender: Sender<UdpMessage>,
         local_addr: SocketAddr,
-        stream_map: Arc<DashMap<SocketAddr, TSafe<SendStream>>>,
+        stream_map: Arc<DashMap<SocketAddr, Arc<UdpFlow>>>,
+        limiter: TrafficLimiter,
         udp_timeout_ms: u64,
-    ) -> Result<TSafe<SendStream>> {
+    ) -> Result<Arc<UdpFlow>> {
         if let Some(s) = stream_map.get(&local_addr) {
             return Ok((*s).clone());
         }
 
-        let (quic_send, mut quic_recv) =
-            conn.open_bi().await.context("open_bi failed for udp out")?;
+        limiter.acquire_stream().await;
+        let (quic_send, mut quic_recv) = mux::open_bi(&conn, tunnel_id)
+            .await
+            .context("open_bi failed for udp out")?;
 
-        let quic_send = Arc::new(Mutex::new(quic_send));
-        stream_map.insert(local_addr, quic_send.clone());
+        let flow = Arc::new(UdpFlow::new(quic_send, datagrams, false));
+        stream_map.insert(local_addr, flow.clone());
+
+        let (stream_sender, mut stream_receiver) = channel(STREAM_QUEUE_LEN);
+        let stream_reader = TaskGuard(tokio::spawn(async move {
+            while let Ok(payload) = TunnelMessage::recv_raw(&mut quic_recv).await {
+                if stream_sender.send(payload).await.is_err() {
+                    break;
+                }
+            }
+        }));
+        let mut datagram_receiver = flow
+            .datagrams
+            .as_ref()
+            .map(|datagrams| datagrams.register(flow.flow_id));
 
         let stream_map = stream_map.clone();
+        let flow_ref = flow.clone();
         tokio::spawn(async move {
+            let _active = ActiveGuard::new(&METRICS.udp_flows);
+            let _stream_reader = stream_reader;
             debug!(
                 "start udp stream: {local_addr}, streams: {}",
                 stream_map.len()
             );
             loop {
-                let mut payload = BUFFER_POOL.alloc_and_fill(UDP_PACKET_SIZE);
-                match tokio::time::timeout(
-                    Duration::from_millis(udp_timeout_ms),
-                    TunnelMessage::recv_raw(&mut quic_recv, &mut payload),
-                )
+                match tokio::time::timeout(Duration::from_millis(udp_timeout_ms), async {
+                    tokio::select! {
+                        payload = stream_receiver.recv() => payload,
+                        Some(payload) = DatagramReceiver::recv(&mut datagram_receiver) => Some(payload),
+                    }
+                })
                 .await
                 {
-                    Ok(Ok(packet_len)) => {
-                        unsafe {
-                            payload.set_len(packet_len as usize);
-                        }
+                    Ok(Some(payload)) => {
+                        // the peer answered, so it knows the flow
+                        flow_ref.accepted.store(true, Ordering::Relaxed);
+                        METRICS.udp_received_datagrams.inc();
+                        METRICS.udp_received_bytes.add(payload.len() as u64);
+                        limiter.acquire_recv(payload.len()).await;
                         let packet = UdpPacket {
                             payload,
                             local_addr,
@@ -128,8 +224,8 @@ impl UdpTunnel {
                         };
                         let _ = udp_sender.send(UdpMessage::Packet(packet)).await;
                     }
-                    Ok(Err(_)) => {
-                        // warn!("failed to read for udp, err: {e}");
+                    Ok(None) => {
+                        // the stream is closed
                         break;
                     }
                     Err(_) => {
@@ -146,14 +242,23 @@ impl UdpTunnel {
             );
         });
 
-        Ok(quic_send)
+        Ok(flow)
     }
 
     /// Accept peer QUIC streams and forward them to an upstream UDP endpoint.
+    ///
+    /// Domain upstreams are resolved and their addresses tried in order.
+    /// Streams whose upstream is denied by `upstream_acl` are reset, packets
+    /// are throttled by `limiter`. Upstream datagrams larger than
+    /// `max_payload_size` are dropped.
     pub async fn start_accepting(
         conn: &quinn::Connection,
-        upstream_addr: Option<SocketAddr>,
+        datagrams: Option<Arc<Datagrams>>,
+        upstream_addr: Option<UpstreamAddr>,
+        upstream_acl: Option<Arc<UpstreamAcl>>,
+        limiter: TrafficLimiter,
         udp_timeout_ms: u64,
+        max_payload_size: usize,
     ) {
         let remote_addr = &conn.remote_address();
         info!("start udp stream, {remote_addr} ↔  {upstream_addr:?}");
@@ -172,9 +277,26 @@ impl UdpTunnel {
                     error!("failed to accept_bi: {remote_addr}, err: {e}");
                     break;
                 }
-                Ok((quic_send, quic_recv)) => tokio::spawn(async move {
-                    Self::process(quic_send, quic_recv, upstream_addr, udp_timeout_ms).await
-                }),
+                Ok((quic_send, quic_recv)) => {
+                    limiter.acquire_stream().await;
+                    let datagrams = datagrams.clone();
+                    let upstream_addr = upstream_addr.clone();
+                    let upstream_acl = upstream_acl.clone();
+                    let limiter = limiter.clone();
+                    tokio::spawn(async move {
+                        Self::process(
+                            quic_send,
+                            quic_recv,
+                            datagrams,
+                            upstream_addr,
+                            upstream_acl,
+                            limiter,
+                            udp_timeout_ms,
+                            max_payload_size,
+                        )
+                        .await
+                    })
+                }
             };
         }
 
@@ -182,98 +304,187 @@ impl UdpTunnel {
     }
 
     /// Process one accepted QUIC pair for UDP bridging.
-    async fn process(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn process(
         quic_send: SendStream,
         mut quic_recv: RecvStream,
-        upstream_addr: Option<SocketAddr>,
+        datagrams: Option<Arc<Datagrams>>,
+        upstream_addr: Option<UpstreamAddr>,
+        upstream_acl: Option<Arc<UpstreamAcl>>,
+        limiter: TrafficLimiter,
         udp_timeout_ms: u64,
+        max_payload_size: usize,
     ) -> Result<()> {
-        let quic_send = Arc::new(Mutex::new(quic_send));
+        let _active = ActiveGuard::new(&METRICS.udp_flows);
+        // the stream is accepted, so the peer may use datagrams right away
+        let flow = Arc::new(UdpFlow::new(quic_send, datagrams, true));
         let mut udp_socket = None;
-        if let Some(upstream_addr) = upstream_addr {
+        if let Some(upstream_addr) = &upstream_addr {
             // pre-create the udp-socket if upstream is specified
-            udp_socket = Self::create_peer_socket_and_exchange_data(
+            udp_socket = Self::connect_upstream(
                 upstream_addr,
-                quic_send.clone(),
+                &upstream_acl,
+                &flow,
+                limiter.clone(),
                 udp_timeout_ms,
+                max_payload_size,
             )
             .await?;
         }
+        let mut current_peer_addr = None;
+
+        let (stream_sender, mut stream_receiver) = channel(STREAM_QUEUE_LEN);
+        let _stream_reader = TaskGuard(tokio::spawn(async move {
+            loop {
+                let result = async {
+                    let peer_addr = match TunnelMessage::recv(&mut quic_recv).await? {
+                        TunnelMessage::ReqUdpStart(UdpPeerAddr(peer_addr)) => peer_addr,
+                        msg => {
+                            log_and_bail!("unexpected tunnel message: {msg}");
+                        }
+                    };
+
+                    let payload = TunnelMessage::recv_raw(&mut quic_recv).await?;
+                    Ok((peer_addr, payload))
+                }
+                .await;
+
+                match result {
+                    Ok(packet) => {
+                        if stream_sender.send(packet).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("failed to read from udp packet from tunnel, err: {e}");
+                        break;
+                    }
+                }
+            }
+        }));
+        let mut datagram_receiver = flow
+            .datagrams
+            .as_ref()
+            .map(|datagrams| datagrams.register(flow.flow_id));
 
-        let mut buf = BUFFER_POOL.alloc_and_fill(UDP_PACKET_SIZE);
         loop {
-            match tokio::time::timeout(Duration::from_millis(udp_timeout_ms), async {
-                let peer_addr = match TunnelMessage::recv(&mut quic_recv).await? {
-                    TunnelMessage::ReqUdpStart(UdpPeerAddr(peer_addr)) => peer_addr,
-                    msg => {
-                        log_and_bail!("unexpected tunnel message: {msg}");
+            let (peer_addr, payload): (Option<UpstreamAddr>, Block<'static, Vec<u8>>) =
+                match tokio::time::timeout(Duration::from_millis(udp_timeout_ms), async {
+                    tokio::select! {
+                        packet = stream_receiver.recv() => packet,
+                        Some(payload) = DatagramReceiver::recv(&mut datagram_receiver) => Some((None, payload)),
+                    }
+                })
+                .await
+                {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => {
+                        // the stream is closed
+                        break;
+                    }
+                    Err(_) => {
+                        // timeout on receiving datagrams from upstream
+                        break;
                     }
                 };
 
-                let packet_len = TunnelMessage::recv_raw(&mut quic_recv, &mut buf).await?;
-                Ok((peer_addr, packet_len))
-            })
-            .await
-            {
-                Ok(Ok((peer_addr, packet_len))) => {
-                    match peer_addr {
-                        Some(peer_addr) => {
-                            if let Some(upstream_addr) = upstream_addr {
-                                warn!("upstream_addr {upstream_addr:?} is specified for the connection, peer_addr {peer_addr} is ignored");
-                            } else if udp_socket.as_ref().and_then(|sock| sock.0.peer_addr().ok())
-                                != Some(peer_addr)
-                            {
-                                if let Some(udp_socket) = udp_socket {
-                                    // shutdown the old socket
-                                    udp_socket.1.send(()).ok();
-                                }
-                                udp_socket = Self::create_peer_socket_and_exchange_data(
-                                    peer_addr,
-                                    quic_send.clone(),
-                                    udp_timeout_ms,
-                                )
-                                .await?;
-                            }
+            match peer_addr {
+                Some(peer_addr) => {
+                    if let Some(upstream_addr) = &upstream_addr {
+                        warn!("upstream_addr {upstream_addr:?} is specified for the connection, peer_addr {peer_addr} is ignored");
+                    } else if current_peer_addr.as_ref() != Some(&peer_addr) {
+                        if let Some(udp_socket) = udp_socket.take() {
+                            // shutdown the old socket
+                            udp_socket.1.send(()).ok();
                         }
-                        None => {
-                            if udp_socket.is_none() {
-                                log_and_bail!("no valid upstream_addr to connect");
-                            }
-                        }
-                    };
-
-                    udp_socket
-                        .as_ref()
-                        .unwrap()
-                        .0
-                        .send(&buf[..packet_len as usize])
-                        .await
-                        .context("failed to send datagram through udp_socket")?;
-                }
-                Ok(Err(e)) => {
-                    warn!("failed to read from udp packet from tunnel, err: {e}");
-                    break;
+                        udp_socket = Self::connect_upstream(
+                            &peer_addr,
+                            &upstream_acl,
+                            &flow,
+                            limiter.clone(),
+                            udp_timeout_ms,
+                            max_payload_size,
+                        )
+                        .await?;
+                        current_peer_addr = Some(peer_addr);
+                    }
                 }
-                Err(_) => {
-                    // timeout on receiving datagrams from upstream
-                    break;
+                None => {
+                    if udp_socket.is_none() {
+                        log_and_bail!("no valid upstream_addr to connect");
+                    }
                 }
-            }
+            };
+
+            METRICS.udp_received_datagrams.inc();
+            METRICS.udp_received_bytes.add(payload.len() as u64);
+            limiter.acquire_recv(payload.len()).await;
+            udp_socket
+                .as_ref()
+                .unwrap()
+                .0
+                .send(&payload)
+                .await
+                .context("failed to send datagram through udp_socket")?;
         }
 
         Ok::<(), anyhow::Error>(())
     }
 
-    /// Spawn a task to forward datagrams from a connected UDP socket to QUIC.
+    /// Resolve `addr` and create a socket connected to the first of its
+    /// addresses allowed by the ACL that works, the flow's stream is reset if
+    /// the ACL denies all of them.
+    async fn connect_upstream(
+        addr: &UpstreamAddr,
+        upstream_acl: &Option<Arc<UpstreamAcl>>,
+        flow: &Arc<UdpFlow>,
+        limiter: TrafficLimiter,
+        udp_timeout_ms: u64,
+        max_payload_size: usize,
+    ) -> Result<Option<(Arc<UdpSocket>, oneshot::Sender<()>)>> {
+        let allowed_addrs: Vec<SocketAddr> = resolver::resolve(addr)
+            .await?
+            .into_iter()
+            .filter(|addr| upstream_acl.as_ref().is_none_or(|acl| acl.is_allowed(addr)))
+            .collect();
+        if allowed_addrs.is_empty() {
+            let error_code = VarInt::from_u32(DENIED_STREAM_ERROR_CODE);
+            flow.quic_send.lock().await.reset(error_code).ok();
+            log_and_bail!("udp upstream denied by acl: {addr}");
+        }
+
+        let mut result = Ok(None);
+        for addr in allowed_addrs {
+            result = Self::create_peer_socket_and_exchange_data(
+                addr,
+                flow.clone(),
+                limiter.clone(),
+                udp_timeout_ms,
+                max_payload_size,
+            )
+            .await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Spawn a task to forward datagrams from a connected UDP socket to QUIC,
+    /// dropping those larger than `max_payload_size`.
     fn udp_to_quic(
+        addr: SocketAddr,
         udp_socket: Arc<UdpSocket>,
-        quic_send: Arc<Mutex<SendStream>>,
+        flow: Arc<UdpFlow>,
+        limiter: TrafficLimiter,
         udp_timeout_ms: u64,
+        max_payload_size: usize,
         mut shutdown_rx: oneshot::Receiver<()>,
     ) {
         tokio::spawn(async move {
-            debug!("start udp stream →  {:?}", udp_socket.peer_addr());
-            let mut buf = BUFFER_POOL.alloc_and_fill(UDP_PACKET_SIZE);
+            debug!("start udp stream →  {addr}");
+            // one byte more than allowed tells oversize datagrams apart
+            let mut buf = BUFFER_POOL.alloc_and_fill(max_payload_size + 1);
             loop {
                 tokio::select! {
                     biased;
@@ -288,10 +499,18 @@ impl UdpTunnel {
                     ) => {
                         match result {
                             Ok(Ok(len)) => {
-                                let mut quic_send = quic_send.lock().await;
-                                TunnelMessage::send_raw(&mut quic_send, &buf[..len])
-                                    .await
-                                    .ok();
+                                if udp::is_oversize(len, max_payload_size, addr) {
+                                    continue;
+                                }
+                                METRICS.udp_sent_datagrams.inc();
+                                METRICS.udp_sent_bytes.add(len as u64);
+                                limiter.acquire_send(len).await;
+                                if !flow.send_datagram(&buf[..len]) {
+                                    let mut quic_send = flow.quic_send.lock().await;
+                                    TunnelMessage::send_raw(&mut quic_send, &buf[..len])
+                                        .await
+                                        .ok();
+                                }
                             }
                             Ok(Err(e)) => {
                                 warn!("failed to receive datagrams from upstream, err: {e:?}");
@@ -305,19 +524,22 @@ impl UdpTunnel {
                     }
                 }
             }
-            debug!("dropped udp stream →  {:?}", udp_socket.peer_addr());
+            debug!("dropped udp stream →  {addr}");
         });
     }
 
     async fn create_peer_socket_and_exchange_data(
         addr: SocketAddr,
-        quic_send: Arc<Mutex<SendStream>>,
+        flow: Arc<UdpFlow>,
+        limiter: TrafficLimiter,
         udp_timeout_ms: u64,
+        max_payload_size: usize,
     ) -> Result<Option<(Arc<UdpSocket>, oneshot::Sender<()>)>> {
-        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let local_addr = socket_addr_with_unspecified_ip_port(addr.is_ipv6());
         match UdpSocket::bind(local_addr).await {
             Ok(udp_socket) => {
                 if let Err(e) = udp_socket.connect(addr).await {
+                    METRICS.upstream_connect_failures.inc();
                     log_and_bail!("failed to connect to upstream: {addr}, err: {e}");
                 };
 
@@ -325,9 +547,12 @@ impl UdpTunnel {
                 let udp_socket = Arc::new(udp_socket);
 
                 Self::udp_to_quic(
+                    addr,
                     udp_socket.clone(),
-                    quic_send.clone(),
+                    flow,
+                    limiter,
                     udp_timeout_ms,
+                    max_payload_size,
                     shutdown_rx,
                 );
 
@@ -0,0 +1,167 @@
+//! Upstream addresses given as an IP socket address or a domain name plus port.
+//!
+//! Domain names are carried to the server as is and resolved there, so names
+//! only the server can resolve (e.g. `db.internal:5432`) may be used as
+//! upstreams. Addresses are (de)serialized in their textual form.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Maximum length of a domain name, see RFC 1035.
+pub(crate) const MAX_DOMAIN_LEN: usize = 253;
+
+/// Destination of an OUT tunnel or of a stream of a channel-based tunnel.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UpstreamAddr {
+    /// IPv4/IPv6 socket address.
+    Ip(SocketAddr),
+    /// Domain name and port, resolved by the server.
+    Domain(String, u16),
+}
+
+impl UpstreamAddr {
+    /// Port of the address.
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Domain(_, port) => *port,
+        }
+    }
+
+    /// The socket address, None for domain names.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Ip(addr) => Some(*addr),
+            Self::Domain(..) => None,
+        }
+    }
+
+    /// Create an address of a domain name, rejecting names that can't be resolved.
+    pub fn domain(domain: &str, port: u16) -> Result<Self> {
+        if domain.is_empty() || domain.len() > MAX_DOMAIN_LEN {
+            bail!("invalid domain length: {}", domain.len());
+        }
+        if !domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+        {
+            bail!("invalid domain: {domain}");
+        }
+        Ok(Self::Domain(domain.to_string(), port))
+    }
+}
+
+impl From<SocketAddr> for UpstreamAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Ip(addr)
+    }
+}
+
+impl FromStr for UpstreamAddr {
+    type Err = anyhow::Error;
+
+    /// Parse IP:PORT, [IPv6]:PORT or DOMAIN:PORT.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self::Ip(addr));
+        }
+
+        let (domain, port) = s
+            .rsplit_once(':')
+            .with_context(|| format!("missing port in address: {s}"))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in address: {s}"))?;
+        Self::domain(domain, port).with_context(|| format!("invalid address: {s}"))
+    }
+}
+
+impl Display for UpstreamAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{addr}"),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+impl Serialize for UpstreamAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UpstreamAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|e| serde::de::Error::custom(format!("{e:#}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addresses() {
+        assert_eq!(
+            "127.0.0.1:80".parse::<UpstreamAddr>().unwrap(),
+            UpstreamAddr::Ip("127.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:53".parse::<UpstreamAddr>().unwrap(),
+            UpstreamAddr::Ip("[::1]:53".parse().unwrap())
+        );
+        assert_eq!(
+            "db.internal:5432".parse::<UpstreamAddr>().unwrap(),
+            UpstreamAddr::Domain("db.internal".to_string(), 5432)
+        );
+        assert_eq!("my_host-1:22".parse::<UpstreamAddr>().unwrap().port(), 22);
+    }
+
+    #[test]
+    fn reject_invalid_addresses() {
+        let too_long = format!("{}:80", "a".repeat(MAX_DOMAIN_LEN + 1));
+        for addr in [
+            "example.com",
+            "example.com:http",
+            "example.com:70000",
+            ":80",
+            "::1:80",
+            "exa mple.com:80",
+            "example.com/x:80",
+            too_long.as_str(),
+        ] {
+            assert!(addr.parse::<UpstreamAddr>().is_err(), "{addr}");
+        }
+        assert!(UpstreamAddr::domain(&"a".repeat(MAX_DOMAIN_LEN), 80).is_ok());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for addr in ["10.0.0.1:8080", "[fe80::1]:443", "db.internal:5432"] {
+            let upstream: UpstreamAddr = addr.parse().unwrap();
+            assert_eq!(upstream.to_string(), addr);
+
+            let json = serde_json::to_string(&upstream).unwrap();
+            assert_eq!(json, format!("\"{addr}\""));
+            assert_eq!(
+                serde_json::from_str::<UpstreamAddr>(&json).unwrap(),
+                upstream
+            );
+        }
+        assert!(serde_json::from_str::<UpstreamAddr>("\"no-port\"").is_err());
+    }
+
+    #[test]
+    fn socket_addr_of_ip_only() {
+        let ip: UpstreamAddr = "10.0.0.1:80".parse().unwrap();
+        assert_eq!(ip.socket_addr(), Some("10.0.0.1:80".parse().unwrap()));
+        let domain: UpstreamAddr = "example.com:80".parse().unwrap();
+        assert_eq!(domain.socket_addr(), None);
+    }
+}
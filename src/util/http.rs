@@ -0,0 +1,256 @@
+//! Minimal HTTP/1.1 helpers for the small admin endpoints of rstund.
+//!
+//! Only what the endpoints need is supported: one request per connection, no
+//! request bodies beyond a small limit, and responses closed after writing.
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// A parsed HTTP request.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Request path without the query string.
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Return true if the request carries HTTP Basic credentials equal to
+    /// `credential` ("user:password").
+    pub fn has_basic_auth(&self, credential: &str) -> bool {
+        let expected = base64_encode(credential.as_bytes());
+        // the scheme is case-insensitive, the credential is compared in
+        // constant time so its content can't be guessed from response times
+        self.header("Authorization")
+            .and_then(|value| value.trim().split_once(' '))
+            .is_some_and(|(scheme, value)| {
+                scheme.eq_ignore_ascii_case("Basic")
+                    && constant_time_eq(value.trim_start().as_bytes(), expected.as_bytes())
+            })
+    }
+}
+
+/// Read one request from `stream`.
+pub async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest> {
+    let mut buf = Vec::with_capacity(1024);
+    let header_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() >= MAX_REQUEST_SIZE {
+            bail!("http request too large");
+        }
+
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before the request was complete");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = std::str::from_utf8(&buf[..header_len]).context("invalid http request")?;
+    let mut request = parse_head(head)?;
+    request.body = buf[header_len..].to_vec();
+
+    let content_len = request
+        .header("Content-Length")
+        .map(|len| len.parse::<usize>())
+        .transpose()
+        .context("invalid Content-Length")?
+        .unwrap_or(0);
+    if header_len + content_len > MAX_REQUEST_SIZE {
+        bail!("http request too large");
+    }
+    while request.body.len() < content_len {
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before the request body was complete");
+        }
+        request.body.extend_from_slice(&chunk[..n]);
+    }
+    request.body.truncate(content_len);
+
+    Ok(request)
+}
+
+/// Parse the request line and headers of `head`, the body is left empty.
+fn parse_head(head: &str) -> Result<HttpRequest> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        bail!("invalid http request line");
+    };
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Write a response with `body` and close the connection.
+pub async fn write_response(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    extra_headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        reason_phrase(status),
+        body.len()
+    );
+    for (name, value) in extra_headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Decode a percent-encoded path segment, None if it is malformed or not UTF-8.
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hi = (bytes.next()? as char).to_digit(16)?;
+            let lo = (bytes.next()? as char).to_digit(16)?;
+            decoded.push((hi << 4 | lo) as u8);
+        } else {
+            decoded.push(b);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_head() {
+        let request = parse_head(
+            "POST /sessions/3/close?now=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/sessions/3/close");
+        assert_eq!(request.header("Content-Length"), Some("0"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.header("Authorization"), None);
+
+        assert!(parse_head("GET\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn decode_percent_encoding() {
+        for (segment, decoded) in [
+            ("alice", "alice"),
+            ("Jane%20Doe", "Jane Doe"),
+            ("jane%40example.com", "jane@example.com"),
+            ("jane@example.com", "jane@example.com"),
+            ("%E5%BC%A0%e4%b8%89", "张三"),
+            ("100%25", "100%"),
+            ("", ""),
+        ] {
+            assert_eq!(percent_decode(segment).as_deref(), Some(decoded));
+        }
+
+        for segment in ["%", "%2", "a%zz", "%+1", "%FF"] {
+            assert_eq!(percent_decode(segment), None);
+        }
+    }
+
+    #[test]
+    fn encode_base64() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("admin:secret", "YWRtaW46c2VjcmV0"),
+        ] {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn check_basic_auth() {
+        let with_auth = |value: &str| {
+            parse_head(&format!("GET / HTTP/1.1\r\nAuthorization: {value}\r\n\r\n")).unwrap()
+        };
+        assert!(with_auth("Basic YWRtaW46c2VjcmV0").has_basic_auth("admin:secret"));
+        assert!(with_auth("basic YWRtaW46c2VjcmV0").has_basic_auth("admin:secret"));
+        assert!(with_auth("BASIC  YWRtaW46c2VjcmV0").has_basic_auth("admin:secret"));
+        assert!(!with_auth("Basic YWRtaW46c2VjcmV1").has_basic_auth("admin:secret"));
+        assert!(!with_auth("Bearer YWRtaW46c2VjcmV0").has_basic_auth("admin:secret"));
+        assert!(!with_auth("YWRtaW46c2VjcmV0").has_basic_auth("admin:secret"));
+        assert!(!parse_head("GET / HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .has_basic_auth("admin:secret"));
+    }
+}
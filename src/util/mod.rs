@@ -1 +1,4 @@
+pub mod http;
+pub mod rate_limiter;
+pub mod resolver;
 pub mod stream_util;
@@ -0,0 +1,228 @@
+//! Token-bucket throughput limiting for the copy loops of TCP streams and UDP packets.
+//!
+//! A [`TrafficLimiter`] is a chain of [`LimitBuckets`], e.g. one shared by the
+//! whole server, one shared by all sessions of a user and one per tunnel. Data
+//! flowing through a tunnel has to pass every bucket in the chain, so the most
+//! restrictive limit wins. The limiter can also bound the rate at which new
+//! streams are opened or accepted on a connection.
+
+use crate::RateLimit;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket allowing `rate` bytes per second with bursts of up to one second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Create a bucket for `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `n` bytes out of the bucket, waiting until the bucket has refilled
+    /// enough if it runs into debt.
+    pub async fn acquire(&self, n: usize) {
+        let wait = self.take(n, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Refill the bucket up to `now` and take `n` bytes out of it, returning
+    /// how long to wait until the bucket is out of debt.
+    fn take(&self, n: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now
+            .saturating_duration_since(state.last_refill)
+            .as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+        state.last_refill = now;
+        state.tokens -= n as f64;
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Buckets enforcing one [`RateLimit`] for data sent to and received from the
+/// QUIC connection; shared by everything the limit applies to.
+#[derive(Debug, Clone, Default)]
+pub struct LimitBuckets {
+    send: Option<Arc<TokenBucket>>,
+    recv: Option<Arc<TokenBucket>>,
+}
+
+impl LimitBuckets {
+    fn new(send_rate: u64, recv_rate: u64) -> Self {
+        let bucket = |rate| (rate > 0).then(|| Arc::new(TokenBucket::new(rate)));
+        LimitBuckets {
+            send: bucket(send_rate),
+            recv: bucket(recv_rate),
+        }
+    }
+
+    /// Buckets for the server side, where download is the data sent to the client.
+    pub fn for_server(limit: &RateLimit) -> Self {
+        Self::new(limit.download_bytes_per_sec, limit.upload_bytes_per_sec)
+    }
+
+    /// Buckets for the client side, where upload is the data sent to the server.
+    pub fn for_client(limit: &RateLimit) -> Self {
+        Self::new(limit.upload_bytes_per_sec, limit.download_bytes_per_sec)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.send.is_none() && self.recv.is_none()
+    }
+}
+
+/// Chain of buckets applied to a tunnel; the default limiter doesn't limit anything.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficLimiter {
+    buckets: Vec<LimitBuckets>,
+    streams: Option<Arc<TokenBucket>>,
+}
+
+impl TrafficLimiter {
+    /// Add `buckets` to the chain.
+    pub fn with(mut self, buckets: LimitBuckets) -> Self {
+        if !buckets.is_empty() {
+            self.buckets.push(buckets);
+        }
+        self
+    }
+
+    /// Limit new streams to `rate` per second, 0 for unlimited.
+    pub fn with_stream_rate(mut self, rate: u32) -> Self {
+        self.streams = (rate > 0).then(|| Arc::new(TokenBucket::new(rate as u64)));
+        self
+    }
+
+    /// Count new streams against the stream limit of `other`, so that the
+    /// tunnels of one connection share it.
+    pub fn with_streams_of(mut self, other: &TrafficLimiter) -> Self {
+        self.streams = other.streams.clone();
+        self
+    }
+
+    /// Wait until a new stream may be opened or accepted.
+    pub async fn acquire_stream(&self) {
+        if let Some(bucket) = &self.streams {
+            bucket.acquire(1).await;
+        }
+    }
+
+    /// Wait until `n` bytes may be sent to the QUIC connection.
+    pub async fn acquire_send(&self, n: usize) {
+        for buckets in &self.buckets {
+            if let Some(bucket) = &buckets.send {
+                bucket.acquire(n).await;
+            }
+        }
+    }
+
+    /// Wait until `n` bytes received from the QUIC connection may be forwarded.
+    pub async fn acquire_recv(&self, n: usize) {
+        for buckets in &self.buckets {
+            if let Some(bucket) = &buckets.recv {
+                bucket.acquire(n).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_one_second_burst() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+        assert_eq!(bucket.take(600, start), Duration::ZERO);
+        assert_eq!(bucket.take(400, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+        assert_eq!(bucket.take(1500, start), Duration::from_millis(500));
+
+        // the debt is paid off after waiting, then the bucket refills
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(0, later), Duration::ZERO);
+        let later = later + Duration::from_millis(250);
+        assert_eq!(bucket.take(500, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_one_second() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(2000, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn limit_directions() {
+        let limit = RateLimit {
+            upload_bytes_per_sec: 100,
+            download_bytes_per_sec: 0,
+        };
+
+        let client = LimitBuckets::for_client(&limit);
+        assert_eq!(client.send.as_ref().map(|bucket| bucket.rate), Some(100.0));
+        assert!(client.recv.is_none());
+
+        let server = LimitBuckets::for_server(&limit);
+        assert!(server.send.is_none());
+        assert_eq!(server.recv.as_ref().map(|bucket| bucket.rate), Some(100.0));
+
+        let unlimited =
+            TrafficLimiter::default().with(LimitBuckets::for_client(&RateLimit::default()));
+        assert!(unlimited.buckets.is_empty());
+    }
+
+    #[test]
+    fn tunnels_share_the_stream_limit() {
+        let conn = TrafficLimiter::default().with_stream_rate(2);
+        let tunnels = [
+            TrafficLimiter::default().with_streams_of(&conn),
+            TrafficLimiter::default().with_streams_of(&conn),
+        ];
+        let bucket = conn.streams.as_ref().unwrap();
+        let start = bucket.state.lock().unwrap().last_refill;
+        for tunnel in &tunnels {
+            let streams = tunnel.streams.as_ref().unwrap();
+            assert!(Arc::ptr_eq(streams, bucket));
+            assert_eq!(streams.take(1, start), Duration::ZERO);
+        }
+        assert_eq!(bucket.take(1, start), Duration::from_millis(500));
+
+        let unlimited = TrafficLimiter::default().with_stream_rate(0);
+        assert!(TrafficLimiter::default()
+            .with_streams_of(&unlimited)
+            .streams
+            .is_none());
+    }
+}
@@ -0,0 +1,43 @@
+//! Resolution of the upstream domain names sent by clients.
+//!
+//! All lookups go through one resolver configured from the system's DNS
+//! settings and hosts file. It caches answers for the TTL of their records, so
+//! repeated streams to the same upstream don't hit the name servers, while
+//! changed records are picked up once the old ones expire.
+
+use crate::UpstreamAddr;
+use anyhow::{Context, Result};
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use std::net::SocketAddr;
+
+lazy_static! {
+    static ref RESOLVER: TokioAsyncResolver = create_resolver();
+}
+
+fn create_resolver() -> TokioAsyncResolver {
+    TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+        warn!("failed to read system DNS config, will use the default name servers, err: {e}");
+        TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+    })
+}
+
+/// Resolve `addr` to the socket addresses to try in order, IP addresses are
+/// returned as is.
+pub async fn resolve(addr: &UpstreamAddr) -> Result<Vec<SocketAddr>> {
+    match addr {
+        UpstreamAddr::Ip(addr) => Ok(vec![*addr]),
+        UpstreamAddr::Domain(domain, port) => {
+            let lookup = RESOLVER
+                .lookup_ip(domain.as_str())
+                .await
+                .with_context(|| format!("failed to resolve {domain}"))?;
+            let addrs: Vec<SocketAddr> =
+                lookup.iter().map(|ip| SocketAddr::new(ip, *port)).collect();
+            debug!("resolved {domain} to {addrs:?}");
+            Ok(addrs)
+        }
+    }
+}
@@ -1,15 +1,19 @@
 //! Stream utilities for bridging AsyncRead/Write streams with QUIC streams.
 //!
 //! Helper methods to transfer data between a local stream and a QUIC stream,
-//! and to (de)serialize socket addresses.
+//! and to (de)serialize upstream addresses.
 
+use crate::metrics::{ActiveGuard, METRICS};
 use crate::tcp::AsyncStream;
-use crate::BUFFER_POOL;
-use anyhow::Result;
+use crate::upstream_addr::MAX_DOMAIN_LEN;
+use crate::util::rate_limiter::TrafficLimiter;
+use crate::{UpstreamAddr, BUFFER_POOL};
+use anyhow::{bail, Result};
 use log::debug;
 use quinn::{RecvStream, SendStream};
 use std::fmt::Display;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::sync::oneshot;
@@ -21,6 +25,7 @@ pub enum TransferError {
     InternalError,
     InvalidIPAddress,
     InvalidIPFamily,
+    InvalidDomain,
     TimeoutError,
 }
 
@@ -30,20 +35,28 @@ impl Display for TransferError {
             Self::InternalError => write!(f, "InternalError"),
             Self::InvalidIPAddress => write!(f, "InvalidIPAddress"),
             Self::InvalidIPFamily => write!(f, "InvalidIPFamily"),
+            Self::InvalidDomain => write!(f, "InvalidDomain"),
             Self::TimeoutError => write!(f, "TimeoutError"),
         }
     }
 }
 
+/// Address type tags of the upstream addresses sent ahead of stream data.
+const ADDR_TYPE_IPV4: u8 = 4;
+const ADDR_TYPE_IPV6: u8 = 6;
+const ADDR_TYPE_DOMAIN: u8 = 3;
+
 pub struct StreamUtil {}
 
 impl StreamUtil {
     /// Start bidirectional flowing between a local Async stream and a pair of
-    /// QUIC send/recv streams. Runs two tasks and logs flow stats.
+    /// QUIC send/recv streams. Runs two tasks and logs flow stats, both
+    /// directions are throttled by `limiter`.
     pub fn start_flowing<S: AsyncStream>(
         tag: &'static str,
         stream: S,
         quic_stream: (SendStream, RecvStream),
+        limiter: TrafficLimiter,
         stream_timeout_ms: u64,
     ) {
         let peer_addr = match stream.peer_addr() {
@@ -59,12 +72,17 @@ impl StreamUtil {
         let index = quic_send.id().index();
 
         debug!("[{tag}] START {index:<3} →  {peer_addr:<20}");
+        // counts the stream as active until both directions are done
+        let active = Arc::new(ActiveGuard::new(&METRICS.tcp_streams));
 
         let (quic_to_stream_tx, quic_to_stream_rx) = oneshot::channel::<()>();
         let (stream_to_quic_tx, stream_to_quic_rx) = oneshot::channel::<()>();
         const BUFFER_SIZE: usize = 8192;
 
+        let recv_limiter = limiter.clone();
+        let recv_active = active.clone();
         tokio::spawn(async move {
+            let _active = recv_active;
             let mut transfer_bytes = 0u64;
             let mut buffer = BUFFER_POOL.alloc_and_fill(BUFFER_SIZE);
             loop {
@@ -72,6 +90,7 @@ impl StreamUtil {
                     &mut quic_recv,
                     &mut stream_write,
                     &mut buffer,
+                    &recv_limiter,
                     &mut transfer_bytes,
                     stream_timeout_ms,
                 )
@@ -101,6 +120,7 @@ impl StreamUtil {
         });
 
         tokio::spawn(async move {
+            let _active = active;
             let mut transfer_bytes = 0u64;
             let mut buffer = BUFFER_POOL.alloc_and_fill(BUFFER_SIZE);
             loop {
@@ -108,6 +128,7 @@ impl StreamUtil {
                     &mut stream_read,
                     &mut quic_send,
                     &mut buffer,
+                    &limiter,
                     &mut transfer_bytes,
                     stream_timeout_ms,
                 )
@@ -142,6 +163,7 @@ impl StreamUtil {
         stream_read: &mut ReadHalf<S>,
         quic_send: &mut SendStream,
         buffer: &mut [u8],
+        limiter: &TrafficLimiter,
         transfer_bytes: &mut u64,
         stream_timeout_ms: u64,
     ) -> Result<usize, TransferError> {
@@ -154,6 +176,8 @@ impl StreamUtil {
         .map_err(|_| TransferError::InternalError)?;
         if len_read > 0 {
             *transfer_bytes += len_read as u64;
+            METRICS.tcp_sent_bytes.add(len_read as u64);
+            limiter.acquire_send(len_read).await;
             quic_send
                 .write_all(&buffer[..len_read])
                 .await
@@ -171,6 +195,7 @@ impl StreamUtil {
         quic_recv: &mut RecvStream,
         stream_write: &mut WriteHalf<S>,
         buffer: &mut [u8],
+        limiter: &TrafficLimiter,
         transfer_bytes: &mut u64,
         stream_timeout_ms: u64,
     ) -> Result<usize, TransferError> {
@@ -183,6 +208,8 @@ impl StreamUtil {
         .map_err(|_| TransferError::InternalError)?;
         if let Some(len_read) = result {
             *transfer_bytes += len_read as u64;
+            METRICS.tcp_received_bytes.add(len_read as u64);
+            limiter.acquire_recv(len_read).await;
             stream_write
                 .write_all(&buffer[..len_read])
                 .await
@@ -197,27 +224,39 @@ impl StreamUtil {
         }
     }
 
-    /// Write an IPv4/IPv6 socket address (or None) into a QUIC send stream.
-    pub async fn write_socket_addr(
+    /// Write an IPv4/IPv6 socket address or a domain name plus port (or
+    /// None) into a QUIC send stream.
+    pub async fn write_upstream_addr(
         quic_send: &mut SendStream,
-        addr: &Option<SocketAddr>,
+        addr: &Option<UpstreamAddr>,
         mark_none: bool,
     ) -> Result<()> {
         match addr {
-            Some(SocketAddr::V4(v4)) => {
+            Some(UpstreamAddr::Ip(SocketAddr::V4(v4))) => {
                 let mut buf = [0u8; 1 + 4 + 2];
-                buf[0] = 4;
+                buf[0] = ADDR_TYPE_IPV4;
                 buf[1..5].copy_from_slice(&v4.ip().octets());
                 buf[5..7].copy_from_slice(&v4.port().to_be_bytes());
                 quic_send.write_all(&buf[..7]).await?;
             }
-            Some(SocketAddr::V6(v6)) => {
+            Some(UpstreamAddr::Ip(SocketAddr::V6(v6))) => {
                 let mut buf = [0u8; 1 + 16 + 2];
-                buf[0] = 6;
+                buf[0] = ADDR_TYPE_IPV6;
                 buf[1..17].copy_from_slice(&v6.ip().octets());
                 buf[17..19].copy_from_slice(&v6.port().to_be_bytes());
                 quic_send.write_all(&buf[..19]).await?;
             }
+            Some(UpstreamAddr::Domain(domain, port)) => {
+                if domain.len() > MAX_DOMAIN_LEN {
+                    bail!("domain is too long: {domain}");
+                }
+                let mut buf = Vec::with_capacity(1 + 1 + domain.len() + 2);
+                buf.push(ADDR_TYPE_DOMAIN);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
+                quic_send.write_all(&buf).await?;
+            }
             None => {
                 if mark_none {
                     quic_send.write_u8(0).await?;
@@ -227,43 +266,67 @@ impl StreamUtil {
         Ok(())
     }
 
-    /// Read an IPv4/IPv6 socket address from a QUIC recv stream with timeout.
-    pub async fn read_socket_addr(
+    /// Read an IPv4/IPv6 socket address or a domain name plus port from a
+    /// QUIC recv stream with timeout.
+    pub async fn read_upstream_addr(
         quic_recv: &mut RecvStream,
         stream_timeout_ms: u64,
-    ) -> Result<SocketAddr, TransferError> {
-        let mut buf = [0u8; 19];
+    ) -> Result<UpstreamAddr, TransferError> {
         tokio::time::timeout(
             Duration::from_millis(stream_timeout_ms),
-            quic_recv.read_exact(&mut buf[..7]),
+            Self::read_upstream_addr_inner(quic_recv),
         )
         .await
         .map_err(|_: Elapsed| TransferError::TimeoutError)?
-        .map_err(|_| TransferError::InternalError)?;
+    }
 
-        match buf[0] {
-            4 => {
+    async fn read_upstream_addr_inner(
+        quic_recv: &mut RecvStream,
+    ) -> Result<UpstreamAddr, TransferError> {
+        let addr_type = quic_recv
+            .read_u8()
+            .await
+            .map_err(|_| TransferError::InternalError)?;
+        match addr_type {
+            ADDR_TYPE_IPV4 => {
+                let mut buf = [0u8; 4 + 2];
+                quic_recv
+                    .read_exact(&mut buf)
+                    .await
+                    .map_err(|_| TransferError::InternalError)?;
                 let ip = Ipv4Addr::from(
-                    <[u8; 4]>::try_from(&buf[1..5]).map_err(|_| TransferError::InvalidIPAddress)?,
+                    <[u8; 4]>::try_from(&buf[..4]).map_err(|_| TransferError::InvalidIPAddress)?,
                 );
-                let port = u16::from_be_bytes(buf[5..7].try_into().unwrap());
-                Ok(SocketAddr::new(ip.into(), port))
+                let port = u16::from_be_bytes(buf[4..6].try_into().unwrap());
+                Ok(SocketAddr::new(ip.into(), port).into())
             }
-            6 => {
-                tokio::time::timeout(
-                    Duration::from_millis(stream_timeout_ms),
-                    quic_recv.read_exact(&mut buf[7..]),
-                )
-                .await
-                .map_err(|_: Elapsed| TransferError::TimeoutError)?
-                .map_err(|_| TransferError::InternalError)?;
-
+            ADDR_TYPE_IPV6 => {
+                let mut buf = [0u8; 16 + 2];
+                quic_recv
+                    .read_exact(&mut buf)
+                    .await
+                    .map_err(|_| TransferError::InternalError)?;
                 let ip = Ipv6Addr::from(
-                    <[u8; 16]>::try_from(&buf[1..17])
+                    <[u8; 16]>::try_from(&buf[..16])
                         .map_err(|_| TransferError::InvalidIPAddress)?,
                 );
-                let port = u16::from_be_bytes(buf[17..19].try_into().unwrap());
-                Ok(SocketAddr::new(ip.into(), port))
+                let port = u16::from_be_bytes(buf[16..18].try_into().unwrap());
+                Ok(SocketAddr::new(ip.into(), port).into())
+            }
+            ADDR_TYPE_DOMAIN => {
+                let len = quic_recv
+                    .read_u8()
+                    .await
+                    .map_err(|_| TransferError::InternalError)? as usize;
+                let mut buf = vec![0u8; len + 2];
+                quic_recv
+                    .read_exact(&mut buf)
+                    .await
+                    .map_err(|_| TransferError::InternalError)?;
+                let domain =
+                    std::str::from_utf8(&buf[..len]).map_err(|_| TransferError::InvalidDomain)?;
+                let port = u16::from_be_bytes(buf[len..].try_into().unwrap());
+                UpstreamAddr::domain(domain, port).map_err(|_| TransferError::InvalidDomain)
             }
             _ => {
                 log::error!("invalid address family");